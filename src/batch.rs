@@ -0,0 +1,172 @@
+//! Headless batch runs of a scenario over a grid of parameters, run in parallel with the results collected into one table
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::config;
+use crate::internet::{LatencyModel, routing_metrics::RoutingReport};
+use crate::scenario::{NodeSet, Scenario};
+
+/// A scenario and the parameters to run it with, every combination of the grid is run once
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct BatchSpec {
+	/// Scenario run for each combination, its outputs are ignored
+	pub base: Scenario,
+	pub grid: ParameterGrid,
+	/// Number of node pairs routing is measured between after each run
+	pub samples: usize,
+	/// Ticks to wait for measured packets to arrive
+	pub timeout: usize,
+	/// Number of runs done at once, defaults to the number of cores
+	pub threads: Option<usize>,
+	/// CSV file the results table is written to
+	pub output: String,
+}
+impl Default for BatchSpec {
+	fn default() -> Self {
+		Self { base: Scenario::default(), grid: ParameterGrid::default(), samples: 50, timeout: 3000, threads: None, output: "target/batch.csv".into() }
+	}
+}
+
+/// Values to sweep, an empty list keeps the base scenario's value
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ParameterGrid {
+	pub node_count: Vec<usize>,
+	/// Applied to the simulation's default node config
+	pub target_peer_count: Vec<usize>,
+	pub latency_model: Vec<LatencyModel>,
+	pub loss_rate: Vec<f64>,
+	pub seed: Vec<u64>,
+}
+
+/// One combination of parameters from the grid
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RunParams {
+	pub node_count: usize,
+	pub target_peer_count: usize,
+	pub latency_model: LatencyModel,
+	pub loss_rate: f64,
+	pub seed: u64,
+}
+impl RunParams {
+	fn from_scenario(scenario: &Scenario) -> Self {
+		Self {
+			node_count: match &scenario.nodes { NodeSet::Count(count) => *count, NodeSet::List(specs) => specs.len() },
+			target_peer_count: scenario.sim.node.target_peer_count,
+			latency_model: scenario.sim.latency_model.clone(),
+			loss_rate: scenario.sim.loss_rate,
+			seed: scenario.seed,
+		}
+	}
+	/// Copy of `base` with these parameters applied, fails if the node count would change a scenario that lists its nodes
+	fn apply(&self, base: &Scenario) -> anyhow::Result<Scenario> {
+		let mut scenario = base.clone();
+		match &scenario.nodes {
+			NodeSet::List(specs) if specs.len() != self.node_count => Err(anyhow!("node_count {} can't be applied to a scenario listing {} nodes", self.node_count, specs.len()))?,
+			NodeSet::List(_) => {},
+			NodeSet::Count(_) => scenario.nodes = NodeSet::Count(self.node_count),
+		}
+		scenario.sim.node.target_peer_count = self.target_peer_count;
+		scenario.sim.latency_model = self.latency_model.clone();
+		scenario.sim.loss_rate = self.loss_rate;
+		scenario.seed = self.seed;
+		scenario.outputs.clear();
+		Ok(scenario)
+	}
+}
+
+impl ParameterGrid {
+	/// Every combination of the grid's values, falling back to `base` for empty lists
+	pub fn combinations(&self, base: &Scenario) -> Vec<RunParams> {
+		fn or_base<T: Clone>(values: &[T], base: T) -> Vec<T> { if values.is_empty() { vec![base] } else { values.to_vec() } }
+		let base = RunParams::from_scenario(base);
+		let mut runs = Vec::new();
+		for node_count in or_base(&self.node_count, base.node_count) {
+			for target_peer_count in or_base(&self.target_peer_count, base.target_peer_count) {
+				for latency_model in or_base(&self.latency_model, base.latency_model.clone()) {
+					for loss_rate in or_base(&self.loss_rate, base.loss_rate) {
+						for seed in or_base(&self.seed, base.seed) {
+							runs.push(RunParams { node_count, target_peer_count, latency_model: latency_model.clone(), loss_rate, seed });
+						}
+					}
+				}
+			}
+		}
+		runs
+	}
+}
+
+/// Outcome of one run of the batch
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BatchRun {
+	pub params: RunParams,
+	pub assertions_passed: usize,
+	pub assertions_failed: usize,
+	/// Ticks the simulation ran for, including the routing measurement
+	pub ticks: usize,
+	pub routing: Option<RoutingReport>,
+	/// Set if the scenario failed to run
+	pub error: Option<String>,
+}
+
+impl BatchSpec {
+	pub fn load(path: &str) -> anyhow::Result<Self> {
+		let spec: Self = config::load(path)?;
+		spec.base.validate().map_err(|err|anyhow!("{}: {}", path, err))?;
+		Ok(spec)
+	}
+
+	/// Run every combination of the grid, spread over `threads` threads. Results are in the order of `ParameterGrid::combinations`.
+	pub fn run(&self) -> Vec<BatchRun> {
+		let combinations = self.grid.combinations(&self.base);
+		let threads = self.threads.unwrap_or_else(||std::thread::available_parallelism().map_or(1, |n|n.get())).max(1).min(combinations.len().max(1));
+		log::info!("Running batch of {} runs on {} threads", combinations.len(), threads);
+
+		let next = AtomicUsize::new(0);
+		let results: Mutex<Vec<Option<BatchRun>>> = Mutex::new(vec![None; combinations.len()]);
+		std::thread::scope(|scope| {
+			for _ in 0..threads {
+				scope.spawn(|| loop {
+					let index = next.fetch_add(1, Ordering::Relaxed);
+					let params = if let Some(params) = combinations.get(index) { params } else { break };
+					let run = self.run_one(params);
+					log::info!("Run {}/{} finished: {:?}", index + 1, combinations.len(), params);
+					results.lock().unwrap()[index] = Some(run);
+				});
+			}
+		});
+		results.into_inner().unwrap().into_iter().flatten().collect()
+	}
+	fn run_one(&self, params: &RunParams) -> BatchRun {
+		let (samples, timeout) = (self.samples, self.timeout);
+		let result = params.apply(&self.base).and_then(|scenario|scenario.run_then(|internet, rng|{
+			let routing = if samples > 0 { Some(internet.measure_routing(samples, timeout, rng)) } else { None };
+			(internet.ticks, routing)
+		}));
+		match result {
+			Ok((result, (ticks, routing))) => {
+				let assertions_passed = result.assertions.iter().filter(|(_,_,passed)|*passed).count();
+				BatchRun { params: params.clone(), assertions_passed, assertions_failed: result.assertions.len() - assertions_passed, ticks, routing, error: None }
+			},
+			Err(err) => BatchRun { params: params.clone(), assertions_passed: 0, assertions_failed: 0, ticks: 0, routing: None, error: Some(err.to_string()) },
+		}
+	}
+}
+
+/// Write the results of a batch as a CSV table, one row per run
+pub fn export(runs: &[BatchRun], path: &str) -> anyhow::Result<()> {
+	let mut writer = BufWriter::new(File::create(path)?);
+	writeln!(writer, "run,node_count,target_peer_count,latency_model,loss_rate,seed,assertions_passed,assertions_failed,ticks,delivery_rate,mean_hops,mean_stretch,median_stretch,max_stretch,error")?;
+	for (i, run) in runs.iter().enumerate() {
+		let p = &run.params;
+		let routing = run.routing.as_ref().map_or(",,,,".to_owned(), |r|format!("{:.4},{:.4},{:.4},{:.4},{:.4}", r.delivery_rate, r.mean_hops, r.mean_stretch, r.median_stretch, r.max_stretch));
+		let error = run.error.as_ref().map_or(String::new(), |e|format!("\"{}\"", e.replace('"', "\"\"")));
+		writeln!(writer, "{},{},{},\"{}\",{},{},{},{},{},{},{}", i, p.node_count, p.target_peer_count, p.latency_model, p.loss_rate, p.seed, run.assertions_passed, run.assertions_failed, run.ticks, routing, error)?;
+	}
+	writer.flush()?;
+	Ok(())
+}
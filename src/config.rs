@@ -0,0 +1,107 @@
+//! Runtime configuration of nodes and the simulation, loadable from TOML or JSON files so parameters can be swept without recompiling
+
+use std::ops::Range;
+
+use serde::de::DeserializeOwned;
+
+use crate::internet::LatencyModel;
+
+/// Parameters of a single node's protocol behaviour, every node may have its own
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct NodeConfig {
+	/// Number of peers a node tries to keep, the node publishes itself once it has this many
+	pub target_peer_count: usize,
+	/// Number of equal angular sectors around a node, the closest node in each is preferred as a peer
+	pub peer_sectors: usize,
+	/// Maximum number of nodes asked to ping this node in response to a RequestPings
+	pub max_request_pings: usize,
+	/// Maximum number of unanswered pings tracked per session
+	pub max_pending_pings: usize,
+	/// Ticks during which repeated RequestPings packets from the same node are ignored
+	pub request_pings_window: usize,
+	/// Ticks during which repeated AcceptWantPing packets from the same node are ignored
+	pub accept_want_ping_window: usize,
+	/// Maximum number of overlay links a Traverse packet may cross
+	pub traverse_hop_limit: usize,
+	/// Ticks to wait for a Traverse receipt before resending
+	pub traverse_receipt_timeout: usize,
+	/// Number of times a reliable Traverse packet is sent before giving up
+	pub max_traverse_attempts: usize,
+	/// Ticks a node remembers where a Traverse packet came from after last passing it or a reply along
+	pub traverse_state_timeout: usize,
+	/// Number of introducers a private node asks for
+	pub introducer_count: usize,
+	/// Ticks between a private node refreshing the paths its introducers reach it through, has to be below `traverse_state_timeout`
+	pub introducer_refresh_interval: usize,
+	/// Ticks an action may wait in the queue before it expires, unless it was queued with its own timeout
+	pub action_timeout: usize,
+	/// Number of times an action is run again after it errors, unless it was queued with its own retry count
+	pub action_retries: usize,
+	/// Maximum number of actions waiting in a node's queue
+	pub max_queued_actions: usize,
+}
+impl Default for NodeConfig {
+	fn default() -> Self {
+		Self {
+			target_peer_count: 5,
+			peer_sectors: 5,
+			max_request_pings: 10,
+			max_pending_pings: 25,
+			request_pings_window: 2000,
+			accept_want_ping_window: 300,
+			traverse_hop_limit: crate::node::TRAVERSE_HOP_LIMIT,
+			traverse_receipt_timeout: 5000,
+			max_traverse_attempts: 3,
+			traverse_state_timeout: 20000,
+			introducer_count: 3,
+			introducer_refresh_interval: 8000,
+			action_timeout: 10000,
+			action_retries: 0,
+			max_queued_actions: 256,
+		}
+	}
+}
+
+/// Parameters of the simulated network
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct SimConfig {
+	/// Area that nodes are randomly placed in
+	pub field_dimensions: (Range<i32>, Range<i32>),
+	/// Random latency added to or removed from every packet
+	pub latency_variance: isize,
+	pub latency_model: LatencyModel,
+	/// Probability that a packet between two nodes is dropped
+	pub loss_rate: f64,
+	/// Tick nodes on multiple threads, results are the same either way
+	pub parallel: bool,
+	/// Jump over ticks on which nothing happens instead of ticking every node, results are the same either way
+	pub skip_idle: bool,
+	/// Configuration given to nodes created without one of their own
+	pub node: NodeConfig,
+}
+impl Default for SimConfig {
+	fn default() -> Self {
+		Self {
+			field_dimensions: (-320..320, -130..130),
+			latency_variance: 2,
+			latency_model: LatencyModel::Distance,
+			loss_rate: 0.0,
+			parallel: true,
+			skip_idle: true,
+			node: NodeConfig::default(),
+		}
+	}
+}
+
+/// Load a configuration from a file, parsed as JSON if the path ends in `.json` and as TOML otherwise.
+/// Missing fields are filled in with their defaults.
+pub fn load<T: DeserializeOwned>(path: &str) -> anyhow::Result<T> {
+	let contents = std::fs::read_to_string(path)?;
+	if path.ends_with(".json") {
+		Ok(serde_json::from_str(&contents)?)
+	} else {
+		Ok(toml::from_str(&contents)?)
+	}
+}
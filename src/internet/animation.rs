@@ -0,0 +1,104 @@
+//! Recording of the network plot every few ticks, written as an animated GIF or as numbered PNG frames with a manifest
+
+use std::ops::Range;
+
+use petgraph::Graph;
+use plotters::prelude::*;
+use plotters::coord::Shift;
+
+use crate::plot::{self, PlotNode, PlotEdge};
+
+/// Milliseconds each frame of a GIF is shown for
+const GIF_FRAME_DELAY: u32 = 100;
+
+/// One frame written to a frame sequence
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FrameInfo {
+	/// Simulation tick the frame shows
+	pub tick: usize,
+	/// File name of the frame, relative to the manifest
+	pub file: String,
+}
+
+/// Manifest written next to a frame sequence
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FrameManifest {
+	/// Ticks between frames
+	pub every: usize,
+	pub dimensions: (u32, u32),
+	pub frames: Vec<FrameInfo>,
+}
+
+enum AnimationOutput {
+	Gif(DrawingArea<BitMapBackend<'static>, Shift>),
+	Frames(FrameManifest),
+}
+
+/// Draws a frame every `every` ticks, to a GIF if the path ends in `.gif` and to a directory of PNG frames otherwise
+pub struct AnimationRecorder {
+	path: String,
+	output: AnimationOutput,
+	every: usize,
+	dimensions: (u32, u32),
+	/// Tick the next frame is drawn on
+	pub next_tick: usize,
+	pub frames: usize,
+}
+impl std::fmt::Debug for AnimationRecorder {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("AnimationRecorder").field("path", &self.path).field("every", &self.every).field("next_tick", &self.next_tick).field("frames", &self.frames).finish()
+	}
+}
+impl AnimationRecorder {
+	/// Start recording, the first frame is drawn on tick `start`
+	pub fn create(path: &str, every: usize, dimensions: (u32, u32), start: usize) -> anyhow::Result<Self> {
+		if every == 0 { Err(anyhow!("Animation needs at least one tick between frames"))? }
+		let output = if path.ends_with(".gif") {
+			AnimationOutput::Gif(BitMapBackend::gif(path, dimensions, GIF_FRAME_DELAY)?.into_drawing_area())
+		} else {
+			std::fs::create_dir_all(path)?;
+			AnimationOutput::Frames(FrameManifest { every, dimensions, frames: Vec::new() })
+		};
+		Ok(Self { path: path.to_owned(), output, every, dimensions, next_tick: start, frames: 0 })
+	}
+	pub fn path(&self) -> &str { &self.path }
+	/// Draw every frame due by tick `now`, all showing `graph`
+	pub fn capture(&mut self, now: usize, graph: &Graph<PlotNode, PlotEdge>, render_range: &(Range<i32>, Range<i32>)) -> anyhow::Result<()> {
+		while self.next_tick <= now {
+			let tick = self.next_tick;
+			match &mut self.output {
+				AnimationOutput::Gif(area) => {
+					draw_frame(area, tick, graph, render_range)?;
+					area.present()?;
+				},
+				AnimationOutput::Frames(manifest) => {
+					let file = format!("{:0>6}.png", manifest.frames.len());
+					let frame_path = format!("{}/{}", self.path, file);
+					let area = BitMapBackend::new(&frame_path, self.dimensions).into_drawing_area();
+					draw_frame(&area, tick, graph, render_range)?;
+					area.present()?;
+					manifest.frames.push(FrameInfo { tick, file });
+				},
+			}
+			self.frames += 1;
+			self.next_tick += self.every;
+		}
+		Ok(())
+	}
+	/// Finish the animation, writing the manifest of a frame sequence
+	pub fn finish(self) -> anyhow::Result<()> {
+		if let AnimationOutput::Frames(manifest) = &self.output {
+			let file = std::fs::File::create(format!("{}/manifest.json", self.path))?;
+			serde_json::to_writer_pretty(std::io::BufWriter::new(file), manifest)?;
+		}
+		Ok(())
+	}
+}
+
+fn draw_frame<DB: DrawingBackend>(area: &DrawingArea<DB, Shift>, tick: usize, graph: &Graph<PlotNode, PlotEdge>, render_range: &(Range<i32>, Range<i32>)) -> anyhow::Result<()>
+	where DB::ErrorType: 'static
+{
+	plot::draw_graph(area, graph, render_range)?;
+	area.draw(&Text::new(format!("Tick {}", tick), (10, 10), ("sans-serif", 24.0).into_font().color(&BLACK)))?;
+	Ok(())
+}
@@ -0,0 +1,72 @@
+//! Measurement of how far the RouteCoords nodes claim are from their real positions in the `InternetRouter`
+
+use nalgebra::Point2;
+
+use crate::internet::InternetSim;
+use crate::node::{Node, NodeID};
+
+/// Real and claimed position of one node
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EmbeddingSample {
+	pub node_id: NodeID,
+	/// Position of the node in the `InternetRouter`
+	pub position: Point2<f32>,
+	/// The node's `RouteCoord`
+	pub route_coord: Point2<f32>,
+	/// Distance between `position` and `route_coord`
+	pub error: f32,
+}
+
+/// Embedding error of every node with a RouteCoord
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct EmbeddingReport {
+	pub tick: usize,
+	pub samples: Vec<EmbeddingSample>,
+	pub mean: f32,
+	pub median: f32,
+	pub p90: f32,
+	pub max: f32,
+}
+impl EmbeddingReport {
+	fn from_samples(tick: usize, samples: Vec<EmbeddingSample>) -> Self {
+		let mut errors: Vec<f32> = samples.iter().map(|s|s.error).collect();
+		errors.sort_by(|a, b|a.partial_cmp(b).unwrap());
+		// Nearest-rank percentile
+		let percentile = |p: f32| if errors.is_empty() { 0.0 } else { errors[((p * errors.len() as f32).ceil() as usize).clamp(1, errors.len()) - 1] };
+		Self {
+			tick,
+			mean: if errors.is_empty() { 0.0 } else { errors.iter().sum::<f32>() / errors.len() as f32 },
+			median: percentile(0.5),
+			p90: percentile(0.9),
+			max: errors.last().cloned().unwrap_or(0.0),
+			samples,
+		}
+	}
+	/// Error of the distance between every pair of nodes: how far the distance between their RouteCoords is from the distance between their real positions.
+	/// Rows and columns are in the same order as `samples`.
+	pub fn pairwise_errors(&self) -> Vec<Vec<f32>> {
+		self.samples.iter().map(|a|{
+			self.samples.iter().map(|b|{
+				(nalgebra::distance(&a.route_coord, &b.route_coord) - nalgebra::distance(&a.position, &b.position)).abs()
+			}).collect()
+		}).collect()
+	}
+}
+impl std::fmt::Display for EmbeddingReport {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "[{: >6}] {} nodes, embedding error mean: {:.2}, median: {:.2}, p90: {:.2}, max: {:.2}",
+			self.tick, self.samples.len(), self.mean, self.median, self.p90, self.max)
+	}
+}
+
+impl InternetSim<Node> {
+	/// Compare every node's RouteCoord to its real position, nodes without a RouteCoord are left out
+	pub fn embedding_report(&self) -> EmbeddingReport {
+		let samples = self.nodes.iter().filter_map(|(net_id, node)|{
+			let position = self.router.node_map.get(net_id)?.position;
+			let route_coord = node.route_coord?.map(|c|c as f32);
+			Some(EmbeddingSample { node_id: node.node_id, position, route_coord, error: nalgebra::distance(&position, &route_coord) })
+		}).collect();
+		EmbeddingReport::from_samples(self.ticks, samples)
+	}
+}
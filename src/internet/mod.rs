@@ -1,34 +1,70 @@
 //#![allow(dead_code)]
 
-use std::{collections::HashMap, fmt::Debug};
+use std::{collections::{HashMap, BTreeMap}, fmt::Debug};
 use std::any::Any;
-use std::ops::Range;
 
-use rand::Rng;
+use rand::{Rng, SeedableRng};
+use serde::{Serialize, de::DeserializeOwned};
 use petgraph::Graph;
 use nalgebra::Point2;
 use plotters::style::RGBColor;
+use rayon::prelude::*;
 use smallvec::SmallVec;
 
 mod router;
 use router::InternetRouter;
+pub use router::LatencyModel;
+pub mod trace;
+pub mod routing_metrics;
+pub mod embedding;
+pub mod animation;
+pub mod traversal;
+pub mod session_stats;
+use animation::AnimationRecorder;
+use trace::{TraceEntry, TraceRecorder};
+use traversal::{TraversalRecorder, TraversalPath};
 
-use crate::node::{Node, NodeID, RouteCoord};
-
-pub const FIELD_DIMENSIONS: (Range<i32>, Range<i32>) = (-320..320, -130..130);
+use crate::node::{Node, NodeID, RouteCoord, RouteScalar, RendezvousDescriptor, DescriptorKey, TraverseHeader};
+use crate::config::SimConfig;
+use crate::plot::{GraphPlottable, PlotNode, PlotEdge};
+use crate::metrics::{MetricKey, MetricsRecorder};
 
 pub type InternetID = u128;
 pub type PacketVec = SmallVec<[InternetPacket; 32]>;
+/// Seedable RNG used to drive the simulation, serializable so that it can be saved in snapshots
+pub type SimRng = rand_xoshiro::Xoshiro256PlusPlus;
+/// Plots a simulation, kept so that generic simulation code can plot it
+type GraphFn<CN> = fn(&InternetSim<CN>) -> Graph<PlotNode, PlotEdge>;
 
-#[derive(Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub enum InternetRequest {
 	RouteCoordDHTRead(NodeID),
 	RouteCoordDHTWrite(NodeID, RouteCoord),
+	RouteCoordDHTDelete(NodeID), // Sent by a node that stopped being public, answered with RouteCoordDHTWriteResponse
 	RouteCoordDHTReadResponse(NodeID, Option<RouteCoord>),
 	RouteCoordDHTWriteResponse(Option<(NodeID, RouteCoord)>),
+	DescriptorDHTRead(NodeID),
+	DescriptorDHTWrite(RendezvousDescriptor),
+	DescriptorDHTReadResponse(NodeID, Option<RendezvousDescriptor>),
+	DescriptorDHTWriteResponse(bool), // false if the descriptor's MAC didn't match the key of the node it describes
+}
+impl InternetRequest {
+	pub fn kind(&self) -> &'static str {
+		match self {
+			InternetRequest::RouteCoordDHTRead(..) => "RouteCoordDHTRead",
+			InternetRequest::RouteCoordDHTWrite(..) => "RouteCoordDHTWrite",
+			InternetRequest::RouteCoordDHTDelete(..) => "RouteCoordDHTDelete",
+			InternetRequest::RouteCoordDHTReadResponse(..) => "RouteCoordDHTReadResponse",
+			InternetRequest::RouteCoordDHTWriteResponse(..) => "RouteCoordDHTWriteResponse",
+			InternetRequest::DescriptorDHTRead(..) => "DescriptorDHTRead",
+			InternetRequest::DescriptorDHTWrite(..) => "DescriptorDHTWrite",
+			InternetRequest::DescriptorDHTReadResponse(..) => "DescriptorDHTReadResponse",
+			InternetRequest::DescriptorDHTWriteResponse(..) => "DescriptorDHTWriteResponse",
+		}
+	}
 }
 
-#[derive(Default, Debug)]
+#[derive(Serialize, Deserialize, Default, Debug)]
 pub struct InternetPacket {
 	pub dest_addr: InternetID,
 	pub data: Vec<u8>,
@@ -41,108 +77,526 @@ pub trait CustomNode: std::fmt::Debug {
 	type CustomNodeAction;
 	fn net_id(&self) -> InternetID;
 	fn tick(&mut self, incoming: PacketVec) -> PacketVec;
+	/// Number of upcoming ticks on which `tick` would do nothing if no packets arrive, None if only an arriving packet can wake the node up
+	fn idle_ticks(&self) -> Option<usize>;
+	/// Let `ticks` idle ticks pass without calling `tick`
+	fn skip(&mut self, ticks: usize);
 	fn action(&mut self, action: Self::CustomNodeAction);
 	fn as_any(&self) -> &dyn Any;
 	fn set_deus_ex_data(&mut self, data: Option<RouteCoord>);
+	/// Give the node its own seeded RNG so that simulations are reproducible
+	fn set_rng(&mut self, rng: SimRng);
+	/// NodeID and the key the node authenticates its RendezvousDescriptors with, given to the DHT when the node is added
+	fn descriptor_key(&self) -> (NodeID, DescriptorKey);
+	/// Decode the type of a packet addressed to this kind of node, used for packet traces
+	fn packet_type(packet: &InternetPacket) -> String where Self: Sized;
+	/// Routing header of a packet if it is a Traverse packet
+	fn traverse_header(packet: &InternetPacket) -> Option<TraverseHeader> where Self: Sized;
+	/// Current value of the metrics this node keeps
+	fn metrics(&self) -> Vec<(MetricKey, f64)>;
 }
 
-#[derive(Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct InternetSim<CN: CustomNode> {
-	pub nodes: HashMap<InternetID, CN>,
+	pub nodes: BTreeMap<InternetID, CN>, // Ordered so that nodes are ticked in a stable order
 	pub router: InternetRouter,
 	route_coord_dht: HashMap<NodeID, RouteCoord>,
+	descriptor_dht: HashMap<NodeID, RendezvousDescriptor>,
+	descriptor_keys: HashMap<NodeID, DescriptorKey>, // Simulation stand-in for the public keys descriptor signatures would be checked with, only the DHT may read it as the secret keys can forge any descriptor
+	pub ticks: usize, // Number of ticks the simulation has run for
+	pub config: SimConfig,
+	#[serde(skip)]
+	trace: Option<TraceRecorder>, // Records every delivered packet if enabled
+	#[serde(skip, default = "Option::default")]
+	animation: Option<(AnimationRecorder, GraphFn<CN>)>, // Plots the network every few ticks if enabled
+	#[serde(skip)]
+	traversals: TraversalRecorder, // Records the paths of Traverse packets sent by selected nodes
+	#[serde(skip)]
+	metrics: Option<MetricsRecorder>, // Samples packet counters and node metrics every few ticks if enabled
 }
 impl<CN: CustomNode> InternetSim<CN> {
-	pub fn new() -> InternetSim<CN> {
+	pub fn new(config: SimConfig) -> InternetSim<CN> {
 		InternetSim {
-			nodes: HashMap::new(),
-			router: InternetRouter::new(FIELD_DIMENSIONS),
+			nodes: BTreeMap::new(),
+			router: InternetRouter::new(config.field_dimensions.clone(), config.latency_variance, config.latency_model.clone(), config.loss_rate),
 			route_coord_dht: HashMap::new(),
+			descriptor_dht: HashMap::new(),
+			descriptor_keys: HashMap::new(),
+			ticks: 0,
+			config,
+			trace: None,
+			animation: None,
+			traversals: TraversalRecorder::default(),
+			metrics: None,
+		}
+	}
+	/// Start recording every packet the router delivers to a trace file, replacing any running trace
+	pub fn start_trace(&mut self, path: &str) -> std::io::Result<()> {
+		self.stop_trace()?;
+		self.trace = Some(TraceRecorder::create(path)?);
+		Ok(())
+	}
+	/// Stop recording packets, returns the path of the trace file and the number of packets recorded
+	pub fn stop_trace(&mut self) -> std::io::Result<Option<(String, usize)>> {
+		if let Some(mut trace) = self.trace.take() {
+			trace.flush()?;
+			Ok(Some((trace.path().to_owned(), trace.recorded)))
+		} else { Ok(None) }
+	}
+	/// Start plotting the network every `every` ticks to a GIF or a directory of frames, replacing any running animation
+	pub fn start_animation(&mut self, path: &str, every: usize, dimensions: (u32, u32)) -> anyhow::Result<()> where Self: GraphPlottable {
+		self.stop_animation()?;
+		self.animation = Some((AnimationRecorder::create(path, every, dimensions, self.ticks)?, <Self as GraphPlottable>::gen_graph));
+		self.capture_frames();
+		Ok(())
+	}
+	/// Stop plotting the network, returns the path of the animation and the number of frames drawn
+	pub fn stop_animation(&mut self) -> anyhow::Result<Option<(String, usize)>> {
+		if let Some((recorder, _)) = self.animation.take() {
+			let result = (recorder.path().to_owned(), recorder.frames);
+			recorder.finish()?;
+			Ok(Some(result))
+		} else { Ok(None) }
+	}
+	/// Start sampling metrics every `every` ticks, replacing any running recorder
+	pub fn start_metrics(&mut self, every: usize) -> anyhow::Result<()> {
+		self.metrics = Some(MetricsRecorder::new(every, self.ticks)?);
+		self.sample_metrics();
+		Ok(())
+	}
+	/// Stop sampling metrics, returns the recorder with every sample taken
+	pub fn stop_metrics(&mut self) -> Option<MetricsRecorder> { self.metrics.take() }
+	pub fn metrics(&self) -> Option<&MetricsRecorder> { self.metrics.as_ref() }
+	/// Take metrics samples that are due
+	fn sample_metrics(&mut self) {
+		if let Some(metrics) = &mut self.metrics {
+			if metrics.next_tick > self.ticks { return }
+			metrics.sample(self.ticks, self.nodes.values().flat_map(|node|node.metrics()).collect());
+		}
+	}
+	/// Record the path of every Traverse packet sent by a node from now on
+	pub fn track_traversals(&mut self, origin: NodeID) {
+		if let Some((&net_id, _)) = self.nodes.iter().find(|(_,node)|node.descriptor_key().0 == origin) { self.traversals.origins.insert(net_id, origin); }
+	}
+	/// Paths of Traverse packets sent by tracked nodes, oldest first
+	pub fn traversal_paths(&self) -> &std::collections::VecDeque<TraversalPath> { &self.traversals.paths }
+	/// Draw animation frames that are due
+	fn capture_frames(&mut self) {
+		if let Some((mut recorder, gen_graph)) = self.animation.take() {
+			if recorder.next_tick > self.ticks { self.animation = Some((recorder, gen_graph)); return }
+			match recorder.capture(self.ticks, &gen_graph(self), &self.router.field_dimensions) {
+				Ok(()) => self.animation = Some((recorder, gen_graph)),
+				Err(err) => log::error!("Stopped animation {} after failing to draw frame: {}", recorder.path(), err),
+			}
 		}
 	}
 	pub fn lease(&self) -> InternetID { self.nodes.len() as InternetID }
-	pub fn add_node(&mut self, node: CN, rng: &mut impl Rng) {
+	pub fn add_node(&mut self, mut node: CN, rng: &mut impl Rng) {
 		self.router.add_node(node.net_id(), rng);
+		node.set_rng(SimRng::seed_from_u64(rng.gen()));
+		let (node_id, key) = node.descriptor_key();
+		self.descriptor_keys.insert(node_id, key);
 		self.nodes.insert(node.net_id(), node);
 	}
 	pub fn del_node(&mut self, net_id: InternetID) { self.nodes.remove(&net_id); }
 	pub fn node_mut(&mut self, net_id: InternetID) -> Option<&mut CN> { self.nodes.get_mut(&net_id) }
 	pub fn node(&self, net_id: InternetID) -> Option<&CN> { self.nodes.get(&net_id) }
-	pub fn tick(&mut self, ticks: usize, rng: &mut impl Rng) {
-		//let packets_tmp = Vec::new();
-		for _ in 0..ticks {
-			for (&node_net_id, node) in self.nodes.iter_mut() {
-				// Get Packets going to node
-				let incoming_packets = self.router.tick_node(node_net_id);
-				// Get packets coming from node
-				let mut outgoing_packets = node.tick(incoming_packets);
-
-				// Make outgoing packets have the correct return address or parse request
-				for packet in &mut outgoing_packets {
-					packet.src_addr = node_net_id;
-					if let Some(request) = &packet.request {
-						log::debug!("InternetID({:?}) Requested InternetRequest::{:?}", node_net_id, request);
-						packet.request = Some(match *request {
-							InternetRequest::RouteCoordDHTRead(node_id) => {
-								packet.dest_addr = packet.src_addr;
-								let route = self.route_coord_dht.get(&node_id).map(|r|r.clone());
-								InternetRequest::RouteCoordDHTReadResponse(node_id, route)
-							},
-							InternetRequest::RouteCoordDHTWrite(node_id, route_coord) => {
-								packet.dest_addr = packet.src_addr;
-								let old_route = self.route_coord_dht.insert(node_id, route_coord);
-								InternetRequest::RouteCoordDHTWriteResponse( old_route.map(|r|(node_id, r) ))
-							}
-							_ => { log::error!("Invalid InternetRequest variant"); unimplemented!() },
-						});
+	/// Save the whole simulation along with the RNG driving it so that it can be resumed with `load_snapshot`
+	pub fn save_snapshot(&self, rng: &SimRng, path: &str) -> anyhow::Result<()> where CN: Serialize {
+		let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+		bincode::serialize_into(file, &(self, rng))?;
+		Ok(())
+	}
+	/// Load a simulation saved with `save_snapshot`, returns the simulation and the RNG to keep ticking it with
+	pub fn load_snapshot(path: &str) -> anyhow::Result<(Self, SimRng)> where CN: DeserializeOwned {
+		let file = std::io::BufReader::new(std::fs::File::open(path)?);
+		Ok(bincode::deserialize_from(file)?)
+	}
+	/// Run the simulation for `ticks` ticks. Ticks on which no packet arrives and no node has anything to do are skipped over
+	/// if `SimConfig::skip_idle` is set, the result is the same as ticking through them.
+	pub fn tick(&mut self, ticks: usize, rng: &mut impl Rng) where CN: Send {
+		let end = self.ticks + ticks;
+		while self.ticks < end {
+			let next_event = if self.config.skip_idle { self.next_event().map_or(end, |t|t.min(end)) } else { self.ticks };
+			if next_event > self.ticks {
+				let idle = next_event - self.ticks;
+				for node in self.nodes.values_mut() { node.skip(idle); }
+				self.ticks = next_event;
+			} else {
+				self.step(rng);
+			}
+			self.capture_frames();
+			self.sample_metrics();
+		}
+	}
+	/// Earliest tick on which a packet arrives or a node has something to do, None if the simulation is idle forever
+	pub fn next_event(&mut self) -> Option<usize> {
+		let wakeup = self.nodes.values().filter_map(|n|n.idle_ticks()).min().map(|idle|self.ticks + idle);
+		let arrival = self.router.next_arrival(self.ticks);
+		wakeup.into_iter().chain(arrival).min()
+	}
+	/// Run a single tick. Every node's incoming packets are taken from the router, then the nodes with something to do are ticked
+	/// (in parallel if `SimConfig::parallel` is set) and finally their outgoing packets are routed in order of InternetID,
+	/// so that the result for a given seed doesn't depend on whether or how the nodes were ticked in parallel.
+	fn step(&mut self, rng: &mut impl Rng) where CN: Send {
+		// Collect packets arriving this tick
+		let mut ticking: Vec<(InternetID, &mut CN, PacketVec)> = Vec::with_capacity(self.nodes.len());
+		for (&node_net_id, node) in self.nodes.iter_mut() {
+			let incoming_packets = self.router.tick_node(node_net_id, self.ticks);
+			if self.config.skip_idle && incoming_packets.is_empty() && node.idle_ticks() != Some(0) { node.skip(1); continue }
+			if let Some(trace) = &mut self.trace {
+				for packet in &incoming_packets {
+					let entry = TraceEntry { tick: self.ticks, src: packet.src_addr, dest: packet.dest_addr, packet_type: CN::packet_type(packet), bytes: packet.data.len() };
+					if let Err(err) = trace.record(&entry) { log::error!("Failed to record packet trace: {}", err) }
+				}
+			}
+			if let Some(metrics) = &mut self.metrics {
+				for packet in &incoming_packets {
+					metrics.count(MetricKey::new("packets_received_total").node(node_net_id).kind(CN::packet_type(packet)), 1.);
+					metrics.count(MetricKey::new("bytes_received_total").node(node_net_id), packet.data.len() as f64);
+				}
+			}
+			if !self.traversals.origins.is_empty() {
+				for packet in &incoming_packets {
+					let header = if let Some(header) = CN::traverse_header(packet) { header } else { continue };
+					if let (Some(from), Some(to)) = (self.router.node_map.get(&packet.src_addr), self.router.node_map.get(&packet.dest_addr)) {
+						self.traversals.record(self.ticks, &header, (packet.src_addr, from.position), (packet.dest_addr, to.position));
 					}
 				}
-				// Send packets through the router
-				self.router.add_packets(outgoing_packets, rng);
-				if let Some(rn) = self.router.node_map.get(&node_net_id) {
-					let cheat_coord = rn.position.clone().map(|s|s.floor() as i64);
-					node.set_deus_ex_data( Some(cheat_coord) ) }
 			}
+			ticking.push((node_net_id, node, incoming_packets));
+		}
+		self.router.clear_schedule(self.ticks);
+
+		// Tick nodes, they only touch their own state
+		let node_map = &self.router.node_map;
+		let tick_node = |(node_net_id, node, incoming_packets): (InternetID, &mut CN, PacketVec)| {
+			let outgoing_packets = node.tick(incoming_packets);
+			if let Some(rn) = node_map.get(&node_net_id) {
+				let cheat_coord = rn.position.clone().map(|s|s.floor() as i64);
+				node.set_deus_ex_data( Some(cheat_coord) ) }
+			(node_net_id, outgoing_packets)
+		};
+		let outgoing: Vec<(InternetID, PacketVec)> = if self.config.parallel {
+			ticking.into_par_iter().map(tick_node).collect()
+		} else {
+			ticking.into_iter().map(tick_node).collect()
+		};
+
+		// Merge outgoing packets in a fixed order
+		for (node_net_id, mut outgoing_packets) in outgoing {
+			// Make outgoing packets have the correct return address or parse request
+			for packet in &mut outgoing_packets {
+				packet.src_addr = node_net_id;
+				if let Some(metrics) = &mut self.metrics {
+					let packet_type = CN::packet_type(packet);
+					if packet.request.is_some() { metrics.count(MetricKey::new("dht_requests_total").kind(packet_type.clone()), 1.); }
+					metrics.count(MetricKey::new("packets_sent_total").node(node_net_id).kind(packet_type), 1.);
+					metrics.count(MetricKey::new("bytes_sent_total").node(node_net_id), packet.data.len() as f64);
+				}
+				if let Some(request) = &packet.request {
+					log::debug!("InternetID({:?}) Requested InternetRequest::{:?}", node_net_id, request);
+					packet.request = Some(match request {
+						&InternetRequest::RouteCoordDHTRead(node_id) => {
+							packet.dest_addr = packet.src_addr;
+							let route = self.route_coord_dht.get(&node_id).copied();
+							InternetRequest::RouteCoordDHTReadResponse(node_id, route)
+						},
+						&InternetRequest::RouteCoordDHTWrite(node_id, route_coord) => {
+							packet.dest_addr = packet.src_addr;
+							let old_route = self.route_coord_dht.insert(node_id, route_coord);
+							InternetRequest::RouteCoordDHTWriteResponse( old_route.map(|r|(node_id, r) ))
+						}
+						&InternetRequest::RouteCoordDHTDelete(node_id) => {
+							packet.dest_addr = packet.src_addr;
+							let old_route = self.route_coord_dht.remove(&node_id);
+							InternetRequest::RouteCoordDHTWriteResponse( old_route.map(|r|(node_id, r) ))
+						}
+						&InternetRequest::DescriptorDHTRead(node_id) => {
+							packet.dest_addr = packet.src_addr;
+							InternetRequest::DescriptorDHTReadResponse(node_id, self.descriptor_dht.get(&node_id).cloned())
+						},
+						InternetRequest::DescriptorDHTWrite(descriptor) => {
+							packet.dest_addr = packet.src_addr;
+							// Only accept descriptors authenticated with the key of the node they describe
+							let valid = self.descriptor_keys.get(&descriptor.node_id).is_some_and(|key|descriptor.authenticate(key));
+							if valid { self.descriptor_dht.insert(descriptor.node_id, descriptor.clone()); }
+							InternetRequest::DescriptorDHTWriteResponse(valid)
+						}
+						_ => { log::error!("Invalid InternetRequest variant"); unimplemented!() },
+					});
+				}
+			}
+			// Send packets through the router
+			self.router.add_packets(outgoing_packets, self.ticks, rng);
 		}
+		self.ticks += 1;
 	}
 }
 
-use crate::plot::GraphPlottable;
 impl GraphPlottable for InternetSim<Node> {
-	fn gen_graph(&self) -> Graph<(String, Point2<i32>), RGBColor> {
+	fn gen_graph(&self) -> Graph<PlotNode, PlotEdge> {
 		//let root = BitMapBackend::new(path, dimensions).into_drawing_area();
 		/* for (idx, node) in &self.nodes {
 
 		} */
 		use petgraph::data::{FromElements, Element};
-		let nodes: Vec<Element<(String, Point2<i32>),RGBColor>> = self.router.node_map.iter().map(|(&net_id, lc)|{
+		let nodes: Vec<Element<PlotNode, PlotEdge>> = self.router.node_map.iter().map(|(&net_id, lc)|{
+			let plot_node = PlotNode::new(net_id.to_string(), lc.position.map(|i|i as i32));
 			Element::Node {
-				weight: (
-					net_id.to_string(),
-					lc.position.map(|i|i as i32),
-				)
+				weight: match self.nodes.get(&net_id) {
+					Some(node) => plot_node.with_info(node_info(net_id, lc.position, node)),
+					None => plot_node.with_info(format!("InternetID: {}\nPosition: ({:.0}, {:.0})", net_id, lc.position[0], lc.position[1])),
+				}
 			}
 		}).collect();
 
 		let node_idx_map = &self.router.node_map.iter().enumerate().map(|(idx,(&id,_))|(id,idx)).collect::<HashMap<InternetID,usize>>();
 
-		let edges = self.nodes.iter().enumerate().map(|(_, (net_id, node))|{
+		let edges = self.nodes.iter().flat_map(|(net_id, node)|{
 			node.node_list.iter().filter_map(move |(_,&remote_id)|{
 				// Get Net ID and set color based on peerage
-				node.remotes[&remote_id].session().ok().map(|s| s.direct().ok().map(|d|{
+				node.remotes[&remote_id].session().ok().and_then(|s| s.direct().ok().map(|d|{
 					let color = if node.peer_list.contains_left(&remote_id) { RGBColor(0,0,0) } else { RGBColor(255,255,255) };
 					(d.net_id, color)
-				})).flatten()
+				}))
 
 			}).map(move |(remote_net_id, color)|{
 				Element::Edge {
-					source: node_idx_map[&net_id],
+					source: node_idx_map[net_id],
 					target: node_idx_map[&remote_net_id],
-					weight: color,
+					weight: color.into(),
 				}
 			})
-		}).flatten();
-		let graph = Graph::from_elements(nodes.into_iter().chain(edges));
-		graph
+		});
+		Graph::from_elements(nodes.into_iter().chain(edges))
+	}
+}
+
+/// Describe a node for interactive plots: its IDs, position, RouteCoord, peers and session statistics
+fn node_info(net_id: InternetID, position: Point2<f32>, node: &Node) -> String {
+	let sessions = node.sessions.iter().filter_map(|(_, id)|node.remotes.get(id)?.session().ok());
+	let (mut direct, mut routed, mut pings, mut dist_sum) = (0, 0, 0, 0);
+	for session in sessions {
+		if session.direct().is_ok() { direct += 1 } else { routed += 1 }
+		pings += session.tracker.ping_count;
+		if session.is_peer() { dist_sum += session.tracker.dist_avg }
+	}
+	let peers = node.peer_list.iter().map(|(id, _)|id.to_string()).collect::<Vec<String>>();
+	let route_coord = node.route_coord.map(|c|format!("({}, {})", c[0], c[1])).unwrap_or_else(||"None".to_owned());
+	format!("NodeID: {}\nInternetID: {}\nPosition: ({:.0}, {:.0})\nRouteCoord: {}\nPeers ({}): {}\nSessions: {} direct, {} routed\nMean peer distance: {}\nPings: {}",
+		node.node_id, net_id, position[0], position[1], route_coord, peers.len(), peers.join(", "), direct, routed,
+		if peers.is_empty() { "None".to_owned() } else { (dist_sum / peers.len() as RouteScalar).to_string() }, pings)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+	use super::*;
+	use crate::scenario::{Assertion, BootstrapPlan, NodeSet, Output, Scenario, ScenarioAction, TimedEvent};
+	use crate::node::{ActionFailure, NodeAction, NodeEvent};
+
+	/// Bootstrap a small network and return the simulation along with the RNG to keep ticking it with
+	pub(crate) fn bootstrapped(config: SimConfig, seed: u64) -> (InternetSim<Node>, SimRng) {
+		let scenario = Scenario { seed, sim: config.clone(), nodes: NodeSet::Count(8), bootstrap: BootstrapPlan { onto: 0, interval: 200, settle: 1500 }, ..Default::default() };
+		let (_, finished) = scenario.run_then(|internet, rng|(std::mem::replace(internet, InternetSim::new(config)), rng.clone())).unwrap();
+		finished
+	}
+	/// Path of a file in the temporary directory, unique to this test process
+	pub(crate) fn temp_path(name: &str) -> String {
+		std::env::temp_dir().join(format!("dither_{}_{}", std::process::id(), name)).to_string_lossy().into_owned()
+	}
+	/// Bootstrap a small network, send a reliable Traverse packet through it and return the trace of every packet delivered from the start
+	pub(crate) fn scenario_trace(config: SimConfig, seed: u64, name: &str) -> String {
+		let path = temp_path(name);
+		let scenario = Scenario {
+			seed, sim: config, nodes: NodeSet::Count(8),
+			bootstrap: BootstrapPlan { onto: 0, interval: 200, settle: 1500 },
+			events: vec![TimedEvent { at: 0, action: ScenarioAction::Traverse { from: 1, to: 6, data: 42, reliable: true, when: None } }],
+			duration: Some(2000),
+			outputs: vec![Output::Trace { path: path.clone() }],
+			..Default::default()
+		};
+		scenario.run().unwrap();
+		let trace = std::fs::read_to_string(&path).unwrap();
+		std::fs::remove_file(&path).unwrap();
+		trace
+	}
+	/// Send a Traverse packet, then run for `ticks` ticks and return every packet delivered in the meantime
+	pub(crate) fn traced_run(internet: &mut InternetSim<Node>, rng: &mut SimRng, ticks: usize, name: &str) -> Vec<TraceEntry> {
+		let path = temp_path(name);
+		internet.start_trace(&path).unwrap();
+		internet.node_mut(1).unwrap().action(crate::node::NodeAction::Traverse(6, 42));
+		internet.tick(ticks, rng);
+		internet.stop_trace().unwrap();
+		let entries = trace::read_trace(&path).unwrap();
+		std::fs::remove_file(&path).unwrap();
+		entries
+	}
+	/// State of every node that the protocol should arrive at deterministically: NodeID, tick, RouteCoord and peers
+	pub(crate) fn node_states(internet: &InternetSim<Node>) -> Vec<(NodeID, usize, Option<RouteCoord>, Vec<NodeID>)> {
+		internet.nodes.values().map(|node|{
+			let mut peers: Vec<NodeID> = node.peer_list.left_values().cloned().collect();
+			peers.sort_unstable();
+			(node.node_id, node.ticks, node.route_coord, peers)
+		}).collect()
+	}
+
+	#[test]
+	fn snapshot_round_trip() {
+		let config = SimConfig { latency_model: LatencyModel::ScaledDistance { factor: 1.5 }, ..Default::default() };
+		let (internet, rng) = bootstrapped(config, 1);
+		let path = temp_path("round_trip.snapshot");
+		internet.save_snapshot(&rng, &path).unwrap();
+		let (loaded, loaded_rng) = InternetSim::<Node>::load_snapshot(&path).unwrap();
+		std::fs::remove_file(&path).unwrap();
+
+		assert_eq!(loaded.config, internet.config);
+		assert_eq!(loaded.router.latency_model, internet.router.latency_model);
+		assert_eq!(loaded.ticks, internet.ticks);
+		assert_eq!(loaded_rng, rng);
+		assert_eq!(loaded.nodes.keys().collect::<Vec<_>>(), internet.nodes.keys().collect::<Vec<_>>());
+		for (loaded, node) in loaded.nodes.values().zip(internet.nodes.values()) {
+			assert_eq!((loaded.node_id, loaded.route_coord, loaded.ticks), (node.node_id, node.route_coord, node.ticks));
+		}
+	}
+
+	#[test]
+	fn snapshot_resumes_identically() {
+		let (mut internet, mut rng) = bootstrapped(SimConfig::default(), 2);
+		let path = temp_path("resume.snapshot");
+		internet.save_snapshot(&rng, &path).unwrap();
+		let (mut restored, mut restored_rng) = InternetSim::<Node>::load_snapshot(&path).unwrap();
+		std::fs::remove_file(&path).unwrap();
+
+		let original_trace = traced_run(&mut internet, &mut rng, 3000, "resume_original.trace");
+		let restored_trace = traced_run(&mut restored, &mut restored_rng, 3000, "resume_restored.trace");
+		assert!(!original_trace.is_empty());
+		assert_eq!(restored_trace, original_trace);
+		assert_eq!(restored.ticks, internet.ticks);
+		assert_eq!(restored_rng, rng);
+		assert_eq!(node_states(&restored), node_states(&internet));
+	}
+
+	#[test]
+	fn same_seed_same_trace() {
+		let first = scenario_trace(SimConfig::default(), 3, "seed_first.trace");
+		assert!(!first.is_empty());
+		assert_eq!(scenario_trace(SimConfig::default(), 3, "seed_second.trace"), first);
+		assert_ne!(scenario_trace(SimConfig::default(), 4, "seed_other.trace"), first);
+	}
+
+	#[test]
+	fn parallel_step_matches_sequential() {
+		for skip_idle in [false, true] {
+			let sequential = scenario_trace(SimConfig { parallel: false, skip_idle, ..Default::default() }, 5, "sequential.trace");
+			let parallel = scenario_trace(SimConfig { parallel: true, skip_idle, ..Default::default() }, 5, "parallel.trace");
+			assert!(!sequential.is_empty());
+			assert_eq!(parallel, sequential, "skip_idle: {}", skip_idle);
+		}
+	}
+
+	#[test]
+	fn skipping_idle_ticks_matches_ticking_every_tick() {
+		for parallel in [false, true] {
+			let every_tick = scenario_trace(SimConfig { skip_idle: false, parallel, ..Default::default() }, 6, "every_tick.trace");
+			let skipping = scenario_trace(SimConfig { skip_idle: true, parallel, ..Default::default() }, 6, "skip_idle.trace");
+			assert!(!every_tick.is_empty());
+			assert_eq!(skipping, every_tick, "parallel: {}", parallel);
+		}
+	}
+
+	#[test]
+	fn measuring_routing_leaves_other_events() {
+		let (mut internet, mut rng) = bootstrapped(SimConfig::default(), 7);
+		let event = crate::node::NodeEvent::TraverseReceived(3, 7, 2);
+		internet.node_mut(4).unwrap().events.push_back(event.clone());
+		let report = internet.measure_routing(5, 1000, &mut rng);
+		assert!(!report.samples.is_empty());
+		assert!(internet.nodes.values().all(|node|node.events.iter().all(|e|e == &event)));
+		assert_eq!(internet.node_mut(4).unwrap().take_events(), vec![event]);
+	}
+
+	#[test]
+	fn latency_model_config_is_tagged() {
+		let config: SimConfig = toml::from_str("latency_model = { type = \"constant\", ticks = 40 }").unwrap();
+		assert_eq!(config.latency_model, LatencyModel::Constant { ticks: 40 });
+		assert_eq!(serde_json::to_value(&config.latency_model).unwrap(), serde_json::json!({ "type": "constant", "ticks": 40 }));
+	}
+
+	#[test]
+	fn scenario_keeps_failed_actions() {
+		let mut config = SimConfig::default();
+		config.node.action_timeout = 300;
+		let scenario = Scenario {
+			seed: 2, sim: config, nodes: NodeSet::Count(4),
+			bootstrap: BootstrapPlan { onto: 0, interval: 200, settle: 1000 },
+			// NodeID 99 doesn't exist, so its RouteCoord never arrives and the Traverse expires
+			events: vec![TimedEvent { at: 0, action: ScenarioAction::Traverse { from: 1, to: 99, data: 5, reliable: false, when: None } }],
+			duration: Some(1000),
+			assertions: vec![Assertion::NoFailedActions { node: Some(2) }, Assertion::NoFailedActions { node: Some(1) }],
+			..Default::default()
+		};
+		let result = scenario.run().unwrap();
+		assert_eq!(result.assertions.iter().map(|(_, _, passed)|*passed).collect::<Vec<_>>(), vec![true, false]);
+		assert!(result.events.iter().any(|(_, id, event)|*id == 1 && matches!(event, NodeEvent::ActionFailed(NodeAction::Condition(_, action), ActionFailure::Expired) if **action == NodeAction::Traverse(99, 5))));
+	}
+
+	#[test]
+	fn private_node_is_reached_through_routed_introducers() {
+		let (mut internet, mut rng, private_net_id) = private_with_introducers(20);
+		let private_id = internet.node(private_net_id).unwrap().node_id;
+		let client_net_id = 5;
+		let client_id = internet.node(client_net_id).unwrap().node_id;
+		internet.node_mut(client_net_id).unwrap().action(crate::node::NodeAction::Rendezvous(private_id, 77));
+		internet.tick(1000, &mut rng);
+		assert!(internet.node(private_net_id).unwrap().rendezvous.clients.contains_key(&client_id));
+	}
+
+	#[test]
+	fn unanswered_introducer_is_replaced() {
+		let (mut internet, mut rng, private_net_id) = private_with_introducers(40);
+		let private_id = internet.node(private_net_id).unwrap().node_id;
+		let (gone, _) = internet.node(private_net_id).unwrap().rendezvous.introducers.clone().unwrap()[0];
+		let gone_net_id = internet.nodes.values().find(|n|n.node_id == gone).unwrap().net_id;
+		internet.del_node(gone_net_id);
+		// The first refresh goes unanswered, the next one drops the introducer and asks for another
+		let interval = internet.node(private_net_id).unwrap().config.introducer_refresh_interval;
+		internet.tick(2 * interval + 1000, &mut rng);
+		let introducers = internet.node(private_net_id).unwrap().rendezvous.introducers.clone().unwrap();
+		assert!(!introducers.iter().any(|(id,_)|*id == gone));
+		assert_eq!(introducers.len(), internet.node(private_net_id).unwrap().config.introducer_count);
+		assert_eq!(internet.descriptor_dht[&private_id].introducers, introducers);
+		// Introducers that are still there took the refreshed paths
+		for (introducer, _) in &introducers {
+			let introducer = internet.nodes.values().find(|n|n.node_id == *introducer).unwrap();
+			let handle = introducer.rendezvous.introducing[&private_id].last().unwrap();
+			assert!(introducer.traverse_hops[handle].used_at + 2 * interval > internet.ticks);
+		}
+	}
+
+	/// Run a network of `node_count` nodes and take node 12 private, returns once it has introducers
+	fn private_with_introducers(node_count: usize) -> (InternetSim<Node>, SimRng, InternetID) {
+		// Big enough that the private node has nodes it never had a session with
+		let scenario = Scenario { seed: 1, nodes: NodeSet::Count(node_count), bootstrap: BootstrapPlan { onto: 0, interval: 200, settle: 5000 }, ..Default::default() };
+		let (_, (mut internet, mut rng)) = scenario.run_then(|internet, rng|(std::mem::replace(internet, InternetSim::new(SimConfig::default())), rng.clone())).unwrap();
+		let private_net_id = 12;
+		let private_id = internet.node(private_net_id).unwrap().node_id;
+		assert!(internet.route_coord_dht.contains_key(&private_id));
+
+		// Going private takes down the published RouteCoord and asks nodes it has no session with to introduce it
+		let private = internet.node_mut(private_net_id).unwrap();
+		private.is_public = false;
+		private.config.introducer_refresh_interval = 3000;
+		private.action(crate::node::NodeAction::CalculatePeers);
+		internet.tick(1000, &mut rng);
+		assert!(!internet.route_coord_dht.contains_key(&private_id));
+		let private = internet.node(private_net_id).unwrap();
+		let introducers = private.rendezvous.introducers.clone().unwrap();
+		assert!(!introducers.is_empty());
+		for (introducer, _) in &introducers {
+			assert!(private.remotes.get(introducer).is_none_or(|r|r.session.is_none()), "introducer NodeID({}) has a session with the private node", introducer);
+			assert!(internet.nodes.values().find(|n|n.node_id == *introducer).unwrap().remotes.get(&private_id).is_none_or(|r|r.session.is_none()));
+		}
+		(internet, rng, private_net_id)
 	}
 }
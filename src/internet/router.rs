@@ -1,10 +1,10 @@
 
-use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet, BTreeMap};
 use std::ops::Range;
 
 use crate::internet::{InternetID, InternetPacket, PacketVec};
 
-const VARIANCE: isize = 2;
 use nalgebra::Point2;
 use rand::Rng;
 
@@ -13,7 +13,60 @@ pub trait LatencyCalculator: Default {
 	fn new(rng: &mut impl rand::Rng) -> Self;
 	fn generate(&self, other: &Self, rng: &mut impl rand::Rng) -> usize;
 } */
-#[derive(Debug)]
+
+/// How the latency of a packet is calculated from the positions of its source and destination.
+/// Written tagged by a `type` field in configuration files (e.g. `{ type = "constant", ticks = 40 }`) and externally tagged in snapshots,
+/// as bincode can't read internally tagged enums.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(remote = "Self")]
+pub enum LatencyModel {
+	/// Latency is the distance between the nodes
+	#[default]
+	Distance,
+	/// Latency is the distance between the nodes multiplied by `factor`
+	ScaledDistance { factor: f64 },
+	/// Every packet takes the same number of ticks no matter the distance
+	Constant { ticks: isize },
+}
+/// Form of `LatencyModel` in human readable formats such as TOML and JSON
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "LatencyModel", tag = "type", rename_all = "snake_case")]
+enum TaggedLatencyModel {
+	Distance,
+	ScaledDistance { factor: f64 },
+	Constant { ticks: isize },
+}
+impl serde::Serialize for LatencyModel {
+	fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		if serializer.is_human_readable() { TaggedLatencyModel::serialize(self, serializer) } else { LatencyModel::serialize(self, serializer) }
+	}
+}
+impl<'de> serde::Deserialize<'de> for LatencyModel {
+	fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		if deserializer.is_human_readable() { TaggedLatencyModel::deserialize(deserializer) } else { LatencyModel::deserialize(deserializer) }
+	}
+}
+impl LatencyModel {
+	/// Latency of a packet travelling `distance`, before variance is added
+	pub fn latency(&self, distance: isize) -> isize {
+		match *self {
+			LatencyModel::Distance => distance,
+			LatencyModel::ScaledDistance { factor } => (distance as f64 * factor) as isize,
+			LatencyModel::Constant { ticks } => ticks,
+		}
+	}
+}
+impl std::fmt::Display for LatencyModel {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			LatencyModel::Distance => write!(f, "distance"),
+			LatencyModel::ScaledDistance { factor } => write!(f, "scaled_distance({})", factor),
+			LatencyModel::Constant { ticks } => write!(f, "constant({})", ticks),
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug)]
 pub struct RouterNode {
 	pub uuid: InternetID,
 	pub variance: isize,
@@ -21,66 +74,143 @@ pub struct RouterNode {
 	pub distance_cache: HashMap<InternetID, isize>,
 }
 impl RouterNode {
-	fn random(uuid: InternetID, range: &(Range<i32>, Range<i32>), rng: &mut impl Rng) -> Self {
+	fn random(uuid: InternetID, range: &(Range<i32>, Range<i32>), variance: isize, rng: &mut impl Rng) -> Self {
 		// let radius = AREA/2;
 		Self {
 			uuid,
-			variance: VARIANCE,
+			variance,
 			position: Point2::new(rng.gen_range(range.0.clone()), rng.gen_range(range.1.clone())).map(|d|d as f32),
 			distance_cache: HashMap::new(),
 		}
 	}
-	fn generate(&mut self, other_uuid: InternetID, other_position: Point2<f32>, rng: &mut impl Rng) -> isize {
+	fn generate(&mut self, other_uuid: InternetID, other_position: Point2<f32>, model: &LatencyModel, rng: &mut impl Rng) -> isize {
 		let dist = *self.distance_cache.entry(other_uuid).or_insert(nalgebra::distance(&self.position, &other_position) as isize);
-		dist as isize + rng.gen_range(-self.variance..self.variance)
+		let latency = model.latency(dist);
+		if self.variance > 0 { latency + rng.gen_range(-self.variance..self.variance) } else { latency }
 	}
 }
 
+/// A packet waiting in the router
+#[derive(Serialize, Deserialize, Debug)]
+pub struct QueuedPacket {
+	/// Tick the packet arrives on
+	pub arrival: usize,
+	/// Packets arriving on the same tick are delivered in the order they were sent
+	sent: u64,
+	pub packet: InternetPacket,
+}
+impl QueuedPacket {
+	fn key(&self) -> Reverse<(usize, u64)> { Reverse((self.arrival, self.sent)) }
+}
+// Ordered so that the earliest arrival is at the top of a `BinaryHeap`
+impl PartialEq for QueuedPacket { fn eq(&self, other: &Self) -> bool { self.key() == other.key() } }
+impl Eq for QueuedPacket {}
+impl PartialOrd for QueuedPacket { fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> { Some(self.cmp(other)) } }
+impl Ord for QueuedPacket { fn cmp(&self, other: &Self) -> std::cmp::Ordering { self.key().cmp(&other.key()) } }
+
 /// Internet router
-#[derive(Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct InternetRouter {
 	pub field_dimensions: (Range<i32>, Range<i32>),
+	/// Random latency variance given to new nodes
+	pub variance: isize,
+	pub latency_model: LatencyModel,
+	/// Probability that a packet between two nodes is dropped
+	pub loss_rate: f64,
 	/// Map linking Node pairs to speed between them (supports differing 2-way speeds)
-	pub node_map: HashMap<InternetID, RouterNode>,
-	/// Map linking destination `Node`s to inbound packets
-	pub packet_map: HashMap<InternetID, Vec<(InternetPacket, isize)>>,
+	pub node_map: BTreeMap<InternetID, RouterNode>,
+	/// Map linking destination `Node`s to inbound packets, earliest arrival first
+	pub packet_map: HashMap<InternetID, BinaryHeap<QueuedPacket>>,
+	/// Number of packets sent, used to keep packets arriving on the same tick in the order they were sent
+	sent: u64,
+	/// Ticks that packets arrive on, earliest first
+	schedule: BinaryHeap<Reverse<usize>>,
+	/// If set, packets between these nodes and the rest of the network are dropped
+	pub partition: Option<HashSet<InternetID>>,
 }
 impl InternetRouter {
-	pub fn new(field_dimensions: (Range<i32>, Range<i32>)) -> Self {
+	pub fn new(field_dimensions: (Range<i32>, Range<i32>), variance: isize, latency_model: LatencyModel, loss_rate: f64) -> Self {
 		Self {
 			field_dimensions,
+			variance,
+			latency_model,
+			loss_rate,
 			node_map: Default::default(),
 			packet_map: Default::default(),
+			sent: 0,
+			schedule: Default::default(),
+			partition: None,
 		}
 	}
+	/// Split the network so that `net_ids` can only reach each other, replacing any existing partition
+	pub fn partition(&mut self, net_ids: HashSet<InternetID>) { self.partition = Some(net_ids); }
+	/// Remove the partition so that every node can reach every other node again
+	pub fn heal(&mut self) { self.partition = None; }
+	fn is_partitioned(&self, src: InternetID, dest: InternetID) -> bool {
+		self.partition.as_ref().is_some_and(|p|p.contains(&src) != p.contains(&dest))
+	}
 	pub fn add_node(&mut self, net_id: InternetID, rng: &mut impl Rng) {
-		self.node_map.entry(net_id).or_insert(RouterNode::random(net_id, &self.field_dimensions, rng));
+		self.node_map.entry(net_id).or_insert(RouterNode::random(net_id, &self.field_dimensions, self.variance, rng));
 	}
-	pub fn add_packets(&mut self, packets: PacketVec, rng: &mut impl Rng) {
+	/// Send packets that were sent on tick `now`, they arrive after their latency (but at least one tick later)
+	pub fn add_packets(&mut self, packets: PacketVec, now: usize, rng: &mut impl Rng) {
 		for packet in packets {
-			let dest = self.node_map.entry(packet.dest_addr).or_insert(RouterNode::random(packet.dest_addr, &self.field_dimensions, rng));
+			if self.is_partitioned(packet.src_addr, packet.dest_addr) {
+				log::debug!("Dropped packet from InternetID({}) to InternetID({}) across partition", packet.src_addr, packet.dest_addr);
+				continue
+			}
+			// DHT requests don't cross the simulated network so they are never lost
+			if packet.request.is_none() && self.loss_rate > 0.0 && rng.gen::<f64>() < self.loss_rate {
+				log::debug!("Lost packet from InternetID({}) to InternetID({})", packet.src_addr, packet.dest_addr);
+				continue
+			}
+			let dest = self.node_map.entry(packet.dest_addr).or_insert(RouterNode::random(packet.dest_addr, &self.field_dimensions, self.variance, rng));
 			let (dest_uuid, dest_position) = (dest.uuid, dest.position);
-			let src = self.node_map.entry(packet.src_addr).or_insert(RouterNode::random(packet.src_addr, &self.field_dimensions, rng));
+			let src = self.node_map.entry(packet.src_addr).or_insert(RouterNode::random(packet.src_addr, &self.field_dimensions, self.variance, rng));
 			
 			// Calculate latency
-			let latency = src.generate(dest_uuid, dest_position, rng);
+			let latency = src.generate(dest_uuid, dest_position, &self.latency_model, rng);
+			let arrival = now + latency.max(1) as usize;
+			self.schedule.push(Reverse(arrival));
 
 			// Add packet to packet stream
-			if let Some(packet_stream) = self.packet_map.get_mut(&packet.dest_addr) {
-				packet_stream.push((packet, latency));
-			} else {
-				self.packet_map
-					.insert(packet.dest_addr, vec![(packet, latency)]);
-			}
+			self.sent += 1;
+			self.packet_map.entry(packet.dest_addr).or_default().push(QueuedPacket { arrival, sent: self.sent, packet });
 		}
 	}
-	pub fn tick_node(&mut self, destination: InternetID) -> PacketVec {
+	/// Take the packets arriving at `destination` on or before tick `now`
+	pub fn tick_node(&mut self, destination: InternetID, now: usize) -> PacketVec {
+		let mut arrived = PacketVec::new();
 		if let Some(packets) = self.packet_map.get_mut(&destination) {
-			packets.iter_mut().for_each(|item| item.1 -= 1); // Decrement ticks
-			// Filter out packets that should be passed
-			packets.drain_filter(|x| x.1 <= 0).map(|x| x.0).collect()
-		} else {
-			return PacketVec::new();
+			while packets.peek().is_some_and(|p|p.arrival <= now) {
+				arrived.push(packets.pop().unwrap().packet);
+			}
 		}
+		arrived
+	}
+	/// Forget the arrival ticks up to and including `now`, their packets have been taken by `tick_node`
+	pub fn clear_schedule(&mut self, now: usize) {
+		while self.schedule.peek().is_some_and(|&Reverse(arrival)|arrival <= now) { self.schedule.pop(); }
 	}
-}
\ No newline at end of file
+	/// Earliest tick on or after `now` that a packet arrives on
+	pub fn next_arrival(&mut self, now: usize) -> Option<usize> {
+		while let Some(&Reverse(arrival)) = self.schedule.peek() {
+			if arrival >= now { return Some(arrival) }
+			self.schedule.pop();
+		}
+		None
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::config::SimConfig;
+	use crate::internet::tests::bootstrapped;
+
+	#[test]
+	fn schedule_only_holds_packets_in_flight() {
+		let (internet, _) = bootstrapped(SimConfig { skip_idle: false, ..Default::default() }, 3);
+		let in_flight: usize = internet.router.packet_map.values().map(|packets|packets.len()).sum();
+		assert_eq!(internet.router.schedule.len(), in_flight);
+	}
+}
@@ -0,0 +1,153 @@
+//! Measurement of how well greedy `Traverse` routing performs compared to sending packets directly through the `InternetRouter`
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use rand::Rng;
+use rand::seq::SliceRandom;
+
+use crate::internet::{InternetID, InternetSim, CustomNode};
+use crate::node::{Node, NodeAction, NodeEvent, NodeID};
+
+/// Number of ticks given to the sources to look up their destinations' RouteCoords before sending
+const ROUTE_COORD_LOOKUP_TICKS: usize = 10;
+
+/// Result of routing one Traverse packet
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RouteSample {
+	pub src: NodeID,
+	pub dest: NodeID,
+	pub delivered: bool,
+	/// Overlay hops taken, None if not delivered
+	pub hops: Option<usize>,
+	/// Ticks between the packet being sent and it arriving, None if not delivered
+	pub overlay_latency: Option<usize>,
+	/// Latency of sending a packet straight from src to dest through the `InternetRouter`
+	pub direct_latency: usize,
+	/// `overlay_latency / direct_latency`, None if not delivered
+	pub stretch: Option<f64>,
+}
+
+/// Aggregated routing quality over a set of sampled source/destination pairs
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RoutingReport {
+	/// Tick the measurement started on
+	pub tick: usize,
+	pub samples: Vec<RouteSample>,
+	/// Fraction of packets that were delivered
+	pub delivery_rate: f64,
+	pub mean_hops: f64,
+	pub mean_stretch: f64,
+	pub median_stretch: f64,
+	pub max_stretch: f64,
+}
+impl RoutingReport {
+	fn from_samples(tick: usize, samples: Vec<RouteSample>) -> Self {
+		let delivered: Vec<&RouteSample> = samples.iter().filter(|s|s.delivered).collect();
+		let mut stretches: Vec<f64> = delivered.iter().filter_map(|s|s.stretch).collect();
+		stretches.sort_by(|a, b|a.partial_cmp(b).unwrap());
+		let mean = |values: &mut dyn Iterator<Item = f64>, count: usize| if count == 0 { 0.0 } else { values.sum::<f64>() / count as f64 };
+		Self {
+			tick,
+			delivery_rate: if samples.is_empty() { 0.0 } else { delivered.len() as f64 / samples.len() as f64 },
+			mean_hops: mean(&mut delivered.iter().filter_map(|s|s.hops).map(|h|h as f64), delivered.len()),
+			mean_stretch: mean(&mut stretches.iter().cloned(), stretches.len()),
+			median_stretch: stretches.get(stretches.len() / 2).cloned().unwrap_or(0.0),
+			max_stretch: stretches.last().cloned().unwrap_or(0.0),
+			samples,
+		}
+	}
+	/// Write the report to a file, as JSON if the path ends in `.json` and as CSV (one row per sample) otherwise
+	pub fn export(&self, path: &str) -> anyhow::Result<()> {
+		let mut writer = BufWriter::new(File::create(path)?);
+		if path.ends_with(".json") {
+			serde_json::to_writer_pretty(&mut writer, self)?;
+		} else {
+			writeln!(writer, "tick,src,dest,delivered,hops,overlay_latency,direct_latency,stretch")?;
+			let opt = |o: Option<String>| o.unwrap_or_default();
+			for s in &self.samples {
+				writeln!(writer, "{},{},{},{},{},{},{},{}", self.tick, s.src, s.dest, s.delivered, opt(s.hops.map(|h|h.to_string())), opt(s.overlay_latency.map(|l|l.to_string())), s.direct_latency, opt(s.stretch.map(|s|format!("{:.4}", s))))?;
+			}
+		}
+		writer.flush()?;
+		Ok(())
+	}
+}
+impl std::fmt::Display for RoutingReport {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "[{: >6}] {} samples, delivery rate: {:.1}%, mean hops: {:.2}, stretch mean: {:.2}, median: {:.2}, max: {:.2}",
+			self.tick, self.samples.len(), self.delivery_rate * 100.0, self.mean_hops, self.mean_stretch, self.median_stretch, self.max_stretch)
+	}
+}
+
+impl InternetSim<Node> {
+	/// Send Traverse packets between `samples` random pairs of nodes with RouteCoords and measure how they were routed.
+	/// Destinations are only picked from nodes that have published their RouteCoord to the DHT.
+	/// Packets that haven't arrived after `timeout` ticks count as undelivered.
+	pub fn measure_routing(&mut self, samples: usize, timeout: usize, rng: &mut impl Rng) -> RoutingReport {
+		let start_tick = self.ticks;
+		let routable: Vec<InternetID> = self.nodes.iter().filter(|(_,n)|n.route_coord.is_some()).map(|(&id,_)|id).collect();
+		let published: Vec<InternetID> = routable.iter().filter(|id|self.route_coord_dht.contains_key(&self.nodes[id].node_id)).cloned().collect();
+		if routable.len() < 2 || published.is_empty() { log::warn!("Need at least 2 nodes with RouteCoords, one of them published, to measure routing"); return RoutingReport::from_samples(start_tick, vec![]) }
+
+		let pairs: Vec<(InternetID, InternetID)> = (0..samples).filter_map(|_|{
+			let dest = *published.choose(rng)?;
+			let src = *routable.iter().filter(|&&id|id != dest).collect::<Vec<_>>().choose(rng)?;
+			Some((*src, dest))
+		}).collect();
+		let node_ids: HashMap<InternetID, NodeID> = routable.iter().map(|&net_id|(net_id, self.nodes[&net_id].node_id)).collect();
+
+		// Look up RouteCoords first so that lookups don't count towards overlay latency
+		for &(src, dest) in &pairs {
+			self.nodes.get_mut(&src).unwrap().action(NodeAction::RequestRouteCoord(node_ids[&dest]));
+		}
+		self.tick(ROUTE_COORD_LOOKUP_TICKS, rng);
+
+		// Tag each packet with a unique value so that it can be identified at the destination
+		let base_data: u64 = rng.gen::<u64>() >> 1;
+		let sample_data = base_data..base_data + pairs.len() as u64;
+		for (i, &(src, dest)) in pairs.iter().enumerate() {
+			self.nodes.get_mut(&src).unwrap().action(NodeAction::Traverse(node_ids[&dest], base_data + i as u64));
+		}
+		let mut sent_at: HashMap<u64, usize> = HashMap::new();
+		let mut received: HashMap<u64, (usize, usize)> = HashMap::new(); // Data -> (tick, hops)
+		let mut undeliverable = 0;
+		for _ in 0..timeout {
+			self.tick(1, rng);
+			// Only take the events of the sample packets, anything else is left for whoever is observing the nodes
+			for node in self.nodes.values_mut() {
+				let events = node.take_events_matching(|event| match event {
+					NodeEvent::TraverseSent(_, data) | NodeEvent::TraverseReceived(_, data, _) | NodeEvent::TraverseUndeliverable(_, data, _) => sample_data.contains(data),
+					_ => false,
+				});
+				for event in events {
+					match event {
+						NodeEvent::TraverseSent(_, data) => { sent_at.insert(data, self.ticks); },
+						NodeEvent::TraverseReceived(_, data, hops) => { received.insert(data, (self.ticks, hops)); },
+						NodeEvent::TraverseUndeliverable(_, data, _) => { undeliverable += 1; log::debug!("Traverse packet with data: {} was reported undeliverable", data) },
+						_ => {},
+					}
+				}
+			}
+			if received.len() + undeliverable == pairs.len() { break }
+		}
+
+		let route_samples = pairs.iter().enumerate().map(|(i, &(src, dest))|{
+			let data = base_data + i as u64;
+			let (src_pos, dest_pos) = (self.router.node_map[&src].position, self.router.node_map[&dest].position);
+			let direct_latency = (self.router.latency_model.latency(nalgebra::distance(&src_pos, &dest_pos) as isize) as usize).max(1);
+			let result = received.get(&data).map(|&(tick, hops)|(tick - sent_at.get(&data).cloned().unwrap_or(start_tick), hops));
+			RouteSample {
+				src: node_ids[&src],
+				dest: node_ids[&dest],
+				delivered: result.is_some(),
+				hops: result.map(|(_, hops)|hops),
+				overlay_latency: result.map(|(latency, _)|latency),
+				direct_latency,
+				stretch: result.map(|(latency, _)|latency as f64 / direct_latency as f64),
+			}
+		}).collect();
+		RoutingReport::from_samples(start_tick, route_samples)
+	}
+}
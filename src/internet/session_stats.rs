@@ -0,0 +1,35 @@
+//! Measurement of what nodes have learned about their sessions compared to the `InternetRouter`'s ground truth
+
+use crate::internet::InternetSim;
+use crate::node::{Node, NodeID, RouteScalar};
+
+/// Measured and real latency of one direct session
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LatencySample {
+	pub node_id: NodeID,
+	pub remote_id: NodeID,
+	/// The session's `SessionTracker::dist_avg`, half the average ping round trip
+	pub measured: RouteScalar,
+	/// Latency the `InternetRouter`'s latency model gives between the two nodes, without variance
+	pub actual: RouteScalar,
+}
+
+impl InternetSim<Node> {
+	/// Measured against real latency of every direct session that has been pinged
+	pub fn latency_samples(&self) -> Vec<LatencySample> {
+		self.nodes.iter().flat_map(|(net_id, node)|{
+			node.remotes.values().filter_map(move |remote|{
+				let session = remote.session().ok()?;
+				let direct = session.direct().ok()?;
+				if session.tracker.ping_count == 0 { return None }
+				let (position, remote_position) = (self.router.node_map.get(net_id)?.position, self.router.node_map.get(&direct.net_id)?.position);
+				let actual = self.router.latency_model.latency(nalgebra::distance(&position, &remote_position) as isize).max(1) as RouteScalar;
+				Some(LatencySample { node_id: node.node_id, remote_id: remote.node_id, measured: session.tracker.dist_avg, actual })
+			})
+		}).collect()
+	}
+	/// Number of peers of every node
+	pub fn peer_counts(&self) -> Vec<usize> {
+		self.nodes.values().map(|node|node.peer_list.len()).collect()
+	}
+}
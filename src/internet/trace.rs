@@ -0,0 +1,73 @@
+//! Recording of every packet delivered by the `InternetRouter`, written as one JSON object per line
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+
+use crate::internet::InternetID;
+
+/// One packet delivered by the router
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TraceEntry {
+	/// Simulation tick the packet was delivered on
+	pub tick: usize,
+	pub src: InternetID,
+	pub dest: InternetID,
+	/// Decoded type of the packet (e.g. `ExchangeInfo`, `Handshake` or `RouteCoordDHTReadResponse`)
+	pub packet_type: String,
+	/// Size of the packet's data
+	pub bytes: usize,
+}
+
+/// Writes trace entries to a file as they are recorded
+#[derive(Debug)]
+pub struct TraceRecorder {
+	path: String,
+	writer: BufWriter<File>,
+	pub recorded: usize,
+}
+impl TraceRecorder {
+	pub fn create(path: &str) -> io::Result<Self> {
+		Ok(Self { path: path.to_owned(), writer: BufWriter::new(File::create(path)?), recorded: 0 })
+	}
+	pub fn path(&self) -> &str { &self.path }
+	pub fn record(&mut self, entry: &TraceEntry) -> io::Result<()> {
+		serde_json::to_writer(&mut self.writer, entry)?;
+		self.writer.write_all(b"\n")?;
+		self.recorded += 1;
+		Ok(())
+	}
+	pub fn flush(&mut self) -> io::Result<()> { self.writer.flush() }
+}
+
+/// Read every entry of a trace file
+pub fn read_trace(path: &str) -> anyhow::Result<Vec<TraceEntry>> {
+	let reader = BufReader::new(File::open(path)?);
+	let mut entries = Vec::new();
+	for (line_num, line) in reader.lines().enumerate() {
+		let line = line?;
+		if line.is_empty() { continue }
+		entries.push(serde_json::from_str(&line).map_err(|err|anyhow!("{}:{}: {}", path, line_num + 1, err))?);
+	}
+	Ok(entries)
+}
+
+/// Selects trace entries for inspection, every set field must match
+#[derive(Debug, Default, Clone)]
+pub struct TraceFilter {
+	/// Entries sent from or delivered to this node
+	pub node: Option<InternetID>,
+	/// Entries with this packet type
+	pub packet_type: Option<String>,
+	/// Entries delivered on or after this tick
+	pub from_tick: Option<usize>,
+	/// Entries delivered on or before this tick
+	pub to_tick: Option<usize>,
+}
+impl TraceFilter {
+	pub fn matches(&self, entry: &TraceEntry) -> bool {
+		self.node.is_none_or(|n|entry.src == n || entry.dest == n)
+			&& self.packet_type.as_ref().is_none_or(|t|t.eq_ignore_ascii_case(&entry.packet_type))
+			&& self.from_tick.is_none_or(|t|entry.tick >= t)
+			&& self.to_tick.is_none_or(|t|entry.tick <= t)
+	}
+}
@@ -0,0 +1,81 @@
+//! Recording of the hops Traverse packets sent by selected nodes take through the network, so that their routes can be plotted
+
+use std::collections::{HashMap, VecDeque};
+
+use nalgebra::Point2;
+
+use crate::internet::InternetID;
+use crate::node::{NodeID, RouteCoord, TraverseHeader, TraverseID, TraverseNonce};
+
+/// Number of paths kept, the oldest are dropped first
+const MAX_PATHS: usize = 100;
+
+/// One overlay link crossed by a Traverse packet
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TraversalHop {
+	/// Tick the packet arrived on
+	pub tick: usize,
+	pub from: InternetID,
+	pub to: InternetID,
+	/// Positions of `from` and `to` in the `InternetRouter`
+	pub from_position: Point2<f32>,
+	pub to_position: Point2<f32>,
+	/// Set if the packet was sent back out of a dead end
+	pub backtrack: bool,
+}
+
+/// Route a Traverse packet took from the node that sent it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TraversalPath {
+	pub origin: NodeID,
+	/// RouteCoord the packet was routed towards
+	pub destination: RouteCoord,
+	pub receipt: Option<TraverseID>,
+	/// Nonce of the packet, the header carries nothing else that tells its hops apart from other packets
+	pub nonce: TraverseNonce,
+	pub hops: Vec<TraversalHop>,
+}
+impl TraversalPath {
+	/// Nodes where greedy routing had no untried peer left and the packet had to backtrack
+	pub fn dead_ends(&self) -> Vec<(InternetID, Point2<f32>)> {
+		let mut dead_ends: Vec<(InternetID, Point2<f32>)> = Vec::new();
+		for hop in self.hops.iter().filter(|h|h.backtrack) {
+			if !dead_ends.iter().any(|(id,_)|*id == hop.from) { dead_ends.push((hop.from, hop.from_position)) }
+		}
+		dead_ends
+	}
+}
+impl std::fmt::Display for TraversalPath {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "NodeID({}) to RouteCoord({}) in {} hops", self.origin, self.destination, self.hops.len())?;
+		for (i, hop) in self.hops.iter().enumerate() {
+			write!(f, "\n  {: >3}. [{: >6}] InternetID({}) -> InternetID({}){}", i + 1, hop.tick, hop.from, hop.to, if hop.backtrack { " (backtrack)" } else { "" })?;
+		}
+		Ok(())
+	}
+}
+
+/// Builds a `TraversalPath` for every Traverse packet sent by one of `origins`
+#[derive(Debug, Default)]
+pub struct TraversalRecorder {
+	/// Nodes whose Traverse packets are recorded, by the InternetID they send from
+	pub origins: HashMap<InternetID, NodeID>,
+	pub paths: VecDeque<TraversalPath>,
+}
+impl TraversalRecorder {
+	/// Record a Traverse packet delivered on `tick` from one node to another
+	pub fn record(&mut self, tick: usize, header: &TraverseHeader, from: (InternetID, Point2<f32>), to: (InternetID, Point2<f32>)) {
+		// A packet that hasn't crossed any links yet was just sent, everything after it is a later hop of the same packet
+		if header.hops == 0 {
+			let origin = if let Some(&origin) = self.origins.get(&from.0) { origin } else { return };
+			if self.paths.len() >= MAX_PATHS { self.paths.pop_front(); }
+			self.paths.push_back(TraversalPath { origin, destination: header.destination, receipt: header.receipt, nonce: header.nonce, hops: Vec::new() });
+		}
+		let path = self.paths.iter_mut().rev().find(|p|p.nonce == header.nonce);
+		if let Some(path) = path {
+			// Tried nodes are never picked as the next hop, so going back to one means backtracking
+			let backtrack = path.hops.iter().any(|h|h.from == to.0);
+			path.hops.push(TraversalHop { tick, from: from.0, to: to.0, from_position: from.1, to_position: to.1, backtrack });
+		}
+	}
+}
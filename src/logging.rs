@@ -0,0 +1,148 @@
+//! Structured logging on top of `log`: spans attach fields such as node_id, tick or packet type to every record logged inside them,
+//! and records are written either as text or as one JSON object per line.
+//!
+//! Text records start with the tick and NodeID of the enclosing span, so node code doesn't write them into its messages.
+//!
+//! Configured from the environment along with `RUST_LOG`:
+//! * `DITHER_LOG_FORMAT=json`: Write records as JSON with the fields of every enclosing span
+//! * `DITHER_LOG_NODE=<NodeID>`: Only write records logged inside spans of this node
+
+use std::cell::RefCell;
+use std::io::Write;
+use std::marker::PhantomData;
+
+use serde_json::{Map, Value};
+use smallvec::SmallVec;
+
+thread_local! {
+	/// Spans entered on this thread, innermost last. Nodes are ticked on one thread each so spans never cross nodes.
+	static SPANS: RefCell<Vec<Span>> = const { RefCell::new(Vec::new()) };
+}
+
+struct Span {
+	name: &'static str,
+	fields: Fields,
+}
+
+/// Fields of a span, kept inline so that entering a span on every tick and packet doesn't allocate
+pub type Fields = SmallVec<[(&'static str, FieldValue); 4]>;
+
+/// Value of a span field, only turned into JSON when a record is written
+#[derive(Debug, Clone)]
+pub enum FieldValue {
+	Int(u64),
+	Name(&'static str),
+	Text(String),
+}
+impl From<u64> for FieldValue { fn from(value: u64) -> Self { FieldValue::Int(value) } }
+impl From<u32> for FieldValue { fn from(value: u32) -> Self { FieldValue::Int(value as u64) } }
+impl From<usize> for FieldValue { fn from(value: usize) -> Self { FieldValue::Int(value as u64) } }
+impl From<&'static str> for FieldValue { fn from(value: &'static str) -> Self { FieldValue::Name(value) } }
+impl From<String> for FieldValue { fn from(value: String) -> Self { FieldValue::Text(value) } }
+impl From<&FieldValue> for Value {
+	fn from(value: &FieldValue) -> Self {
+		match value {
+			FieldValue::Int(int) => Value::from(*int),
+			FieldValue::Name(name) => Value::from(*name),
+			FieldValue::Text(text) => Value::from(text.as_str()),
+		}
+	}
+}
+
+/// Leaves its span when dropped
+#[must_use = "the span is left as soon as the guard is dropped"]
+pub struct SpanGuard {
+	entered: bool,
+	// Spans are thread-local, so the guard must be dropped on the thread that entered it
+	_not_send: PhantomData<*const ()>,
+}
+impl Drop for SpanGuard {
+	fn drop(&mut self) {
+		if self.entered { SPANS.with(|spans|spans.borrow_mut().pop()); }
+	}
+}
+
+/// Enter a span, every record logged on this thread until the guard is dropped carries `fields`. Does nothing if logging is off.
+pub fn enter(name: &'static str, fields: impl FnOnce() -> Fields) -> SpanGuard {
+	let entered = log::max_level() != log::LevelFilter::Off;
+	if entered { SPANS.with(|spans|spans.borrow_mut().push(Span { name, fields: fields() })); }
+	SpanGuard { entered, _not_send: PhantomData }
+}
+
+/// Add a field to the innermost span, for values that are only known partway through it.
+/// Values that take work to build should only be recorded if `log::log_enabled!` for the records that would show them.
+pub fn record(key: &'static str, value: impl Into<FieldValue>) {
+	SPANS.with(|spans|{
+		if let Some(span) = spans.borrow_mut().last_mut() { span.fields.push((key, value.into())); }
+	});
+}
+
+/// Enter a span with fields: `let _span = span!("parse_packet", node_id = self.node_id, tick = self.ticks);`
+macro_rules! span {
+	($name:expr $(, $key:ident = $value:expr)* $(,)?) => {
+		$crate::logging::enter($name, || {
+			#[allow(unused_mut)]
+			let mut fields = $crate::logging::Fields::new();
+			$( fields.push((stringify!($key), $crate::logging::FieldValue::from($value))); )*
+			fields
+		})
+	};
+}
+pub(crate) use span;
+
+/// Fields of every span entered on this thread, inner spans and later fields override earlier ones, along with the span names from outermost to innermost
+fn current_fields() -> (Vec<&'static str>, Map<String, Value>) {
+	SPANS.with(|spans|{
+		let spans = spans.borrow();
+		let mut fields = Map::new();
+		for span in spans.iter() {
+			fields.extend(span.fields.iter().map(|(k, v)|((*k).to_owned(), Value::from(v))));
+		}
+		(spans.iter().map(|s|s.name).collect(), fields)
+	})
+}
+
+/// Writes records through `env_logger`, dropping those logged outside the spans of the selected node
+struct Logger {
+	inner: env_logger::Logger,
+	node: Option<Value>,
+}
+impl log::Log for Logger {
+	fn enabled(&self, metadata: &log::Metadata) -> bool { self.inner.enabled(metadata) }
+	fn log(&self, record: &log::Record) {
+		if let Some(node) = &self.node {
+			if current_fields().1.get("node_id") != Some(node) { return }
+		}
+		self.inner.log(record)
+	}
+	fn flush(&self) { self.inner.flush() }
+}
+
+/// Install the logger, configured from `RUST_LOG`, `DITHER_LOG_FORMAT` and `DITHER_LOG_NODE`
+pub fn init() {
+	let mut builder = env_logger::Builder::from_default_env();
+	if std::env::var("DITHER_LOG_FORMAT").as_deref() != Ok("json") {
+		// env_logger's default format with the span's tick and NodeID in front of the message: `[<time> INFO  <target>] [  1200] NodeID(3) <message>`
+		builder.format(|buf, record| {
+			let fields = current_fields().1;
+			let level = buf.default_styled_level(record.level());
+			write!(buf, "[{} {:<5} {}] ", buf.timestamp(), level, record.target())?;
+			if let Some(tick) = fields.get("tick") { write!(buf, "[{: >6}] ", tick.to_string())?; }
+			if let Some(node_id) = fields.get("node_id") { write!(buf, "NodeID({}) ", node_id)?; }
+			writeln!(buf, "{}", record.args())
+		});
+	} else {
+		builder.format(|buf, record| {
+			let (spans, mut fields) = current_fields();
+			fields.insert("level".to_owned(), record.level().as_str().into());
+			fields.insert("target".to_owned(), record.target().into());
+			fields.insert("message".to_owned(), record.args().to_string().into());
+			fields.insert("spans".to_owned(), spans.into());
+			writeln!(buf, "{}", Value::Object(fields))
+		});
+	}
+	let node = std::env::var("DITHER_LOG_NODE").ok().and_then(|n|n.parse::<u64>().ok()).map(Value::from);
+	let inner = builder.build();
+	log::set_max_level(inner.filter());
+	if let Err(err) = log::set_boxed_logger(Box::new(Logger { inner, node })) { eprintln!("Failed to install logger: {}", err) }
+}
@@ -1,5 +1,3 @@
-#![feature(drain_filter)]
-
 #[macro_use]
 extern crate serde;
 extern crate log;
@@ -12,28 +10,68 @@ extern crate anyhow;
 
 use std::io::{self, prelude::*};
 
+pub mod batch;
+pub mod config;
+use config::{NodeConfig, SimConfig};
 pub mod internet;
-use internet::{InternetID, InternetSim, CustomNode};
+pub mod logging;
+pub mod metrics;
+use internet::{InternetID, InternetSim, CustomNode, SimRng};
 pub mod node;
 use node::{Node, NodeAction, NodeID};
 pub mod plot;
+pub mod scenario;
+pub mod socks;
+use socks::Socks5Proxy;
 use rand::SeedableRng;
 
 fn main() {
-	env_logger::init();
+	logging::init();
 	println!("Hello, Network!");
 	let _ = std::fs::create_dir_all("target/images");
 
-	let rng = &mut rand::rngs::SmallRng::seed_from_u64(0);
-	let mut internet = InternetSim::new();
+	// Run a scenario file to completion instead of starting the REPL: run <scenario path>
+	if std::env::args().nth(1).as_deref() == Some("run") {
+		let path = std::env::args().nth(2).expect("run: requires path of scenario to run");
+		let result = scenario::Scenario::load(&path).and_then(|s|s.run());
+		match result {
+			Ok(result) if result.passed() => println!("Scenario {} passed {} assertions", path, result.assertions.len()),
+			Ok(result) => {
+				println!("Scenario {} failed {} of {} assertions", path, result.assertions.iter().filter(|(_,_,passed)|!passed).count(), result.assertions.len());
+				std::process::exit(1);
+			},
+			Err(err) => { println!("Scenario {} errored: {:?}", path, err); std::process::exit(2); },
+		}
+		return
+	}
+	// Run a scenario over a grid of parameters and write a table of the results: batch <batch path>
+	if std::env::args().nth(1).as_deref() == Some("batch") {
+		let path = std::env::args().nth(2).expect("batch: requires path of batch spec to run");
+		let spec = batch::BatchSpec::load(&path).unwrap_or_else(|err|{ println!("Batch {} failed to load: {:?}", path, err); std::process::exit(2) });
+		let runs = spec.run();
+		for (i, run) in runs.iter().enumerate() {
+			let routing = run.routing.as_ref().map_or(String::from("not measured"), |r|r.to_string());
+			println!("Run {}: {:?}, assertions passed: {}, failed: {}, {}{}", i, run.params, run.assertions_passed, run.assertions_failed, routing, run.error.as_ref().map_or(String::new(), |e|format!(", error: {}", e)));
+		}
+		if let Err(err) = batch::export(&runs, &spec.output) { println!("Failed to write batch results: {:?}", err); std::process::exit(2); }
+		println!("Wrote results of {} runs to {}", runs.len(), spec.output);
+		return
+	}
+	// Simulation config may be passed as the first argument
+	let sim_config: SimConfig = if let Some(path) = std::env::args().nth(1) {
+		config::load(&path).expect("Failed to load simulation config")
+	} else { SimConfig::default() };
+
+	let rng = &mut SimRng::seed_from_u64(0);
+	let mut internet = InternetSim::new(sim_config);
 
 	for i in 0..3 {
-		let node2 = Node::new(i, internet.lease());
+		let node2 = Node::new(i, internet.lease(), internet.config.node.clone());
 		internet.add_node(node2, rng);
 	}
 
 	let snapshots_per_boot = 10;
-	for i in 1..(internet.nodes.len()+0) {
+	for i in 1..internet.nodes.len() {
 		if let Some(node) = internet.node_mut(i as InternetID) {
 			node.action(NodeAction::Bootstrap(0,0));
 		} else { log::error!("Node at InternetID({}) doesn't exist", i)}
@@ -48,6 +86,7 @@ fn main() {
 	//internet.node_mut(8).unwrap().action(NodeAction::ConnectRouted(19, 3)); 
 	//internet.tick(1000, rng);
 
+	let mut proxies = Vec::new();
 	let stdin = io::stdin();
 	let split_regex = fancy_regex::Regex::new(r#"((?<=")[^"]*(?=")|[^" ]+)"#).unwrap();
 
@@ -56,7 +95,7 @@ fn main() {
 			// Look for 
 			let input: Vec<&str> = split_regex.find_iter(&line[..]).flatten().map(|m|m.as_str()).collect();
 			
-			if let Err(err) = parse_command(&mut internet, &input, rng) {
+			if let Err(err) = parse_command(&mut internet, &mut proxies, &input, rng) {
 				println!("Error: {:?}", err);
 			}
 			
@@ -65,13 +104,25 @@ fn main() {
 }
 
 use std::error::Error;
-fn parse_command(internet: &mut InternetSim<Node>, input: &Vec<&str>, rng: &mut impl rand::Rng) -> Result<(), Box<dyn Error>> {
+/// Tick the simulation while keeping SOCKS5 proxies serviced every tick
+fn run_ticks(internet: &mut InternetSim<Node>, proxies: &mut [(InternetID, Socks5Proxy)], num_ticks: usize, rng: &mut impl rand::Rng) {
+	if proxies.is_empty() { return internet.tick(num_ticks, rng) }
+	for _ in 0..num_ticks {
+		internet.tick(1, rng);
+		for (net_id, proxy) in proxies.iter_mut() {
+			if let Some(node) = internet.node_mut(*net_id) { proxy.poll(node) }
+		}
+	}
+}
+
+fn parse_command(internet: &mut InternetSim<Node>, proxies: &mut Vec<(InternetID, Socks5Proxy)>, input: &Vec<&str>, rng: &mut SimRng) -> Result<(), Box<dyn Error>> {
 	let mut command = input.iter();
 	match command.next() {
-		// Adding Nodes
+		// Adding Nodes: add <NodeID> [node config path]
 		Some(&"add") => {
 			if let Some(Ok(node_id)) = command.next().map(|s|s.parse::<NodeID>()) {
-				let node = Node::new(node_id, internet.lease());
+				let node_config: NodeConfig = if let Some(path) = command.next() { config::load(path)? } else { internet.config.node.clone() };
+				let node = Node::new(node_id, internet.lease(), node_config);
 				println!("Adding Node: {:?}", node);
 				internet.add_node(node, rng);
 			} else { Err("add: requires second argument to be NodeID")? }
@@ -85,15 +136,181 @@ fn parse_command(internet: &mut InternetSim<Node>, input: &Vec<&str>, rng: &mut
 		Some(&"tick") => {
 			if let Some(Ok(num_ticks)) = command.next().map(|s|s.parse::<usize>()) {
 				println!("Running {} ticks", num_ticks);
-				internet.tick(num_ticks, rng);
+				run_ticks(internet, proxies, num_ticks, rng);
+			}
+		},
+		// Save simulation state to a file
+		Some(&"save") => {
+			let path = command.next().ok_or("save: requires path to write snapshot to")?;
+			internet.save_snapshot(rng, path)?;
+			println!("Saved snapshot to {}", path);
+		},
+		// Replace simulation state with one loaded from a file
+		Some(&"load") => {
+			let path = command.next().ok_or("load: requires path to read snapshot from")?;
+			let (loaded, loaded_rng) = InternetSim::load_snapshot(path)?;
+			*internet = loaded;
+			*rng = loaded_rng;
+			proxies.clear(); // Streams aren't saved in snapshots
+			println!("Loaded snapshot from {} with {} nodes", path, internet.nodes.len());
+		},
+		// Record delivered packets to a file and inspect recorded traces
+		Some(&"trace") => {
+			match command.next() {
+				Some(&"start") => {
+					let path = command.next().ok_or("trace: start: requires path to write trace to")?;
+					internet.start_trace(path)?;
+					println!("Recording packet trace to {}", path);
+				},
+				Some(&"stop") => {
+					if let Some((path, recorded)) = internet.stop_trace()? {
+						println!("Recorded {} packets to {}", recorded, path);
+					} else { Err("trace: stop: no trace is being recorded")? }
+				},
+				Some(&"show") => {
+					let path = command.next().ok_or("trace: show: requires path of trace to read")?;
+					let mut filter = internet::trace::TraceFilter::default();
+					while let Some(key) = command.next() {
+						let value = command.next().ok_or(format!("trace: show: {} requires a value", key))?;
+						match *key {
+							"node" => filter.node = Some(value.parse()?),
+							"type" => filter.packet_type = Some(value.to_string()),
+							"from" => filter.from_tick = Some(value.parse()?),
+							"to" => filter.to_tick = Some(value.parse()?),
+							_ => Err(format!("trace: show: unknown filter: {}", key))?,
+						}
+					}
+					let mut type_counts = std::collections::BTreeMap::new();
+					for entry in internet::trace::read_trace(path)?.iter().filter(|e|filter.matches(e)) {
+						println!("[{: >6}] InternetID({}) -> InternetID({}): {} ({} bytes)", entry.tick, entry.src, entry.dest, entry.packet_type, entry.bytes);
+						*type_counts.entry(entry.packet_type.clone()).or_insert(0usize) += 1;
+					}
+					println!("Matching packets by type: {:?}", type_counts);
+				},
+				_ => Err("trace: requires subcommand: start <path>, stop or show <path> [node <InternetID>] [type <packet type>] [from <tick>] [to <tick>]")?,
+			}
+		},
+		// Measure routing quality between random node pairs: measure <samples> [timeout] [path (.csv or .json)]
+		Some(&"measure") => {
+			let samples = if let Some(Ok(samples)) = command.next().map(|s|s.parse::<usize>()) { samples } else { return Err("measure: requires number of node pairs to sample")? };
+			let timeout = command.next().map(|s|s.parse::<usize>()).transpose()?.unwrap_or(1000);
+			let report = internet.measure_routing(samples, timeout, rng);
+			println!("{}", report);
+			if let Some(path) = command.next() {
+				report.export(path)?;
+				println!("Exported routing report to {}", path);
 			}
 		},
 		// Configuring network
 		Some(&"net") => {
 			println!("{:#?}", internet);
 		},
+		// Plot the network every few ticks while the simulation runs: animate <path (.gif or directory)> [ticks between frames] or animate stop
+		Some(&"animate") => {
+			match command.next() {
+				Some(&"stop") => {
+					if let Some((path, frames)) = internet.stop_animation()? {
+						println!("Wrote {} frames to {}", frames, path);
+					} else { Err("animate: stop: no animation is being recorded")? }
+				},
+				Some(path) => {
+					let every = command.next().map(|s|s.parse::<usize>()).transpose()?.unwrap_or(100);
+					internet.start_animation(path, every, (1280, 720))?;
+					println!("Plotting network every {} ticks to {}", every, path);
+				},
+				None => Err("animate: requires path of GIF or frame directory to write to, or stop")?,
+			}
+		},
+		// Plot real node positions against their RouteCoords: embedding [path]
+		Some(&"embedding") => {
+			let path = command.next().unwrap_or(&"target/images/embedding.png");
+			let report = internet.embedding_report();
+			println!("{}", report);
+			plot::embedding_overlay(&report, &internet.router.field_dimensions, path, (1280, 720))?;
+		},
+		// Sample packet counters and node metrics: metrics start <every> | metrics show | metrics export <path> | metrics stop
+		Some(&"metrics") => {
+			match command.next() {
+				Some(&"start") => {
+					let every = command.next().ok_or("metrics: start: requires number of ticks between samples")?.parse::<usize>()?;
+					internet.start_metrics(every)?;
+					println!("Sampling metrics every {} ticks", every);
+				},
+				Some(&"show") => print!("{}", internet.metrics().ok_or("metrics: show: metrics aren't being sampled")?.prometheus()),
+				// Write samples as CSV, JSON if the path ends in .json or the latest sample in Prometheus text format if it ends in .prom
+				Some(&"export") => {
+					let path = command.next().ok_or("metrics: export: requires path to write samples to")?;
+					let metrics = internet.metrics().ok_or("metrics: export: metrics aren't being sampled")?;
+					metrics.export(path)?;
+					println!("Exported {} samples to {}", metrics.samples.len(), path);
+				},
+				Some(&"stop") => {
+					let metrics = internet.stop_metrics().ok_or("metrics: stop: metrics aren't being sampled")?;
+					println!("Stopped sampling metrics after {} samples", metrics.samples.len());
+				},
+				_ => Err("metrics: requires subcommand: start <every> | show | export <path> | stop")?,
+			}
+		},
+		// Charts of simulation statistics, as SVG if the path ends in .svg: chart latency|heatmap|peers [path] | chart error <ticks> [every] [path]
+		Some(&"chart") => {
+			let dimensions = (1280, 720);
+			match command.next() {
+				Some(&"latency") => {
+					let path = command.next().unwrap_or(&"target/images/latency.png");
+					plot::charts::latency_histogram(&internet.latency_samples(), path, dimensions)?;
+					println!("Plotted latency histogram to {}", path);
+				},
+				Some(&"heatmap") => {
+					let path = command.next().unwrap_or(&"target/images/embedding_heatmap.png");
+					plot::charts::embedding_error_heatmap(&internet.embedding_report(), path, dimensions)?;
+					println!("Plotted embedding error heatmap to {}", path);
+				},
+				Some(&"peers") => {
+					let path = command.next().unwrap_or(&"target/images/peer_counts.png");
+					plot::charts::peer_count_histogram(&internet.peer_counts(), path, dimensions)?;
+					println!("Plotted peer counts to {}", path);
+				},
+				// Run the simulation, measuring the mean embedding error every few ticks
+				Some(&"error") => {
+					let ticks = command.next().ok_or("chart: error: requires number of ticks to run")?.parse::<usize>()?;
+					let mut arg = command.next();
+					let mut every = 100;
+					if let Some(Ok(e)) = arg.map(|s|s.parse::<usize>()) { every = e.max(1); arg = command.next(); }
+					let path = arg.unwrap_or(&"target/images/embedding_error.png");
+					println!("Running {} ticks", ticks);
+					let mut series = vec![(internet.ticks, internet.embedding_report().mean)];
+					for chunk in (0..ticks).step_by(every) {
+						run_ticks(internet, proxies, every.min(ticks - chunk), rng);
+						series.push((internet.ticks, internet.embedding_report().mean));
+					}
+					plot::charts::error_time_series(&series, path, dimensions)?;
+					println!("Plotted mean embedding error over {} samples to {}", series.len(), path);
+				},
+				_ => Err("chart: requires subcommand: latency | heatmap | peers | error")?,
+			}
+		},
+		// Routes of Traverse packets sent from the REPL: traversal list | traversal plot [index] [path]
+		Some(&"traversal") => {
+			let paths = internet.traversal_paths();
+			match command.next() {
+				Some(&"list") => paths.iter().enumerate().for_each(|(i, path)| println!("{}: {}", i, path)),
+				Some(&"plot") => {
+					let mut index = paths.len().checked_sub(1).ok_or("traversal: plot: no Traverse packets recorded, send one with: node <id> traverse <NodeID> <data>")?;
+					let mut arg = command.next();
+					if let Some(Ok(i)) = arg.map(|s|s.parse::<usize>()) { index = i; arg = command.next(); }
+					let path = paths.get(index).ok_or("traversal: plot: no recorded Traverse packet with that index")?;
+					let output = arg.unwrap_or(&"target/images/traversal.png");
+					println!("{}", path);
+					plot::traversal_overlay(&*internet, path, &internet.router.field_dimensions, output, (1280, 720))?;
+					println!("Plotted traversal to {}", output);
+				},
+				_ => Err("traversal: requires subcommand: list | plot [index] [path]")?,
+			}
+		},
+		// Plot the network, as SVG or interactive HTML depending on the extension: graph [path]
 		Some(&"graph") => {
-			plot::default_graph(internet, &internet.router.field_dimensions, "target/images/network_snapshot.png", (1280,720))?;
+			let path = command.next().unwrap_or(&"target/images/network_snapshot.png");
+			plot::default_graph(internet, &internet.router.field_dimensions, path, (1280,720))?;
 			//internet.gen_routing_plot("target/images/network_snapshot.png", (500, 500))?;
 		},
 		// List nodes
@@ -106,7 +323,7 @@ fn parse_command(internet: &mut InternetSim<Node>, input: &Vec<&str>, rng: &mut
 					"routes" => internet.nodes.iter().for_each(|(id,node)| println!("{}: {:?}", id, node.route_coord)),
 					"router" => internet.router.node_map.iter().for_each(|(net_id,lc)| println!("{}: {:?}", net_id, lc)),
 					"node" => {
-						if let Some(node_id) = command.next().map(|s|s.parse::<InternetID>().ok()).flatten() {
+						if let Some(node_id) = command.next().and_then(|s|s.parse::<InternetID>().ok()) {
 							println!("{:#?}", internet.node(node_id));
 						}
 					}
@@ -116,6 +333,21 @@ fn parse_command(internet: &mut InternetSim<Node>, input: &Vec<&str>, rng: &mut
 				internet.nodes.iter().for_each(|(id,node)|println!("{}:	{:?}", id, node));
 			}
 		},
+		// Start a SOCKS5 proxy that tunnels through a node: socks <InternetID> <exit NodeID> <port>, the exit has to be a node it has a session with
+		Some(&"socks") => {
+			let net_id = if let Some(Ok(net_id)) = command.next().map(|s|s.parse::<InternetID>()) { net_id } else { return Err("socks: requires InternetID of the node to tunnel through")? };
+			if internet.node(net_id).is_none() { Err("socks: no node at that network address")? }
+			let exit_node_id = if let Some(Ok(node_id)) = command.next().map(|s|s.parse::<NodeID>()) { node_id } else { return Err("socks: requires NodeID of exit node")? };
+			let port = if let Some(Ok(port)) = command.next().map(|s|s.parse::<u16>()) { port } else { return Err("socks: requires port to listen on")? };
+			let proxy = Socks5Proxy::bind(("127.0.0.1", port), exit_node_id)?;
+			println!("SOCKS5 proxy for InternetID({}) listening on {} with exit NodeID({})", net_id, proxy.local_addr()?, exit_node_id);
+			proxies.push((net_id, proxy));
+		},
+		// Start a local TCP echo server to test proxies against: echo <port>
+		Some(&"echo") => {
+			let port = if let Some(Ok(port)) = command.next().map(|s|s.parse::<u16>()) { port } else { return Err("echo: requires port to listen on")? };
+			println!("Echo server listening on {}", socks::spawn_echo_server(("127.0.0.1", port))?);
+		},
 		Some(&"print") => {
 			if let Some(Ok(net_id)) = command.next().map(|s|s.parse::<InternetID>()) {
 				if let Some(node) = internet.node(net_id) { println!("{:#?}", node) }
@@ -147,20 +379,49 @@ fn parse_command(internet: &mut InternetSim<Node>, input: &Vec<&str>, rng: &mut
 				Some(&"print") => {
 					println!("Node: {:#?}", internet.node(net_id).ok_or("node: info: No node matches this InternetID")?);
 				},
+				// Print and clear events the node reported, e.g. actions that expired or failed
+				Some(&"events") => {
+					for event in node.take_events() { println!("{:?}", event); }
+				},
+				// Plot what a node believes about the network next to the real network: node <id> graph [path]
+				Some(&"graph") => {
+					let path = command.next().map(|s|s.to_string()).unwrap_or_else(||format!("target/images/node_{}_view.png", net_id));
+					let node = internet.node(net_id).ok_or("node: graph: No node matches this InternetID")?;
+					plot::side_by_side((&format!("NodeID({}) view", node.node_id), node), ("Ground truth (labelled by InternetID)", &*internet), &internet.router.field_dimensions, &path, (2560, 720))?;
+					println!("Plotted NodeID({}) view to {}", node.node_id, path);
+				},
 				Some(&"traverse") | Some(&"tv") => {
 					if let Some(Ok(remote_node_id)) = command.next().map(|s|s.parse::<NodeID>()) {
 						if let Some(Ok(data)) = command.next().map(|s|s.parse::<u64>()) {
-							node.action(NodeAction::Traverse(remote_node_id, data));
+							// Ask for a receipt and resend until one comes back: node <id> traverse <NodeID> <data> reliable
+							if let Some(&"reliable") = command.next() {
+								node.action(NodeAction::TraverseReliable(remote_node_id, data));
+							} else { node.action(NodeAction::Traverse(remote_node_id, data)); }
+							// Record the route so that it can be plotted with `traversal plot`
+							let node_id = node.node_id;
+							internet.track_traversals(node_id);
 						} else { Err("node: traverse: data must be u64")? }
 					} else { Err("node: traverse: requires a NodeID to send to")? }
 				},
+				// Stop publishing RouteCoord and become reachable only through introducers
+				Some(&"private") => {
+					node.is_public = false;
+					node.action(NodeAction::CalculatePeers);
+				},
+				Some(&"rendezvous") | Some(&"rv") => {
+					if let Some(Ok(remote_node_id)) = command.next().map(|s|s.parse::<NodeID>()) {
+						if let Some(Ok(data)) = command.next().map(|s|s.parse::<u64>()) {
+							node.action(NodeAction::Rendezvous(remote_node_id, data));
+						} else { Err("node: rendezvous: data must be u64")? }
+					} else { Err("node: rendezvous: requires a NodeID to send to")? }
+				},
 				Some(&"route") => {
 					if let Some(Ok(remote_node_id)) = command.next().map(|s|s.parse::<NodeID>()) {
 						node.action(NodeAction::ConnectRouted(remote_node_id, 3));
 					} else { Err("node: route: requires a NodeID to create route to")? }
 				}
 				Some(_) => Err(format!("node: unknown node command: {:?}", input[2]))?,
-				None => Err(format!("node: requires subcommand"))?
+				None => Err("node: requires subcommand".to_string())?
 			}
 		},
 		Some(_) => Err(format!("Invalid Command: {:?}", input))?,
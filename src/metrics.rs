@@ -0,0 +1,121 @@
+//! Counters and gauges sampled from the simulation every few ticks, exported as CSV, JSON or in Prometheus text format
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use crate::internet::InternetID;
+
+/// Prefix of every metric name in Prometheus text format
+const PROMETHEUS_PREFIX: &str = "dither_";
+
+/// Name, Prometheus type and description of every metric
+pub const METRICS: &[(&str, &str, &str)] = &[
+	("packets_sent_total", "counter", "Packets sent by a node, by packet type"),
+	("packets_received_total", "counter", "Packets delivered to a node, by packet type"),
+	("bytes_sent_total", "counter", "Bytes of packet data sent by a node"),
+	("bytes_received_total", "counter", "Bytes of packet data delivered to a node"),
+	("handshakes_started_total", "counter", "Handshakes a node sent to start a direct session"),
+	("handshakes_completed_total", "counter", "Handshakes a node sent that were acknowledged"),
+	("errors_total", "counter", "Errors a node hit handling packets and actions, by NodeError variant"),
+	("action_queue_length", "gauge", "Actions waiting in a node's action list"),
+	("dht_requests_total", "counter", "DHT reads and writes sent to the simulation, by request type"),
+];
+
+/// Identifies one time series: a metric, optionally for a single node and split by type (e.g. the `NodePacket` variant)
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MetricKey {
+	pub name: &'static str,
+	pub node: Option<InternetID>,
+	#[serde(rename = "type")]
+	pub kind: Option<String>,
+}
+impl MetricKey {
+	pub fn new(name: &'static str) -> Self { Self { name, node: None, kind: None } }
+	pub fn node(mut self, node: InternetID) -> Self { self.node = Some(node); self }
+	pub fn kind(mut self, kind: impl Into<String>) -> Self { self.kind = Some(kind.into()); self }
+	/// Labels in Prometheus text format, e.g. `{node="3",type="Ping"}`
+	fn labels(&self) -> String {
+		let labels: Vec<String> = self.node.map(|n|format!("node=\"{}\"", n)).into_iter()
+			.chain(self.kind.as_ref().map(|k|format!("type=\"{}\"", k))).collect();
+		if labels.is_empty() { String::new() } else { format!("{{{}}}", labels.join(",")) }
+	}
+}
+
+/// Value of one time series
+#[derive(Serialize, Debug, Clone)]
+pub struct MetricValue {
+	#[serde(flatten)]
+	pub key: MetricKey,
+	pub value: f64,
+}
+
+/// Value of every metric on one tick
+#[derive(Serialize, Debug, Clone)]
+pub struct MetricsSample {
+	pub tick: usize,
+	pub values: Vec<MetricValue>,
+}
+
+/// Keeps the counters the simulation counts itself and samples them along with the metrics reported by nodes every `every` ticks
+#[derive(Debug)]
+pub struct MetricsRecorder {
+	every: usize,
+	/// Tick the next sample is taken on
+	pub next_tick: usize,
+	counters: HashMap<MetricKey, f64>,
+	pub samples: Vec<MetricsSample>,
+}
+impl MetricsRecorder {
+	/// Start sampling, the first sample is taken on tick `start`
+	pub fn new(every: usize, start: usize) -> anyhow::Result<Self> {
+		if every == 0 { Err(anyhow!("Metrics need at least one tick between samples"))? }
+		Ok(Self { every, next_tick: start, counters: HashMap::new(), samples: Vec::new() })
+	}
+	pub fn count(&mut self, key: MetricKey, amount: f64) { *self.counters.entry(key).or_default() += amount; }
+	/// Take every sample due by tick `now`, `node_metrics` are the values reported by the nodes
+	pub fn sample(&mut self, now: usize, node_metrics: Vec<(MetricKey, f64)>) {
+		if self.next_tick > now { return }
+		let values: BTreeMap<MetricKey, f64> = self.counters.iter().map(|(k, &v)|(k.clone(), v)).chain(node_metrics).collect();
+		let values: Vec<MetricValue> = values.into_iter().map(|(key, value)|MetricValue { key, value }).collect();
+		while self.next_tick <= now {
+			self.samples.push(MetricsSample { tick: self.next_tick, values: values.clone() });
+			self.next_tick += self.every;
+		}
+	}
+	/// Latest sample in Prometheus text format
+	pub fn prometheus(&self) -> String {
+		let mut text = String::new();
+		let sample = if let Some(sample) = self.samples.last() { sample } else { return text };
+		for (name, metric_type, help) in METRICS {
+			let mut values = sample.values.iter().filter(|v|v.key.name == *name).peekable();
+			if values.peek().is_none() { continue }
+			let _ = writeln!(text, "# HELP {}{} {}", PROMETHEUS_PREFIX, name, help);
+			let _ = writeln!(text, "# TYPE {}{} {}", PROMETHEUS_PREFIX, name, metric_type);
+			for value in values {
+				let _ = writeln!(text, "{}{}{} {}", PROMETHEUS_PREFIX, name, value.key.labels(), value.value);
+			}
+		}
+		text
+	}
+	/// Write the samples to a file: as JSON if the path ends in `.json`, the latest sample in Prometheus text format if it ends in `.prom`
+	/// and as CSV (one row per metric per sample) otherwise
+	pub fn export(&self, path: &str) -> anyhow::Result<()> {
+		let mut writer = BufWriter::new(File::create(path)?);
+		if path.ends_with(".json") {
+			serde_json::to_writer_pretty(&mut writer, &self.samples)?;
+		} else if path.ends_with(".prom") {
+			writer.write_all(self.prometheus().as_bytes())?;
+		} else {
+			writeln!(writer, "tick,metric,node,type,value")?;
+			for sample in &self.samples {
+				for MetricValue { key, value } in &sample.values {
+					writeln!(writer, "{},{},{},{},{}", sample.tick, key.name, key.node.map(|n|n.to_string()).unwrap_or_default(), key.kind.as_deref().unwrap_or(""), value)?;
+				}
+			}
+		}
+		writer.flush()?;
+		Ok(())
+	}
+}
@@ -1,62 +1,172 @@
-#[allow(unused_imports)]
-
-const TARGET_PEER_COUNT: usize = 5;
 // Amount of time to wait to connect to a peer who wants to ping
 // const WANT_PING_CONN_TIMEOUT: usize = 300;
-const MAX_REQUEST_PINGS: usize = 10;
+// Oldest events are dropped once this many are waiting to be taken
+const MAX_QUEUED_EVENTS: usize = 1024;
 
-use std::collections::{HashMap, BTreeMap};
+#[allow(unused_imports)]
+use std::collections::{HashMap, BTreeMap, VecDeque};
 use std::any::Any;
 
 //use nalgebra::{DMatrix, SymmetricEigen, Vector2};
 use petgraph::{graphmap::DiGraphMap, graph::Graph};
 use bimap::BiHashMap;
 use smallvec::SmallVec;
-use nalgebra::Point2;
+use rand::{Rng, SeedableRng};
 
 mod types;
 mod session;
-pub use types::{NodeID, SessionID, RouteCoord, NodePacket, NodeEncryption, RemoteNode, RemoteNodeError, RouteScalar};
-use session::{SessionError, RemoteSession};
-pub use crate::internet::{CustomNode, InternetID, InternetPacket, PacketVec};
-use crate::{internet::InternetRequest, plot::GraphPlottable};
+mod stream;
+mod rendezvous;
+mod traverse;
+mod action_queue;
+pub use types::{NodeID, SessionID, RouteCoord, NodePacket, NodePacketType, NodeEncryption, RemoteNode, RemoteNodeError, RouteScalar, RendezvousDescriptor, DescriptorKey, TraverseHeader, TraverseFailure, TraverseID, TraverseNonce, TraverseReceipt, HopHandle, TRAVERSE_HOP_LIMIT};
+use session::{SessionError, RemoteSession, SessionType};
+pub use stream::{StreamID, StreamEnd, StreamStatus, NodeStream, ExitConnection, STREAM_BUFFER};
+pub use rendezvous::Rendezvous;
+pub use traverse::{PendingReceipt, HopState};
+pub use action_queue::{ActionQueue, QueuedAction, ActionPriority, ActionOptions, ActionFailure};
+pub use crate::internet::{CustomNode, InternetID, InternetPacket, PacketVec, SimRng};
+use crate::{internet::InternetRequest, plot::GraphPlottable, metrics::MetricKey};
+use crate::logging::{self, span};
+pub use crate::config::NodeConfig;
 
-#[derive(Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
 /// A condition that should be satisfied before an action is executed
 pub enum NodeActionCondition {
 	/// Yields if there is a session of any kind with NodeID
 	Session(NodeID),
 	/// Yields if passed NodeID has a RouteCoord
 	RemoteRouteCoord(NodeID),
+	/// Yields if passed NodeID has a RendezvousDescriptor
+	RemoteDescriptor(NodeID),
 	/// Yields if a time in the future has passed
 	RunAt(usize), 
+	/// Yields if every condition yields
+	And(Vec<NodeActionCondition>),
+	/// Yields if any condition yields
+	Or(Vec<NodeActionCondition>),
+	/// Yields if the condition doesn't
+	Not(Box<NodeActionCondition>),
+	/// Yields if this node has calculated its own RouteCoord
+	HasRouteCoord,
+	/// Yields if this node has at least this many peers
+	PeerCountAtLeast(usize),
+	/// Yields if there is an active session with NodeID and it is one of this node's peers
+	SessionIsPeer(NodeID),
+	/// Yields if at least this many pings over the session with NodeID were answered (see `SessionTracker::ping_count`)
+	PingCountAtLeast(NodeID, usize),
+	/// Yields if `condition` yields within `ticks` ticks of the action being queued, after that `fallback` runs instead of the conditional action.
+	/// Can be nested in `And` and other `Timeout`s but not in `Or` or `Not`, as the whole condition could still yield after the fallback ran.
+	Timeout {
+		ticks: usize,
+		condition: Box<NodeActionCondition>,
+		fallback: Box<NodeAction>,
+		/// Tick the timeout runs out on, set once the action is queued
+		#[serde(default)]
+		deadline: Option<usize>,
+	},
 }
 impl NodeActionCondition {
 	// Returns true if condition is satisfied
 	fn check(&self, node: &mut Node) -> Result<bool, NodeError> {
-		Ok(match *self {
+		Ok(match self {
 			// Yields None if there is a session active
-			NodeActionCondition::Session(node_id) => node.remote(&node_id)?.session_active(),
+			NodeActionCondition::Session(node_id) => node.remote(node_id).ok().is_some_and(|r|r.session_active()),
 			// Yields None if a specified amount of time has passed
-			NodeActionCondition::RunAt(time) => node.ticks >= time,
+			NodeActionCondition::RunAt(time) => node.ticks >= *time,
 			// Yield if this node has a routecoord
-			NodeActionCondition::RemoteRouteCoord(node_id) => node.remote(&node_id).ok().map(|r|r.route_coord).flatten().is_some(),
-			// Yields None if there is a session and it is direct
-			/* NodeActionCondition::PeerSession(node_id) => {
-				let remote = node.remote(&node_id)?;
-				(remote.session_active() && remote.session()?.is_peer()).then(||self)
-			},
-			// Yields None if direct session is viable
-			NodeActionCondition::PeerTested(node_id) => {
-				let remote = node.remote_mut(&node_id)?;
-				if remote.session_active() {
-					remote.session_mut()?.tracker.is_viable().is_some().then(||self)
-				} else { true.then(||self) }
-			}, */
+			NodeActionCondition::RemoteRouteCoord(node_id) => node.remote(node_id).ok().and_then(|r|r.route_coord).is_some(),
+			// Yield if remote has a rendezvous descriptor
+			NodeActionCondition::RemoteDescriptor(node_id) => node.remote(node_id).ok().map(|r|r.descriptor.is_some()).unwrap_or(false),
+			NodeActionCondition::And(conditions) => {
+				for condition in conditions { if !condition.check(node)? { return Ok(false) } }
+				true
+			},
+			NodeActionCondition::Or(conditions) => {
+				for condition in conditions { if condition.check(node)? { return Ok(true) } }
+				false
+			},
+			NodeActionCondition::Not(condition) => !condition.check(node)?,
+			NodeActionCondition::HasRouteCoord => node.route_coord.is_some(),
+			NodeActionCondition::PeerCountAtLeast(count) => node.peer_list.len() >= *count,
+			// Yields if there is a session and this node peered with it
+			NodeActionCondition::SessionIsPeer(node_id) => node.remote(node_id).ok().filter(|r|r.session_active())
+				.and_then(|r|r.session().ok()).map(|s|s.is_peer()).unwrap_or(false),
+			NodeActionCondition::PingCountAtLeast(node_id, count) => node.remote(node_id).ok().and_then(|r|r.session().ok())
+				.map(|s|s.tracker.ping_count >= *count).unwrap_or(false),
+			// Yields only while its time hasn't run out
+			NodeActionCondition::Timeout { condition, deadline, .. } => deadline.is_none_or(|deadline|node.ticks < deadline) && condition.check(node)?,
 		})
 	}
+	/// Yields if `condition` yields within `ticks` ticks of the action being queued, otherwise `fallback` runs
+	pub fn timeout(ticks: usize, condition: NodeActionCondition, fallback: NodeAction) -> Self {
+		NodeActionCondition::Timeout { ticks, condition: Box::new(condition), fallback: Box::new(fallback), deadline: None }
+	}
+	/// Fallback action of a `Timeout` whose time has run out. Found through `And` and the conditions of other `Timeout`s,
+	/// which can't yield any more once a `Timeout` in them has run out.
+	fn fallback(&self, now: usize) -> Option<&NodeAction> {
+		match self {
+			NodeActionCondition::Timeout { fallback, deadline: Some(deadline), .. } if now >= *deadline => Some(fallback),
+			NodeActionCondition::Timeout { condition, .. } => condition.fallback(now),
+			NodeActionCondition::And(conditions) => conditions.iter().find_map(|c|c.fallback(now)),
+			_ => None,
+		}
+	}
+	/// Check that the fallback of every `Timeout` in this condition can run, i.e. that none is nested in `Or` or `Not`
+	pub fn validate(&self) -> Result<(), NodeError> {
+		match self {
+			NodeActionCondition::And(conditions) => conditions.iter().try_for_each(|c|c.validate()),
+			NodeActionCondition::Timeout { condition, fallback, .. } => { condition.validate()?; fallback.validate() },
+			NodeActionCondition::Or(conditions) if conditions.iter().any(|c|c.has_timeout()) => Err(NodeError::UnreachableFallback { condition: self.clone() }),
+			NodeActionCondition::Not(condition) if condition.has_timeout() => Err(NodeError::UnreachableFallback { condition: self.clone() }),
+			_ => Ok(()),
+		}
+	}
+	fn has_timeout(&self) -> bool {
+		match self {
+			NodeActionCondition::Timeout { .. } => true,
+			NodeActionCondition::And(conditions) | NodeActionCondition::Or(conditions) => conditions.iter().any(|c|c.has_timeout()),
+			NodeActionCondition::Not(condition) => condition.has_timeout(),
+			_ => false,
+		}
+	}
+	/// Start the clock of every `Timeout` that hasn't started yet
+	fn start(&mut self, now: usize) {
+		match self {
+			NodeActionCondition::Timeout { ticks, condition, deadline, .. } => {
+				deadline.get_or_insert(now + *ticks);
+				condition.start(now);
+			},
+			NodeActionCondition::And(conditions) | NodeActionCondition::Or(conditions) => conditions.iter_mut().for_each(|c|c.start(now)),
+			NodeActionCondition::Not(condition) => condition.start(now),
+			_ => {},
+		}
+	}
+	/// Every time this condition waits for through `RunAt` and started `Timeout`s
+	pub fn times(&self) -> Vec<usize> {
+		match self {
+			NodeActionCondition::RunAt(time) => vec![*time],
+			NodeActionCondition::Timeout { condition, deadline, .. } => deadline.iter().cloned().chain(condition.times()).collect(),
+			NodeActionCondition::And(conditions) | NodeActionCondition::Or(conditions) => conditions.iter().flat_map(|c|c.times()).collect(),
+			NodeActionCondition::Not(condition) => condition.times(),
+			_ => Vec::new(),
+		}
+	}
+	/// Move every `RunAt` time in this condition `ticks` later, e.g. to turn times relative to now into times in the node's ticks.
+	/// `Timeout`s are already relative to when the action is queued.
+	pub fn delay(self, ticks: usize) -> Self {
+		match self {
+			NodeActionCondition::RunAt(time) => NodeActionCondition::RunAt(time + ticks),
+			NodeActionCondition::Timeout { ticks: timeout, condition, fallback, deadline } => NodeActionCondition::Timeout { ticks: timeout, condition: Box::new(condition.delay(ticks)), fallback, deadline },
+			NodeActionCondition::And(conditions) => NodeActionCondition::And(conditions.into_iter().map(|c|c.delay(ticks)).collect()),
+			NodeActionCondition::Or(conditions) => NodeActionCondition::Or(conditions.into_iter().map(|c|c.delay(ticks)).collect()),
+			NodeActionCondition::Not(condition) => NodeActionCondition::Not(Box::new(condition.delay(ticks))),
+			condition => condition,
+		}
+	}
 }
-#[derive(Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum NodeAction {
 	/// Bootstrap this node onto a specific other network node, starts the self-organization process
 	Bootstrap(NodeID, InternetID),
@@ -84,8 +194,14 @@ pub enum NodeAction {
 	CalculatePeers,
 	/// Sends a packet out onto the network for a specific recipient
 	Traverse(NodeID, u64),
+	/// Like Traverse, but the recipient sends back a receipt and the packet is resent if none arrives
+	TraverseReliable(NodeID, u64),
 	/// Send DHT request for Route Coordinate
 	RequestRouteCoord(NodeID),
+	/// Send DHT request for a private node's RendezvousDescriptor
+	RequestDescriptor(NodeID),
+	/// Send data to a private node through its introducers (or reply to a client that reached this node through one)
+	Rendezvous(NodeID, u64),
 	/// Establishes Routed session with remote NodeID
 	/// Looks up remote node's RouteCoord on DHT and runs CalculateRoute after RouteCoord is received
 	/// * `usize`: Number of intermediate nodes to route through
@@ -93,19 +209,92 @@ pub enum NodeAction {
 	ConnectRouted(NodeID, usize),
 	/// Send specific packet to node
 	Packet(NodeID, NodePacket),
+	/// Ask an exit node to open a TCP connection to an address and tunnel it through a stream
+	/// * `StreamID`: ID of the new stream
+	/// * `NodeID`: Exit node that opens the connection
+	/// * `String`: Address for the exit node to connect to (`host:port`)
+	OpenStream(StreamID, NodeID, String),
+	/// Send data through an open stream
+	StreamSend(StreamID, Vec<u8>),
+	/// Tell the other end of a stream that no more data will be sent, data can still be received until the other end finishes too
+	FinishStream(StreamID),
+	/// Close a stream and notify the other end
+	CloseStream(StreamID),
 	/// Establish a dynamic routed connection
 	// Route(NodeID, RouteCoord),
 	/// Condition for a condition to be fulfilled before running imbedded Action
 	Condition(NodeActionCondition, Box<NodeAction>),
 }
 impl NodeAction {
+	/// Name of the variant, used to tag log records
+	pub fn kind(&self) -> &'static str {
+		match self {
+			NodeAction::Bootstrap(..) => "Bootstrap",
+			NodeAction::Connect(..) => "Connect",
+			NodeAction::UpdateRemote(..) => "UpdateRemote",
+			NodeAction::RequestPeers(..) => "RequestPeers",
+			NodeAction::CalcRouteCoord => "CalcRouteCoord",
+			NodeAction::ExchangeInformation(..) => "ExchangeInformation",
+			NodeAction::CalculatePeers => "CalculatePeers",
+			NodeAction::Traverse(..) => "Traverse",
+			NodeAction::TraverseReliable(..) => "TraverseReliable",
+			NodeAction::RequestRouteCoord(..) => "RequestRouteCoord",
+			NodeAction::RequestDescriptor(..) => "RequestDescriptor",
+			NodeAction::Rendezvous(..) => "Rendezvous",
+			NodeAction::ConnectRouted(..) => "ConnectRouted",
+			NodeAction::Packet(..) => "Packet",
+			NodeAction::OpenStream(..) => "OpenStream",
+			NodeAction::StreamSend(..) => "StreamSend",
+			NodeAction::FinishStream(..) => "FinishStream",
+			NodeAction::CloseStream(..) => "CloseStream",
+			NodeAction::Condition(..) => "Condition",
+		}
+	}
 	pub fn gen_condition(self, condition: NodeActionCondition) -> NodeAction {
 		NodeAction::Condition(condition, Box::new(self))
 	}
+	/// Check the conditions of this action and any action it will run, see `NodeActionCondition::validate`
+	pub fn validate(&self) -> Result<(), NodeError> {
+		if let NodeAction::Condition(condition, embedded_action) = self { condition.validate()?; embedded_action.validate() } else { Ok(()) }
+	}
+	/// Start the clock of every `Timeout` in this action's condition, called when the action starts waiting in the queue
+	pub fn start(mut self, now: usize) -> Self {
+		if let NodeAction::Condition(condition, _) = &mut self { condition.start(now) }
+		self
+	}
 }
 type ActionVec = SmallVec<[NodeAction; 8]>;
-#[derive(Default, Derivative)]
-#[derivative(Debug)]
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+/// Something that happened on a node that the simulation may want to observe
+pub enum NodeEvent {
+	/// This node sent a Traverse packet
+	/// * `NodeID`: Recipient of the packet
+	/// * `u64`: Data sent
+	TraverseSent(NodeID, u64),
+	/// A Traverse packet addressed to this node arrived
+	/// * `NodeID`: Sender of the packet
+	/// * `u64`: Data received
+	/// * `usize`: Number of overlay hops the packet took
+	TraverseReceived(NodeID, u64, usize),
+	/// A Traverse packet this node sent couldn't be delivered
+	/// * `NodeID`: Intended recipient of the packet
+	/// * `u64`: Data sent
+	/// * `TraverseFailure`: Why it wasn't delivered
+	TraverseUndeliverable(NodeID, u64, TraverseFailure),
+	/// A receipt came back for a Traverse packet this node sent
+	/// * `NodeID`: Recipient of the packet
+	/// * `u64`: Data sent
+	/// * `usize`: Round trip ticks from sending the packet to getting the receipt
+	/// * `usize`: Number of overlay hops the packet took
+	TraverseAcknowledged(NodeID, u64, usize, usize),
+	/// A queued action was dropped without completing
+	/// * `NodeAction`: The action that was dropped, as it was when it left the queue (e.g. still wrapped in its condition)
+	/// * `ActionFailure`: Why it was dropped
+	ActionFailed(NodeAction, ActionFailure),
+}
+#[derive(Derivative, Serialize, Deserialize)]
+#[derivative(Debug, Default)]
 pub struct Node {
 	pub node_id: NodeID,
 	pub net_id: InternetID,
@@ -115,23 +304,46 @@ pub struct Node {
 	deux_ex_data: Option<RouteCoord>,
 	pub is_public: bool, // Does this node publish it's RouteCoord to the DHT?
 	#[derivative(Debug="ignore")]
+	descriptor_key: DescriptorKey, // Authenticates this node's RendezvousDescriptors, drawn from its RNG when the simulator seeds it
+	#[derivative(Debug="ignore")]
 	public_route: Option<RouteCoord>,
 	pub ticks: usize, // Amount of time passed since startup of this node
+	#[derivative(Debug="ignore")]
+	busy: bool, // Did anything happen last tick that may let more happen on the next one
+	#[derivative(Debug="ignore", Default(value="SimRng::seed_from_u64(0)"))]
+	pub rng: SimRng, // Source of all of this node's randomness, seeded by the simulator so runs can be replayed
+	#[derivative(Debug="ignore")]
+	pub config: NodeConfig, // Protocol parameters of this node
 
 	pub remotes: HashMap<NodeID, RemoteNode>, // All remotes this node has ever connected to
 	pub sessions: BiHashMap<SessionID, NodeID>, // Each SessionID links to a unique NodeID
 	pub node_list: BTreeMap<u64, NodeID>, // All nodes that have been tested, sorted by lowest value
-	pub peer_list: BiHashMap<NodeID, RouteCoord>, // Used for routing and peer management, peer count should be no more than TARGET_PEER_COUNT
+	pub peer_list: BiHashMap<NodeID, RouteCoord>, // Used for routing and peer management, peer count should be no more than config.target_peer_count
 	#[derivative(Debug="ignore")]
+	#[serde(with = "route_map_serde")]
 	pub route_map: DiGraphMap<NodeID, u64>, // Bi-directional graph of all locally known nodes and the estimated distances between them
 	// pub peered_nodes: PriorityQueue<SessionID, Reverse<RouteScalar>>, // Top subset of all 
-	pub action_list: ActionVec, // Actions will wait here until NodeID session is established
+	#[serde(skip)]
+	pub streams: HashMap<StreamID, NodeStream>, // TCP streams this node opened as the client, live connections aren't saved in snapshots
+	#[serde(skip)]
+	pub exit_streams: BTreeMap<(NodeID, StreamID), NodeStream>, // TCP streams this node is the exit of, keyed by the requesting node since every client picks its own StreamIDs
+	pub rendezvous: Rendezvous, // Introducers used if this node is private and introductions this node relays for others
+	pub traverse_receipts: BTreeMap<TraverseID, PendingReceipt>, // Traverse packets sent by this node that are waiting for a receipt, ordered so that resends go out in the same order every run
+	pub traverse_hops: BTreeMap<HopHandle, HopState>, // Traverse packets this node sent or passed on, by the handle it added to their path
+	pub action_list: ActionQueue, // Actions will wait here until NodeID session is established, their deadline passes or they run out of retries
+	#[derivative(Debug="ignore")]
+	pub events: VecDeque<NodeEvent>, // Events waiting to be taken by whoever is observing this node
+	#[derivative(Debug="ignore")]
+	#[serde(default)]
+	pub metrics: NodeMetrics, // Counters sampled by the simulation's metrics recorder
 }
 impl CustomNode for Node {
 	type CustomNodeAction = NodeAction;
 	fn net_id(&self) -> InternetID { self.net_id }
 	fn tick(&mut self, incoming: PacketVec) -> PacketVec {
+		let _span = span!("tick", node_id = self.node_id, net_id = self.net_id as u64, tick = self.ticks);
 		let mut outgoing = PacketVec::new();
+		self.busy = !incoming.is_empty();
 
 		// Parse Incoming Packets
 		for packet in incoming {
@@ -139,31 +351,131 @@ impl CustomNode for Node {
 			match self.parse_packet(packet, &mut outgoing) {
 				Ok(Some((return_node_id, node_packet))) => {
 					if let Err(err) = self.parse_node_packet(return_node_id, node_packet, &mut outgoing) {
-						log::error!("Error in parsing NodePacket from NodeID({}) to NodeID({}): {:?}", return_node_id, self.node_id, anyhow::Error::new(err));
+						self.count_error(&err);
+						log::error!("Error in parsing NodePacket from NodeID({}): {:?}", return_node_id, anyhow::Error::new(err));
 					}
 				},
 				Ok(None) => {},
-				Err(err) => { log::error!("Error in parsing InternetPacket from InternetID({}) to InternetID({}): {:?}", src_addr, dest_addr, anyhow::Error::new(err)); println!("{:?}", self); }
+				Err(err) => { self.count_error(&err); log::error!("Error in parsing InternetPacket from InternetID({}) to InternetID({}): {:?}", src_addr, dest_addr, anyhow::Error::new(err)); println!("{:?}", self); }
 			}
 		}
 		
-		let mut new_actions = ActionVec::new(); // Create buffer for new actions
-		let aq = std::mem::replace(&mut self.action_list, Default::default()); // Move actions out of action_list
-		// Execute and collect actions back into action_list
-		self.action_list = aq.into_iter().filter_map(|action|{
-			let action_clone = action.clone();
-			self.parse_action(action, &mut outgoing, &mut new_actions).unwrap_or_else(|err|{
-				log::error!("NodeID({}), Action {:?} errored: {:?}", self.node_id, action_clone, err); None
-			})
-		}).collect();
-		self.action_list.append(&mut new_actions); // Record new actions
+		let mut new_actions = Vec::new(); // Create buffer for new actions
+		let mut remaining_actions = Vec::new();
+		// Execute actions in order of priority, keeping those that are still waiting
+		for mut queued in self.action_list.take() {
+			if queued.expired(self.ticks) {
+				log::warn!("Action {:?} expired", queued.action);
+				self.push_event(NodeEvent::ActionFailed(queued.action, ActionFailure::Expired));
+				continue;
+			}
+			let action_clone = queued.action.clone();
+			let mut spawned = ActionVec::new();
+			let keep = match self.parse_action(queued.action, &mut outgoing, &mut spawned) {
+				Ok(Some(remaining)) => { queued.action = remaining; true },
+				Ok(None) => { queued.action = action_clone; self.busy = true; false },
+				Err(err) => {
+					self.count_error(&err);
+					self.busy = true;
+					let retry = queued.retries > 0;
+					if retry {
+						log::warn!("Action {:?} errored, {} retries left: {:?}", action_clone, queued.retries, err);
+						queued.retries -= 1;
+					} else {
+						log::error!("Action {:?} errored: {:?}", action_clone, err);
+						self.push_event(NodeEvent::ActionFailed(action_clone.clone(), ActionFailure::Errored(err.to_string())));
+					}
+					queued.action = action_clone;
+					retry
+				}
+			};
+			new_actions.extend(spawned.into_iter().map(|action|queued.spawn(action, self.ticks)));
+			if keep { remaining_actions.push(queued); }
+		}
+		for dropped in self.action_list.restore(remaining_actions, self.config.max_queued_actions) { self.queue_full(dropped); }
+		for queued in new_actions { self.enqueue(queued); } // Record new actions
+
+		// Pass data from exit connections back through their streams
+		self.poll_streams(&mut outgoing);
+		// Resend Traverse packets whose receipts haven't come back
+		self.check_traverse_receipts(&mut outgoing);
+		self.expire_traverse_hops();
+		if let Err(err) = self.refresh_introducers(&mut outgoing) {
+			self.count_error(&err);
+			log::error!("Failed to refresh introducers: {:?}", err);
+		}
 		
+		if !outgoing.is_empty() { self.busy = true; }
 		self.ticks += 1;
 		outgoing
 	}
-	fn action(&mut self, action: NodeAction) { self.action_list.push(action); }
+	fn idle_ticks(&self) -> Option<usize> {
+		// Exit streams have to be polled every tick
+		if self.busy || !self.exit_streams.is_empty() || self.streams.values().any(|s|s.is_busy()) { return Some(0) }
+		// Conditional actions wait for packets or a point in time, anything else runs on the next tick
+		// Waiting actions also wake the node up when they expire
+		let actions = self.action_list.iter().filter_map(|queued| match &queued.action {
+			NodeAction::Condition(condition, _) => condition.times().into_iter().filter(|&time|time >= self.ticks).min().map(|time|time - self.ticks),
+			_ => Some(0),
+		}.into_iter().chain(Some(queued.deadline.saturating_sub(self.ticks))).min());
+		let receipts = self.traverse_receipts.values().map(|p|(p.sent_at + self.config.traverse_receipt_timeout).saturating_sub(self.ticks));
+		let refresh = self.rendezvous.introducers.as_ref().map(|_|self.rendezvous.next_refresh.saturating_sub(self.ticks));
+		actions.chain(receipts).chain(refresh).min()
+	}
+	fn skip(&mut self, ticks: usize) { self.ticks += ticks; }
+	fn action(&mut self, action: NodeAction) { self.queue_action(action, ActionOptions::default()); }
 	fn as_any(&self) -> &dyn Any { self }
 	fn set_deus_ex_data(&mut self, data: Option<RouteCoord>) { self.deux_ex_data = data; }
+	fn set_rng(&mut self, rng: SimRng) {
+		self.rng = rng;
+		self.descriptor_key = self.rng.gen();
+	}
+	fn descriptor_key(&self) -> (NodeID, DescriptorKey) { (self.node_id, self.descriptor_key) }
+	fn packet_type(packet: &InternetPacket) -> String {
+		if let Some(request) = &packet.request { return request.kind().to_owned() }
+		match NodeEncryption::unpackage(packet) {
+			Ok(NodeEncryption::Handshake { .. }) => "Handshake".to_owned(),
+			Ok(NodeEncryption::Acknowledge { .. }) => "Acknowledge".to_owned(),
+			Ok(NodeEncryption::Session { packet, .. }) => format!("{:?}", packet.packet_type()),
+			Ok(_) => "Encrypted".to_owned(),
+			Err(_) => "Invalid".to_owned(),
+		}
+	}
+	fn metrics(&self) -> Vec<(MetricKey, f64)> {
+		let mut metrics = vec![
+			(MetricKey::new("handshakes_started_total").node(self.net_id), self.metrics.handshakes_started as f64),
+			(MetricKey::new("handshakes_completed_total").node(self.net_id), self.metrics.handshakes_completed as f64),
+			(MetricKey::new("action_queue_length").node(self.net_id), self.action_list.len() as f64),
+		];
+		metrics.extend(self.metrics.errors.iter().map(|(kind, &count)|(MetricKey::new("errors_total").node(self.net_id).kind(kind.clone()), count as f64)));
+		metrics
+	}
+	fn traverse_header(packet: &InternetPacket) -> Option<TraverseHeader> {
+		if packet.request.is_some() { return None }
+		match NodeEncryption::unpackage(packet) {
+			Ok(NodeEncryption::Session { packet: NodePacket::Traverse(header, _), .. }) => Some(header),
+			_ => None,
+		}
+	}
+}
+/// GraphMap doesn't implement serde, so route_map is stored as a list of nodes and edges
+mod route_map_serde {
+	use petgraph::graphmap::DiGraphMap;
+	use serde::{Serialize, Serializer, Deserialize, Deserializer};
+	use super::NodeID;
+
+	pub fn serialize<S: Serializer>(graph: &DiGraphMap<NodeID, u64>, serializer: S) -> Result<S::Ok, S::Error> {
+		let nodes: Vec<NodeID> = graph.nodes().collect();
+		let edges: Vec<(NodeID, NodeID, u64)> = graph.all_edges().map(|(a, b, &w)|(a, b, w)).collect();
+		(nodes, edges).serialize(serializer)
+	}
+	pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<DiGraphMap<NodeID, u64>, D::Error> {
+		let (nodes, edges) = <(Vec<NodeID>, Vec<(NodeID, NodeID, u64)>)>::deserialize(deserializer)?;
+		let mut graph = DiGraphMap::with_capacity(nodes.len(), edges.len());
+		for node in nodes { graph.add_node(node); }
+		for (a, b, w) in edges { graph.add_edge(a, b, w); }
+		Ok(graph)
+	}
 }
 #[derive(Error, Debug)]
 pub enum NodeError {
@@ -181,6 +493,18 @@ pub enum NodeError {
 	NoCalculatedRouteCoord,
 	#[error("There is no remote RouteCoord recorded for NodeID({remote:?})")]
 	NoRemoteRouteCoord { remote: NodeID },
+	#[error("There is no RendezvousDescriptor recorded for NodeID({remote:?})")]
+	NoDescriptor { remote: NodeID },
+	#[error("There is no known stream: {stream_id:?}")]
+	UnknownStream { stream_id: StreamID },
+	#[error("Stream {stream_id:?} is not open")]
+	StreamNotOpen { stream_id: StreamID },
+	#[error("Traverse path doesn't end in a handle this node knows, it may have expired")]
+	UnknownHopHandle,
+	#[error("Rendezvous packet from NodeID({remote:?}) arrived over a session instead of being routed")]
+	UnroutedRendezvous { remote: NodeID },
+	#[error("Timeout nested in Or or Not, its fallback might never run: {condition:?}")]
+	UnreachableFallback { condition: NodeActionCondition },
 	#[error("Triggered RemoteNodeError")]
 	RemoteNodeError(#[from] RemoteNodeError),
 	#[error("Remote Session Error")]
@@ -190,22 +514,124 @@ pub enum NodeError {
 	#[error(transparent)]
     Other(#[from] anyhow::Error),
 }
+impl NodeError {
+	/// Name of the variant, used to count errors by kind
+	pub fn kind(&self) -> &'static str {
+		match self {
+			NodeError::NoRemoteError { .. } => "NoRemoteError",
+			NodeError::UnknownSession { .. } => "UnknownSession",
+			NodeError::InvalidNetworkRecipient { .. } => "InvalidNetworkRecipient",
+			NodeError::InvalidHandshakeRecipient { .. } => "InvalidHandshakeRecipient",
+			NodeError::UnknownAcknowledgement { .. } => "UnknownAcknowledgement",
+			NodeError::NoCalculatedRouteCoord => "NoCalculatedRouteCoord",
+			NodeError::NoRemoteRouteCoord { .. } => "NoRemoteRouteCoord",
+			NodeError::NoDescriptor { .. } => "NoDescriptor",
+			NodeError::UnknownStream { .. } => "UnknownStream",
+			NodeError::StreamNotOpen { .. } => "StreamNotOpen",
+			NodeError::UnknownHopHandle => "UnknownHopHandle",
+			NodeError::UnroutedRendezvous { .. } => "UnroutedRendezvous",
+			NodeError::UnreachableFallback { .. } => "UnreachableFallback",
+			NodeError::RemoteNodeError(..) => "RemoteNodeError",
+			NodeError::SessionError(..) => "SessionError",
+			NodeError::SerdeDecodeError(..) => "SerdeDecodeError",
+			NodeError::Other(..) => "Other",
+		}
+	}
+}
+
+/// Counters a node keeps for `CustomNode::metrics`
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct NodeMetrics {
+	pub handshakes_started: u64,
+	pub handshakes_completed: u64,
+	/// Errors handling packets and actions, by `NodeError::kind`
+	pub errors: BTreeMap<String, u64>,
+}
 
 impl Node {
-	pub fn new(node_id: NodeID, net_id: InternetID) -> Node {
+	pub fn new(node_id: NodeID, net_id: InternetID, config: NodeConfig) -> Node {
 		Node {
 			node_id,
 			net_id,
+			config,
 			is_public: true,
 			..Default::default()
 		}
 	}
-	pub fn with_action(mut self, action: NodeAction) -> Self { self.action_list.push(action); self }
+	pub fn with_action(mut self, action: NodeAction) -> Self { self.action(action); self }
+	/// Queue an action with a priority, timeout or retry count other than the defaults from `NodeConfig`
+	pub fn queue_action(&mut self, action: NodeAction, options: ActionOptions) {
+		let _span = span!("queue_action", node_id = self.node_id, net_id = self.net_id as u64, tick = self.ticks, action = action.kind());
+		if let Err(err) = action.validate() {
+			self.count_error(&err);
+			log::error!("Action {:?} rejected: {}", action, err);
+			self.push_event(NodeEvent::ActionFailed(action, ActionFailure::Errored(err.to_string())));
+			return;
+		}
+		let queued = QueuedAction::new(action, options, &self.config, self.ticks);
+		self.enqueue(queued);
+		self.busy = true; // Check the action on the next tick even if nothing else would wake this node up
+	}
+	/// Queue an action caused by a packet from a remote. These are queued at low priority so that a remote flooding this node
+	/// only pushes out other remotes' actions, never the node's own.
+	fn remote_action(&mut self, action: NodeAction) { self.queue_action(action, ActionOptions { priority: ActionPriority::Low, ..Default::default() }); }
+	fn enqueue(&mut self, queued: QueuedAction) {
+		if let Some(dropped) = self.action_list.push(queued, self.config.max_queued_actions) { self.queue_full(dropped); }
+	}
+	fn queue_full(&mut self, dropped: QueuedAction) {
+		log::warn!("Action queue full, dropped {:?}", dropped.action);
+		self.push_event(NodeEvent::ActionFailed(dropped.action, ActionFailure::QueueFull));
+	}
 	pub fn remote(&self, node_id: &NodeID) -> Result<&RemoteNode, NodeError> { self.remotes.get(node_id).ok_or(NodeError::NoRemoteError{node_id: *node_id}) }
 	pub fn remote_mut(&mut self, node_id: &NodeID) -> Result<&mut RemoteNode, NodeError> { self.remotes.get_mut(node_id).ok_or(NodeError::NoRemoteError{node_id: *node_id}) }
+	fn count_error(&mut self, err: &NodeError) { *self.metrics.errors.entry(err.kind().to_owned()).or_default() += 1; }
+	/// Take all events that happened since the last call
+	pub fn take_events(&mut self) -> VecDeque<NodeEvent> { std::mem::take(&mut self.events) }
+	/// Take the events `matches` returns true for, leaving the rest queued in order
+	pub fn take_events_matching(&mut self, mut matches: impl FnMut(&NodeEvent) -> bool) -> VecDeque<NodeEvent> {
+		let (taken, kept) = std::mem::take(&mut self.events).into_iter().partition(|event|matches(event));
+		self.events = kept;
+		taken
+	}
+	fn push_event(&mut self, event: NodeEvent) {
+		if self.events.len() >= MAX_QUEUED_EVENTS { self.events.pop_front(); }
+		self.events.push_back(event);
+	}
+	/// Pick up to `target_peer_count` peers from `viable_peers` (sorted by latency) so that every direction around `self_route_coord` is covered.
+	/// The closest node in each of the `peer_sectors` angular sectors is taken first (spread evenly around the circle if there are more sectors than slots),
+	/// then any remaining slots are filled by latency.
+	fn select_peers(&self, self_route_coord: RouteCoord, viable_peers: Vec<(NodeID, RouteCoord)>) -> BiHashMap<NodeID, RouteCoord> {
+		let (target_peer_count, peer_sectors) = (self.config.target_peer_count, self.config.peer_sectors.max(1));
+		let self_route_coord_f64 = self_route_coord.map(|s|s as f64);
+		let mut sector_peers: Vec<Option<(f64, NodeID, RouteCoord)>> = vec![None; peer_sectors];
+		for &(node_id, route_coord) in &viable_peers {
+			let offset = route_coord.map(|s|s as f64) - self_route_coord_f64;
+			let angle = offset.y.atan2(offset.x) + std::f64::consts::PI; // 0..=2π
+			let sector = ((angle / std::f64::consts::TAU * peer_sectors as f64) as usize).min(peer_sectors - 1);
+			let dist = offset.norm();
+			match sector_peers[sector] {
+				Some((closest_dist, closest_id, _)) if (closest_dist, closest_id) <= (dist, node_id) => {},
+				_ => sector_peers[sector] = Some((dist, node_id, route_coord)),
+			}
+		}
+		let occupied = sector_peers.into_iter().flatten().collect::<Vec<_>>();
+		let picks = target_peer_count.min(occupied.len());
+		let mut peers: BiHashMap<NodeID, RouteCoord> = (0..picks).map(|i|occupied[i * occupied.len() / picks]).map(|(_, id, coord)|(id, coord)).collect();
+		for (node_id, route_coord) in viable_peers {
+			if peers.len() >= target_peer_count { break }
+			if !peers.contains_left(&node_id) { peers.insert(node_id, route_coord); }
+		}
+		peers
+	}
+	/// Find the peer closest to a RouteCoord that isn't in `exclude`, ties are broken by NodeID so that routing is deterministic
+	pub fn closest_peer(&self, route_coord: RouteCoord, exclude: &[NodeID]) -> Option<NodeID> {
+		let route_coord = route_coord.map(|s|s as f64);
+		self.peer_list.iter().filter(|(id,_)|!exclude.contains(id)).min_by_key(|(&id,p)|(nalgebra::distance_squared(&p.map(|s|s as f64), &route_coord) as i64, id)).map(|(&id,_)|id)
+	}
 
 	// Returns true if action should be deleted and false if it should not be
 	pub fn parse_action(&mut self, action: NodeAction, outgoing: &mut PacketVec, out_actions: &mut ActionVec) -> Result<Option<NodeAction>, NodeError> {
+		let _span = span!("parse_action", action = action.kind());
 		match action {
 			// Bootstrap node onto the network
 			NodeAction::Bootstrap(remote_node_id, net_id) => {
@@ -226,7 +652,7 @@ impl Node {
 				remote.route_coord = remote_route_coord;
 
 				// If this node has coord,
-				if let None = self.route_coord {
+				if self.route_coord.is_none() {
 					out_actions.push(NodeAction::CalcRouteCoord);
 					did_route_change = false;
 				}
@@ -234,8 +660,9 @@ impl Node {
 					out_actions.push(NodeAction::CalculatePeers);
 				}
 				// If need more peers & remote has a peer, request pings
-				if self.node_list.len() < TARGET_PEER_COUNT && remote_direct_count >= 2 {
-					self.remote_mut(&remote_node_id)?.add_packet(NodePacket::RequestPings(TARGET_PEER_COUNT, self_route_coord), outgoing)?;
+				if self.node_list.len() < self.config.target_peer_count && remote_direct_count >= 2 {
+					let target_peer_count = self.config.target_peer_count;
+					self.remote_mut(&remote_node_id)?.add_packet(NodePacket::RequestPings(target_peer_count, self_route_coord), outgoing)?;
 				}
 			}
 			NodeAction::CalcRouteCoord => {
@@ -251,11 +678,12 @@ impl Node {
 				// Collect the viable peers
 				let self_route_coord = self.route_coord.ok_or(NodeError::NoCalculatedRouteCoord)?;
 				let direct_nodes = self.node_list.iter().map(|s|*s.1).collect::<Vec<NodeID>>();
-				self.peer_list = direct_nodes.iter().filter_map(|node_id| {
+				let viable_peers = direct_nodes.iter().filter_map(|node_id| {
 					let remote = self.remote(node_id).unwrap();
 					// Decides whether remote should be added to peer list
-					if let Some(route_coord) = remote.is_viable_peer(self_route_coord) { Some((*node_id, route_coord)) } else { None }
-				}).take(TARGET_PEER_COUNT).collect();
+					remote.is_viable_peer(self_route_coord).map(|route_coord| (*node_id, route_coord))
+				}).collect::<Vec<(NodeID, RouteCoord)>>();
+				self.peer_list = self.select_peers(self_route_coord, viable_peers);
 				
 				// Notify Peers if just became peer
 				let num_peers = self.peer_list.len();
@@ -265,32 +693,61 @@ impl Node {
 					if !remote.session()?.is_peer() && toggle {
 						let dist = remote.session()?.tracker.dist_avg;
 						remote.add_packet(NodePacket::PeerNotify(0, self_route_coord, num_peers, dist), outgoing)?;
-					} else {  }
+					}
 					remote.session_mut()?.set_peer(toggle);
 				}
 				
+				// Take down the RouteCoord published before this node went private
+				if !self.is_public && self.public_route.take().is_some() {
+					outgoing.push( InternetPacket::gen_request(self.net_id, InternetRequest::RouteCoordDHTDelete(self.node_id)) );
+				}
 				// If have enough peers & want to host node as public, write RouteCoord to DHT
-				if self.peer_list.len() >= TARGET_PEER_COUNT && self.is_public && self.public_route != self.route_coord {
+				if self.peer_list.len() >= self.config.target_peer_count && self.is_public && self.public_route != self.route_coord {
 					self.public_route = self.route_coord;
 					outgoing.push( InternetPacket::gen_request(self.net_id, InternetRequest::RouteCoordDHTWrite(self.node_id, self_route_coord)) );
 				}
+				// Private nodes don't publish their RouteCoord, instead they become reachable through introducers
+				if self.peer_list.len() >= self.config.target_peer_count && !self.is_public && self.rendezvous.introducers.is_none() {
+					self.request_introducers(outgoing)?;
+				}
 			},
 			NodeAction::Traverse(remote_node_id, data) => {
 				if let Ok(Some(remote_route_coord)) = self.remote(&remote_node_id).map(|n|n.route_coord) {
 					let encryption = NodeEncryption::Traversal { recipient: remote_node_id, data, sender: self.node_id };
-					let remote_route_coord_f64 = remote_route_coord.map(|s|s as f64);
-					if let Some((min_node_id, _)) = self.peer_list.iter().min_by_key(|(_,p)|nalgebra::distance_squared(&p.map(|s|s as f64), &remote_route_coord_f64) as i64) {
-						self.remote(min_node_id)?.add_packet(NodePacket::Traverse(remote_route_coord, Box::new(encryption)), outgoing)?;
-					} else { log::error!("Could not find close node for Traverse action"); }
+					self.push_event(NodeEvent::TraverseSent(remote_node_id, data));
+					self.send_traverse(remote_route_coord, encryption, outgoing)?;
 					return Ok(None);
 				} else {
 					out_actions.push(NodeAction::RequestRouteCoord(remote_node_id));
 					out_actions.push(NodeAction::Traverse(remote_node_id, data).gen_condition(NodeActionCondition::RemoteRouteCoord(remote_node_id)));
 				}
 			},
+			NodeAction::TraverseReliable(remote_node_id, data) => {
+				if let Ok(Some(_)) = self.remote(&remote_node_id).map(|n|n.route_coord) {
+					self.push_event(NodeEvent::TraverseSent(remote_node_id, data));
+					let traverse_id = self.rng.gen();
+					self.traverse_receipts.insert(traverse_id, PendingReceipt { recipient: remote_node_id, data, sent_at: self.ticks, attempts: 0 });
+					self.send_traverse_reliable(traverse_id, outgoing)?;
+				} else {
+					out_actions.push(NodeAction::RequestRouteCoord(remote_node_id));
+					out_actions.push(NodeAction::TraverseReliable(remote_node_id, data).gen_condition(NodeActionCondition::RemoteRouteCoord(remote_node_id)));
+				}
+			},
 			NodeAction::RequestRouteCoord(remote_node_id) => {
 				outgoing.push(InternetPacket::gen_request(self.net_id, InternetRequest::RouteCoordDHTRead(remote_node_id)));
 			},
+			NodeAction::RequestDescriptor(remote_node_id) => {
+				outgoing.push(InternetPacket::gen_request(self.net_id, InternetRequest::DescriptorDHTRead(remote_node_id)));
+			},
+			NodeAction::Rendezvous(remote_node_id, data) => {
+				let has_descriptor = self.remote(&remote_node_id).map(|r|r.descriptor.is_some()).unwrap_or(false);
+				if has_descriptor || self.rendezvous.clients.contains_key(&remote_node_id) {
+					self.rendezvous_send(remote_node_id, data, outgoing)?;
+				} else {
+					out_actions.push(NodeAction::RequestDescriptor(remote_node_id));
+					out_actions.push(NodeAction::Rendezvous(remote_node_id, data).gen_condition(NodeActionCondition::RemoteDescriptor(remote_node_id)));
+				}
+			},
 			NodeAction::ConnectRouted(remote_node_id, hops) => {
 				let self_route_coord = self.route_coord.ok_or(NodeError::NoCalculatedRouteCoord)?;
 				// Check if Remote Route Coord was allready requested
@@ -302,7 +759,7 @@ impl Node {
 					for i in 1..hops {
 						routes.push(self_route_coord + diff * i as f64);
 					}
-					println!("Routes: {:?}", routes);
+					log::debug!("Routed session to NodeID({}) would go through {:?}, routed sessions aren't implemented", remote_node_id, routes);
 					self.routed_connect(remote_node_id, outgoing);
 					//self.remote_mut(&remote_node_id)?.connect_routed(routes);
 				} else { // Otherwise, Request it and await Condition for next ConnectRouted
@@ -313,17 +770,38 @@ impl Node {
 			NodeAction::Packet(remote_node_id, ref packet) => {
 				self.remote(&remote_node_id)?.add_packet(packet.clone(), outgoing)?;
 			},
+			NodeAction::OpenStream(stream_id, exit_node_id, ref addr) => {
+				self.open_stream(stream_id, exit_node_id, addr.clone(), outgoing, out_actions)?;
+			},
+			NodeAction::StreamSend(stream_id, ref data) => {
+				self.stream_send(stream_id, data.clone())?;
+			},
+			NodeAction::FinishStream(stream_id) => {
+				self.finish_stream(stream_id)?;
+			},
+			NodeAction::CloseStream(stream_id) => {
+				self.close_stream(stream_id, outgoing)?;
+			},
 			NodeAction::Condition(condition, embedded_action) => {
 				// Returns embedded action if condition is satisfied (e.g. check() returns true), else returns false to prevent action from being deleted
-				if condition.check(self)? { return Ok(Some(*embedded_action)); } else { return Ok(Some(NodeAction::Condition(condition, embedded_action))); }
+				// The action that takes the condition's place in the queue starts waiting now
+				if condition.check(self)? { self.busy = true; return Ok(Some(embedded_action.start(self.ticks))); }
+				if let Some(fallback) = condition.fallback(self.ticks) {
+					log::debug!("Condition {:?} timed out, running fallback", condition);
+					self.busy = true; return Ok(Some(fallback.clone().start(self.ticks)));
+				}
+				return Ok(Some(NodeAction::Condition(condition, embedded_action)));
 			}
 			_ => { unimplemented!("Unimplemented Action") },
 		}
-		log::trace!("[{: >6}] NodeID({}) Completed Action: {:?}", self.ticks, self.node_id, action);
+		log::trace!("Completed Action: {:?}", action);
 		Ok(None) // By default don't return action
 	}
 	pub fn parse_node_packet(&mut self, return_node_id: NodeID, received_packet: NodePacket, outgoing: &mut PacketVec) -> Result<(), NodeError> {
-		log::debug!("[{: >6}] Node({}) received NodePacket::{:?} from NodeID({})", self.ticks, self.node_id, received_packet, return_node_id);
+		let _span = span!("parse_node_packet", remote_id = return_node_id);
+		if log::log_enabled!(log::Level::Debug) { logging::record("packet_type", format!("{:?}", received_packet.packet_type())); }
+		if let Some(session) = self.remotes.get(&return_node_id).and_then(|r|r.session().ok()) { logging::record("session_id", session.session_id); }
+		log::debug!("Received NodePacket::{:?} from NodeID({})", received_packet, return_node_id);
 		//let return_remote = self.remote_mut(&return_node_id)?;
 		let self_ticks = self.ticks;
 		let packet_last_received  = self.remote_mut(&return_node_id)?.session_mut()?.check_packet_time(&received_packet, return_node_id, self_ticks);
@@ -342,7 +820,7 @@ impl Node {
 				if self.node_id == 0 && self.node_list.len() == 1 && self.route_coord.is_none() { self.route_coord = Some(self.calculate_route_coord()?); }
 
 				// Note Data, Update Remote
-				self.action(NodeAction::UpdateRemote(return_node_id, remote_route_coord, _remote_direct_count, remote_ping));
+				self.remote_action(NodeAction::UpdateRemote(return_node_id, remote_route_coord, _remote_direct_count, remote_ping));
 
 				// Send Return Packet
 				let route_coord = self.route_coord;
@@ -352,11 +830,11 @@ impl Node {
 				remote.add_packet(NodePacket::ExchangeInfoResponse(route_coord, peer_count, ping), outgoing)?;
 			},
 			NodePacket::ExchangeInfoResponse(remote_route_coord, remote_direct_count, remote_ping) => {
-				self.action(NodeAction::UpdateRemote(return_node_id, remote_route_coord, remote_direct_count, remote_ping));
+				self.remote_action(NodeAction::UpdateRemote(return_node_id, remote_route_coord, remote_direct_count, remote_ping));
 			},
 
 			NodePacket::ProposeRouteCoords(route_coord_proposal, remote_route_coord_proposal) => {
-				if None == self.route_coord {
+				if self.route_coord.is_none() {
 					self.route_coord = Some(route_coord_proposal);
 					let remote = self.remote_mut(&return_node_id)?;
 					remote.route_coord = Some(remote_route_coord_proposal);
@@ -366,24 +844,20 @@ impl Node {
 					remote.add_packet(NodePacket::ProposeRouteCoordsResponse(route_coord_proposal, remote_route_coord_proposal, false), outgoing)?;
 				}
 			},
-			NodePacket::ProposeRouteCoordsResponse(initial_remote_proposal, initial_self_proposal, accepted) => {
-				if accepted {
-					self.route_coord = Some(initial_self_proposal);
-					self.remote_mut(&return_node_id)?.route_coord = Some(initial_remote_proposal);
-				}
+			NodePacket::ProposeRouteCoordsResponse(initial_remote_proposal, initial_self_proposal, true) => {
+				self.route_coord = Some(initial_self_proposal);
+				self.remote_mut(&return_node_id)?.route_coord = Some(initial_remote_proposal);
 			},
 			NodePacket::RequestPings(requests, requester_route_coord) => {
-				if let Some(time) = packet_last_received { if time < 2000 { return Ok(()) } } // Nodes should not be spamming this multiple times
-				// Loop through first min(N,MAX_REQUEST_PINGS) items of priorityqueue
-				let num_requests = usize::min(requests, MAX_REQUEST_PINGS); // Maximum of 10 requests
+				if let Some(time) = packet_last_received { if time < self.config.request_pings_window { return Ok(()) } } // Nodes should not be spamming this multiple times
+				// Loop through first min(N,max_request_pings) items of priorityqueue
+				let num_requests = usize::min(requests, self.config.max_request_pings);
 
 				self.remote_mut(&return_node_id)?.route_coord = requester_route_coord;
 				let closest_nodes = if let Some(route_coord) = requester_route_coord {
 					let point_target = route_coord.map(|s|s as f64);
 					let mut sorted = self.node_list.iter().filter_map(|(&_,&id)|{
-						if let Some(p) = self.remote(&id).unwrap().route_coord {
-							Some((id, nalgebra::distance_squared(&p.map(|s|s as f64), &point_target) as u64))
-						} else { None }
+						self.remote(&id).unwrap().route_coord.map(|p| (id, nalgebra::distance_squared(&p.map(|s|s as f64), &point_target) as u64))
 					}).collect::<Vec<(NodeID, u64)>>();
 					sorted.sort_unstable_by_key(|k|k.1);
 					sorted.iter().map(|s|s.0).take(num_requests).collect()
@@ -413,13 +887,13 @@ impl Node {
 					return Ok(())
 				} else { // If no session, send request
 					if request_remote.pending_session.is_none() {
-						self.action(NodeAction::Connect(requesting_node_id, requesting_net_id, vec![NodePacket::AcceptWantPing(return_node_id, distance_self_to_return)]));
+						self.remote_action(NodeAction::Connect(requesting_node_id, requesting_net_id, vec![NodePacket::AcceptWantPing(return_node_id, distance_self_to_return)]));
 					}
 				}
 			},
 			NodePacket::AcceptWantPing(intermediate_node_id, return_to_intermediate_distance) => {
 				self.route_map.add_edge(return_node_id, intermediate_node_id, return_to_intermediate_distance);
-				if let Some(time) = packet_last_received { if time < 300 { return Ok(()) } }
+				if let Some(time) = packet_last_received { if time < self.config.accept_want_ping_window { return Ok(()) } }
 
 				let self_route_coord = self.route_coord;
 				let self_node_count = self.node_list.len();
@@ -432,25 +906,16 @@ impl Node {
 				//let session = self.remote_mut(&return_node_id)?.session_mut()?;
 				//session.record_peer_notify(rank);
 				// Update remote
-				self.action(NodeAction::UpdateRemote(return_node_id, Some(route_coord), peer_count, peer_distance));
-			},
-			NodePacket::Traverse(ref target_route_coord, ref encrypted_data) => {
-				let float_target_route_coord = target_route_coord.map(|s|s as f64);
-				if let Some((&min_node_id, _)) = self.peer_list.iter().min_by_key(|(_,p)|nalgebra::distance_squared(&p.map(|s|s as f64), &float_target_route_coord) as i64) {
-					if match **encrypted_data {
-						NodeEncryption::Traversal { recipient, data, sender } => {
-							if recipient == self.node_id {
-								// If packet meant for me, log it
-								log::info!("NodeID({}) Received Traverse packet with data: {} from NodeID({})", self.node_id, data, sender); false
-							} else { true }
-						}
-						_ => { unimplemented!("Traverse doesn't support this NodeEncryption variant") }
-					} {
-						if min_node_id != return_node_id {
-							self.remote(&min_node_id)?.add_packet(received_packet, outgoing)?; // Forward packet to nearest to destination
-						} else { log::error!("Shoudn't sent Traverse back to sending node") }
-					}
-				} else { log::error!("Failed to find Minimum distance node"); }
+				self.remote_action(NodeAction::UpdateRemote(return_node_id, Some(route_coord), peer_count, peer_distance));
+			},
+			NodePacket::Traverse(..) | NodePacket::TraverseUndeliverable(..) | NodePacket::TraverseDelivered(..) | NodePacket::TraverseReturn(..) => {
+				self.parse_traverse_packet(return_node_id, received_packet, outgoing)?;
+			},
+			NodePacket::StreamOpen(..) | NodePacket::StreamOpenResponse(..) | NodePacket::StreamData(..) | NodePacket::StreamFinish(..) | NodePacket::StreamAck(..) | NodePacket::StreamClose(..) => {
+				self.parse_stream_packet(return_node_id, received_packet, outgoing)?;
+			},
+			NodePacket::IntroducerRequest | NodePacket::IntroducerAccept(..) | NodePacket::Introduction(..) | NodePacket::IntroductionReply(..) => {
+				return Err(NodeError::UnroutedRendezvous { remote: return_node_id });
 			},
 			_ => { },
 		}
@@ -459,17 +924,18 @@ impl Node {
 
 	/// Initiate handshake process and send packets when completed
 	fn direct_connect(&mut self, dest_node_id: NodeID, dest_addr: InternetID, initial_packets: Vec<NodePacket>, outgoing: &mut PacketVec) {
-		let session_id: SessionID = rand::random(); // Create random session ID
+		let session_id: SessionID = self.rng.gen(); // Create random session ID
 		//let self_node_id = self.node_id;
 		let self_ticks = self.ticks;
 		let remote = self.remotes.entry(dest_node_id).or_insert(RemoteNode::new(dest_node_id));
 		remote.pending_session = Some(Box::new((session_id, self_ticks, initial_packets)));
 		// TODO: public key encryption
 		let encryption = NodeEncryption::Handshake { recipient: dest_node_id, session_id, signer: self.node_id };
+		self.metrics.handshakes_started += 1;
 		outgoing.push(encryption.package(dest_addr))
 	}
 	// Create multiple Routed Sessions that sequentially resolve their pending_route fields as Traversal Packets are acknowledged
-	fn routed_connect(&mut self, dest_node_id: NodeID, outgoing: &mut PacketVec) {
+	fn routed_connect(&mut self, _dest_node_id: NodeID, _outgoing: &mut PacketVec) {
 		/*let session_id: SessionID = rand::random();
 		let remote = self.remotes.entry(dest_node_id).or_insert(RemoteNode::new(dest_node_id));
 		remote.pending_session = Some((session_id, usize::MAX, initial_packets));*/
@@ -477,9 +943,11 @@ impl Node {
 	}
 	/// Parses handshakes, acknowledgments and sessions, Returns Some(remote_net_id, packet_to_parse) if session or handshake finished
 	fn parse_packet(&mut self, received_packet: InternetPacket, outgoing: &mut PacketVec) -> Result<Option<(NodeID, NodePacket)>, NodeError> {
+		let _span = span!("parse_packet", src_addr = received_packet.src_addr as u64);
 		if received_packet.dest_addr != self.net_id { return Err(NodeError::InvalidNetworkRecipient { from: received_packet.src_addr, intended_dest: received_packet.dest_addr }) }
 
 		if let Some(request) = received_packet.request {
+			logging::record("packet_type", request.kind());
 			match request {
 				InternetRequest::RouteCoordDHTReadResponse(query_node_id, route_option) => {
 					if let Some(query_route_coord) = route_option {
@@ -490,6 +958,20 @@ impl Node {
 					}
 				},
 				InternetRequest::RouteCoordDHTWriteResponse(_) => {},
+				InternetRequest::DescriptorDHTReadResponse(query_node_id, descriptor_option) => {
+					match descriptor_option {
+						// The DHT only stores descriptors it authenticated, clients can't check the MAC without the private node's key
+						Some(descriptor) if descriptor.node_id == query_node_id => {
+							let remote = self.remotes.entry(query_node_id).or_insert(RemoteNode::new(query_node_id));
+							remote.descriptor = Some(descriptor);
+						},
+						Some(_) => log::warn!("Invalid RendezvousDescriptor found for: {:?}", query_node_id),
+						None => log::warn!("No RendezvousDescriptor found for: {:?}", query_node_id),
+					}
+				},
+				InternetRequest::DescriptorDHTWriteResponse(valid) => {
+					if !valid { log::error!("RendezvousDescriptor was rejected by the DHT") }
+				},
 				_ => { log::warn!("Not a InternetRequest Response variant") }
 			}
 			return Ok(None);
@@ -497,32 +979,40 @@ impl Node {
 
 		let return_net_id = received_packet.src_addr;
 		let encrypted = NodeEncryption::unpackage(&received_packet)?;
+		match &encrypted {
+			NodeEncryption::Handshake { session_id, .. } => { logging::record("packet_type", "Handshake"); logging::record("session_id", *session_id); },
+			NodeEncryption::Acknowledge { session_id, .. } => { logging::record("packet_type", "Acknowledge"); logging::record("session_id", *session_id); },
+			NodeEncryption::Session { session_id, packet } => {
+				// Only formatted when the records that show it can be written
+				if log::log_enabled!(log::Level::Debug) { logging::record("packet_type", format!("{:?}", packet.packet_type())); }
+				logging::record("session_id", *session_id);
+			},
+			_ => {},
+		}
 		let self_ticks = self.ticks;
 		let self_node_id = self.node_id;
 		Ok(match encrypted {
 			NodeEncryption::Handshake { recipient, session_id, signer } => {
 				if recipient != self.node_id { Err(RemoteNodeError::UnknownAckRecipient { recipient })?; }
 				let remote = self.remotes.entry(signer).or_insert(RemoteNode::new(signer));
-				if remote.pending_session.is_some() {
-					if self_node_id < remote.node_id { remote.pending_session = None }
-				}
+				if remote.pending_session.is_some() && self_node_id < remote.node_id { remote.pending_session = None }
 				let mut session = RemoteSession::from_address(session_id, return_net_id);
-				let return_ping_id = session.tracker.gen_ping(self_ticks);
+				let return_ping_id = session.tracker.gen_ping(self_ticks, self.config.max_pending_pings, &mut self.rng);
 				remote.session = Some(session);
 				outgoing.push(NodeEncryption::Acknowledge { session_id, acknowledger: recipient, return_ping_id }.package(return_net_id));
 				self.sessions.insert(session_id, signer);
-				log::debug!("[{: >6}] Node({:?}) Received Handshake: {:?}", self_ticks, self_node_id, encrypted);
+				log::debug!("Received Handshake: {:?}", encrypted);
 				None
 			},
 			NodeEncryption::Acknowledge { session_id, acknowledger, return_ping_id } => {
-				let mut remote = self.remote_mut(&acknowledger)?;
+				let remote = self.remotes.get_mut(&acknowledger).ok_or(NodeError::NoRemoteError { node_id: acknowledger })?;
 				if let Some(boxed_pending) = remote.pending_session.take() {
 					let (pending_session_id, time_sent_handshake, packets_to_send) = *boxed_pending;
 					
 					if pending_session_id == session_id {
 						// Create session and acknowledge out-of-tracker ping
 						let mut session = RemoteSession::from_address(session_id, return_net_id);
-						let ping_id = session.tracker.gen_ping(time_sent_handshake);
+						let ping_id = session.tracker.gen_ping(time_sent_handshake, self.config.max_pending_pings, &mut self.rng);
 						let distance = session.tracker.acknowledge_ping(ping_id, self_ticks)?;
 						remote.session = Some(session); // update remote
 
@@ -532,10 +1022,11 @@ impl Node {
 						// Send connection packets
 						self.remote_mut(&acknowledger)?.add_packet(NodePacket::ConnectionInit(return_ping_id, packets_to_send), outgoing)?;
 						self.sessions.insert(session_id, acknowledger);
+						self.metrics.handshakes_completed += 1;
 
 						self.node_list.insert(distance, acknowledger);
 						self.route_map.add_edge(self.node_id, acknowledger, distance);
-						log::debug!("[{: >6}] Node({:?}) Received Acknowledgement: {:?}", self_ticks, self_node_id, encrypted);
+						log::debug!("Received Acknowledgement: {:?}", encrypted);
 						None
 					} else { Err( RemoteNodeError::UnknownAck { passed: session_id } )? }
 				} else { Err(RemoteNodeError::NoPendingHandshake)? }
@@ -558,8 +1049,8 @@ impl Node {
 	}
 	fn calculate_route_coord(&mut self) -> Result<RouteCoord, NodeError> {
 		let route_coord = self.deux_ex_data.ok_or(NodeError::Other(anyhow!("no deus ex machina data")))?;
-		log::debug!("NodeID({}) Calculated RouteCoord({})", self.node_id, route_coord);
-		return Ok(route_coord);
+		log::debug!("Calculated RouteCoord({})", route_coord);
+		Ok(route_coord)
 
 		/* // TODO: Refactor this implementation of multidimensional scaling
 		// println!("node_list: {:?}", self.remotes.iter().map(|(&id,n)|(id,n.route_coord)).collect::<Vec<(NodeID,Option<RouteCoord>)>>() );
@@ -609,11 +1100,11 @@ impl Node {
 		let top_eigenvalues = nalgebra::Matrix2::new(max_eigenvalue.1.abs().sqrt(), 0., 0., second_max_eigenvalue.1.abs().sqrt()); // Eigenvalue matrix
 		let top_eigenvectors = DMatrix::from_fn(mat_size, 2, |r,c| if c==0 { eigen.eigenvectors[(r,max_eigenvalue.0)] } else { eigen.eigenvectors[(r,second_max_eigenvalue.0)] });
 		let mut x_matrix = top_eigenvectors.clone() * top_eigenvalues; // Output, index 0 needs to be mapped to virtual routecoord coordinates based on other indices
-		log::trace!("NodeID({}) x_matrix prediction = {}", self.node_id, x_matrix);
+		log::trace!("x_matrix prediction = {}", x_matrix);
 		/* if mat_size == 3 {
 			x_matrix.row_iter_mut().for_each(|mut r|r[1] = -r[1]);
 		}
-		log::trace!("NodeID({}) x_matrix prediction flip = {}", self.node_id, x_matrix); */
+		log::trace!("x_matrix prediction flip = {}", x_matrix); */
 
 		// Map MDS output to 2 RouteCoordinates
 		// TODO: Refactor this messy code
@@ -650,20 +1141,151 @@ impl Node {
 	}
 }
 
-use plotters::style::RGBColor;
+use plotters::style::{RGBColor, colors::{BLACK, WHITE, BLUE}};
+use crate::plot::{PlotNode, PlotEdge};
 impl GraphPlottable for Node {
-	fn gen_graph(&self) -> Graph<(String, Point2<i32>), RGBColor> {
-		for _node in self.route_map.nodes() {
+	/// This node's view of the network: every node it knows the RouteCoord of, placed at that RouteCoord, and the latencies it has measured or been told about.
+	/// Links from this node are colored by session: black for peers, white for other direct sessions, blue for routed sessions and yellow for pending sessions.
+	/// Latencies measured between other nodes are gray.
+	fn gen_graph(&self) -> Graph<PlotNode, PlotEdge> {
+		let mut graph = Graph::new();
+		let coord = |node_id: NodeID| if node_id == self.node_id { self.route_coord } else { self.remotes.get(&node_id).and_then(|r|r.route_coord) };
+		let mut indices = HashMap::new();
+		let node_ids = std::iter::once(self.node_id).chain(self.route_map.nodes()).chain(self.remotes.keys().cloned());
+		for node_id in node_ids {
+			if indices.contains_key(&node_id) { continue }
+			if let Some(route_coord) = coord(node_id) {
+				let color = if node_id == self.node_id { RGBColor(0, 90, 200) } else if self.peer_list.contains_left(&node_id) { BLACK } else { RGBColor(100, 100, 100) };
+				indices.insert(node_id, graph.add_node(PlotNode::new(node_id.to_string(), route_coord.map(|c|c as i32)).with_color(color)));
+			}
+		}
+		let session_color = |remote: &RemoteNode| -> Option<RGBColor> {
+			if remote.pending_session.is_some() { return Some(RGBColor(230, 180, 0)) }
+			match &remote.session.as_ref()?.session_type {
+				SessionType::Direct(direct) if direct.is_peered => Some(BLACK),
+				SessionType::Direct(_) => Some(WHITE),
+				SessionType::Routed(_) => Some(BLUE),
+			}
+		};
+		for (from, to, &latency) in self.route_map.all_edges() {
+			if let (Some(&from_idx), Some(&to_idx)) = (indices.get(&from), indices.get(&to)) {
+				// Links to this node are colored by the session with the other end
+				let remote = if from == self.node_id { self.remotes.get(&to) } else if to == self.node_id { self.remotes.get(&from) } else { None };
+				let color = remote.and_then(session_color).unwrap_or(RGBColor(150, 150, 150));
+				graph.add_edge(from_idx, to_idx, PlotEdge { color, label: Some(latency.to_string()) });
+			}
+		}
+		// Sessions that no latency has been measured over yet
+		if let Some(&self_idx) = indices.get(&self.node_id) {
+			for (&remote_id, remote) in &self.remotes {
+				if self.route_map.contains_edge(self.node_id, remote_id) || self.route_map.contains_edge(remote_id, self.node_id) { continue }
+				if let (Some(&remote_idx), Some(color)) = (indices.get(&remote_id), session_color(remote)) {
+					graph.add_edge(self_idx, remote_idx, color.into());
+				}
+			}
+		}
+		graph
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
 
+	/// Action that waits forever, so that it stays in the queue to be inspected
+	fn parked(data: u64) -> NodeAction { NodeAction::Traverse(9, data).gen_condition(NodeActionCondition::RunAt(usize::MAX)) }
+	fn run(node: &mut Node, ticks: usize) { for _ in 0..ticks { node.tick(PacketVec::new()); } }
+	fn queued_actions(node: &Node) -> Vec<NodeAction> { node.action_list.iter().map(|q|q.action.clone()).collect() }
 
+	#[test]
+	fn timeout_counts_from_queueing() {
+		let mut node = Node::new(1, 1, NodeConfig::default());
+		node.skip(100);
+		let condition = NodeActionCondition::timeout(5, NodeActionCondition::PeerCountAtLeast(99), parked(1));
+		node.action(NodeAction::CalculatePeers.gen_condition(condition));
+		assert!(matches!(queued_actions(&node)[0], NodeAction::Condition(NodeActionCondition::Timeout { deadline: Some(105), .. }, _)));
+		run(&mut node, 5);
+		assert!(matches!(queued_actions(&node)[0], NodeAction::Condition(NodeActionCondition::Timeout { .. }, _)));
+		run(&mut node, 1);
+		assert_eq!(queued_actions(&node), vec![parked(1)]);
+	}
+
+	#[test]
+	fn nested_timeout_runs_fallback() {
+		let mut node = Node::new(1, 1, NodeConfig::default());
+		let condition = NodeActionCondition::And(vec![
+			NodeActionCondition::HasRouteCoord,
+			NodeActionCondition::timeout(3, NodeActionCondition::PeerCountAtLeast(99), parked(2)),
+		]);
+		node.action(NodeAction::CalculatePeers.gen_condition(condition));
+		run(&mut node, 4);
+		assert_eq!(queued_actions(&node), vec![parked(2)]);
+	}
+
+	#[test]
+	fn timeout_under_or_or_not_is_rejected() {
+		let timeout = NodeActionCondition::timeout(3, NodeActionCondition::HasRouteCoord, parked(3));
+		for condition in [NodeActionCondition::Or(vec![NodeActionCondition::HasRouteCoord, timeout.clone()]), NodeActionCondition::Not(Box::new(timeout))] {
+			let mut node = Node::new(1, 1, NodeConfig::default());
+			let action = NodeAction::CalculatePeers.gen_condition(condition);
+			node.action(action.clone());
+			assert!(node.action_list.is_empty());
+			assert!(matches!(node.take_events().pop_front(), Some(NodeEvent::ActionFailed(failed, ActionFailure::Errored(_))) if failed == action));
+		}
+	}
+
+	#[test]
+	fn peers_are_spread_over_sectors() {
+		let config = NodeConfig { target_peer_count: 2, peer_sectors: 4, ..Default::default() };
+		let node = Node::new(1, 1, config);
+		// One viable peer in each quadrant, starting from the lowest angle
+		let viable_peers = vec![(10, RouteCoord::new(-10, -10)), (11, RouteCoord::new(10, -10)), (12, RouteCoord::new(10, 10)), (13, RouteCoord::new(-10, 10))];
+		let peers = node.select_peers(RouteCoord::new(0, 0), viable_peers);
+		let mut peer_ids = peers.left_values().copied().collect::<Vec<_>>();
+		peer_ids.sort();
+		assert_eq!(peer_ids, vec![10, 12]);
+	}
+
+	#[test]
+	fn undeliverable_notice_retries_its_own_packet() {
+		let mut node = Node::new(1, 1, NodeConfig::default());
+		let mut remote = RemoteNode::new(7);
+		remote.route_coord = Some(RouteCoord::new(10, 10));
+		node.remotes.insert(7, remote);
+		// Two pending packets with the same recipient and data
+		for traverse_id in [3, 4] { node.traverse_receipts.insert(traverse_id, PendingReceipt { recipient: 7, data: 5, sent_at: 0, attempts: 1 }); }
+		node.traverse_hops.insert(2, HopState { previous: None, nonce: 6, tried: vec![], used_at: 0 });
+		let encryption = Box::new(NodeEncryption::Traversal { recipient: 7, data: 5, sender: 1 });
+		node.parse_traverse_packet(8, NodePacket::TraverseUndeliverable(vec![2], TraverseFailure::DeadEnd, Some(4), encryption), &mut PacketVec::new()).unwrap();
+		// Without peers every retry of packet 4 is undeliverable straight away, until it is given up on
+		assert!(!node.traverse_receipts.contains_key(&4));
+		assert_eq!(node.traverse_receipts[&3].attempts, 1);
+		assert_eq!(node.take_events(), vec![NodeEvent::TraverseUndeliverable(7, 5, TraverseFailure::NoReceipt)]);
+	}
+
+	#[test]
+	fn flooding_remote_cant_push_out_own_actions() {
+		let mut node = Node::new(1, 1, NodeConfig { max_queued_actions: 8, ..Default::default() });
+		let mut remote = RemoteNode::new(9);
+		remote.session = Some(RemoteSession::from_address(1, 9));
+		node.remotes.insert(9, remote);
+		for _ in 0..20 { node.parse_node_packet(9, NodePacket::ExchangeInfoResponse(None, 0, 0), &mut PacketVec::new()).unwrap(); }
+		assert_eq!(node.action_list.len(), 8);
+		node.action(parked(1));
+		assert_eq!(node.action_list.len(), 8);
+		assert!(queued_actions(&node).contains(&parked(1)));
+		assert!(node.take_events().iter().all(|event|matches!(event, NodeEvent::ActionFailed(NodeAction::UpdateRemote(9, ..), ActionFailure::QueueFull))));
+	}
+
+	#[test]
+	fn unknown_remote_has_no_session() {
+		let unknown_session = || NodeActionCondition::Session(42);
+		for condition in [NodeActionCondition::Or(vec![unknown_session(), NodeActionCondition::HasRouteCoord]), NodeActionCondition::Not(Box::new(unknown_session()))] {
+			let mut node = Node::new(1, 1, NodeConfig::default());
+			node.route_coord = Some(RouteCoord::new(0, 0));
+			node.action(parked(4).gen_condition(condition));
+			run(&mut node, 1);
+			assert_eq!(queued_actions(&node), vec![parked(4)]);
 		}
-		/* let node_index self.node_list.iter().map(|(_, id)|self.remotes[id].)
-		self.route_map.clone().into_graph().filter_map(|idx, node_id|{
-			let remote = self.remote(&id).ok();
-			remote.map(|r|r.route_coord.map(|c|(id, c)))
-		}, |idx, _|{
-			
-		}) */
-		Graph::with_capacity(0, 0)
-	}
-}
\ No newline at end of file
+	}
+}
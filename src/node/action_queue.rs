@@ -0,0 +1,130 @@
+use crate::node::{NodeAction, NodeConfig};
+
+/// Order actions run in each tick, higher priorities first
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ActionPriority { Low, #[default] Normal, High }
+
+/// How an action is queued, unset fields are taken from the node's `NodeConfig`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct ActionOptions {
+	#[serde(default)]
+	pub priority: ActionPriority,
+	/// Ticks the action may wait in the queue before it expires, `NodeConfig::action_timeout` if None
+	pub timeout: Option<usize>,
+	/// Number of times the action is run again after it errors, `NodeConfig::action_retries` if None
+	pub retries: Option<usize>,
+}
+
+/// Why an action left the queue without completing
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ActionFailure {
+	/// The action was still waiting when its deadline passed
+	Expired,
+	/// The action errored and had no retries left
+	Errored(String),
+	/// The queue was full of actions of the same or higher priority
+	QueueFull,
+}
+
+/// An action waiting in an `ActionQueue`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QueuedAction {
+	pub action: NodeAction,
+	pub priority: ActionPriority,
+	/// Tick the action expires on if it is still waiting
+	pub deadline: usize,
+	/// Number of times the action will still be run again if it errors
+	pub retries: usize,
+	/// Order the action was queued in, used to run actions of the same priority first in first out
+	seq: u64,
+}
+impl QueuedAction {
+	/// Queue `action` on tick `now`, starting the clock of its condition's `Timeout`s.
+	/// The action's own timeout only starts once the last time its condition waits for (`RunAt` or `Timeout`) has come.
+	pub fn new(action: NodeAction, options: ActionOptions, config: &NodeConfig, now: usize) -> Self {
+		let action = action.start(now);
+		let start = if let NodeAction::Condition(condition, _) = &action { condition.times().into_iter().fold(now, usize::max) } else { now };
+		let deadline = start.saturating_add(options.timeout.unwrap_or(config.action_timeout));
+		Self { action, priority: options.priority, deadline, retries: options.retries.unwrap_or(config.action_retries), seq: 0 }
+	}
+	/// Action created on tick `now` while running this one, it keeps this action's priority, deadline and retries
+	pub fn spawn(&self, action: NodeAction, now: usize) -> Self { Self { action: action.start(now), priority: self.priority, deadline: self.deadline, retries: self.retries, seq: 0 } }
+	pub fn expired(&self, now: usize) -> bool { now >= self.deadline }
+	fn order(&self) -> (std::cmp::Reverse<ActionPriority>, u64) { (std::cmp::Reverse(self.priority), self.seq) }
+}
+
+/// Bounded queue of actions, ordered by priority and then by the order they were queued in
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ActionQueue {
+	actions: Vec<QueuedAction>,
+	next_seq: u64,
+}
+impl ActionQueue {
+	/// Queue an action, keeping at most `max` actions. Returns the action dropped to stay within the bound:
+	/// the newest action of the lowest priority if it is lower than the new action's, otherwise the new action itself.
+	pub fn push(&mut self, mut queued: QueuedAction, max: usize) -> Option<QueuedAction> {
+		queued.seq = self.next_seq;
+		self.next_seq += 1;
+		let dropped = if self.actions.len() >= max {
+			match self.actions.last() {
+				Some(lowest) if lowest.priority < queued.priority => self.actions.pop(),
+				_ => return Some(queued),
+			}
+		} else { None };
+		self.insert(queued);
+		dropped
+	}
+	fn insert(&mut self, queued: QueuedAction) {
+		let index = self.actions.partition_point(|a|a.order() < queued.order());
+		self.actions.insert(index, queued);
+	}
+	/// Take every queued action to run them
+	pub fn take(&mut self) -> Vec<QueuedAction> { std::mem::take(&mut self.actions) }
+	/// Put back actions taken with `take` that are still waiting, in their original order, along with any queued since.
+	/// Returns the actions dropped to keep at most `max`: the newest of the lowest priority.
+	pub fn restore(&mut self, actions: Vec<QueuedAction>, max: usize) -> Vec<QueuedAction> {
+		for queued in actions { self.insert(queued) }
+		if self.actions.len() > max { self.actions.split_off(max) } else { Vec::new() }
+	}
+	pub fn len(&self) -> usize { self.actions.len() }
+	pub fn is_empty(&self) -> bool { self.actions.is_empty() }
+	pub fn iter(&self) -> impl Iterator<Item = &QueuedAction> { self.actions.iter() }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn queued(action: NodeAction, priority: ActionPriority) -> QueuedAction {
+		QueuedAction::new(action, ActionOptions { priority, ..Default::default() }, &NodeConfig::default(), 0)
+	}
+	fn actions(queue: &ActionQueue) -> Vec<NodeAction> { queue.iter().map(|q|q.action.clone()).collect() }
+
+	#[test]
+	fn push_evicts_lower_priority_or_rejects() {
+		let mut queue = ActionQueue::default();
+		assert!(queue.push(queued(NodeAction::Traverse(1, 1), ActionPriority::Low), 2).is_none());
+		assert!(queue.push(queued(NodeAction::Traverse(2, 2), ActionPriority::Normal), 2).is_none());
+		// Full: a higher priority action evicts the lowest priority one, an equal priority one is rejected
+		let evicted = queue.push(queued(NodeAction::Traverse(3, 3), ActionPriority::High), 2).unwrap();
+		assert_eq!(evicted.action, NodeAction::Traverse(1, 1));
+		let rejected = queue.push(queued(NodeAction::Traverse(4, 4), ActionPriority::Normal), 2).unwrap();
+		assert_eq!(rejected.action, NodeAction::Traverse(4, 4));
+		assert_eq!(actions(&queue), vec![NodeAction::Traverse(3, 3), NodeAction::Traverse(2, 2)]);
+	}
+
+	#[test]
+	fn restore_keeps_the_bound() {
+		let mut queue = ActionQueue::default();
+		for i in 0..3 { queue.push(queued(NodeAction::Traverse(i, i as u64), ActionPriority::Normal), 3); }
+		let taken = queue.take();
+		// Actions queued while the taken ones were running
+		queue.push(queued(NodeAction::Traverse(10, 10), ActionPriority::High), 3);
+		queue.push(queued(NodeAction::Traverse(11, 11), ActionPriority::Low), 3);
+		let dropped = queue.restore(taken, 3);
+		assert_eq!(queue.len(), 3);
+		assert_eq!(actions(&queue), vec![NodeAction::Traverse(10, 10), NodeAction::Traverse(0, 0), NodeAction::Traverse(1, 1)]);
+		assert_eq!(dropped.into_iter().map(|q|q.action).collect::<Vec<_>>(), vec![NodeAction::Traverse(2, 2), NodeAction::Traverse(11, 11)]);
+	}
+}
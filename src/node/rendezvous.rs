@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+
+use rand::Rng;
+
+use crate::internet::{InternetPacket, InternetRequest};
+use crate::node::{Node, NodeID, NodePacket, NodeEncryption, NodeError, PacketVec, RouteCoord, RendezvousDescriptor, HopHandle};
+
+/// Introducer requests are routed to random points this many times farther away than the farthest peer
+const INTRODUCER_DISTANCE_FACTOR: f64 = 4.0;
+
+/// State for reaching private nodes through introducers without either side learning the other's RouteCoord
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Rendezvous {
+	/// Introducers this (private) node can be reached through, None if they haven't been requested yet
+	pub introducers: Option<Vec<(NodeID, RouteCoord)>>,
+	/// Introducers asked to refresh their path back to this node that haven't accepted yet, they are replaced if they still haven't by the next refresh
+	pub unconfirmed: Vec<NodeID>,
+	/// Tick the introducers are next refreshed on
+	pub next_refresh: usize,
+	/// Private nodes that this node introduces clients to and the handles of the path back to each of them
+	pub introducing: HashMap<NodeID, Vec<HopHandle>>,
+	/// Clients that sent introductions through this node and where to traverse replies to
+	pub client_coords: HashMap<NodeID, RouteCoord>,
+	/// Clients that reached this (private) node, mapped to the introducer they came through
+	pub clients: HashMap<NodeID, NodeID>,
+}
+
+impl Node {
+	/// Ask nodes far from this one to become introducers, called once a private node has enough peers.
+	/// Each request is routed towards a random point beyond the farthest peer and taken by the node closest to it,
+	/// so introducers learn neither this node's InternetID nor its RouteCoord, and their RouteCoords in the descriptor don't give away where it is.
+	pub(super) fn request_introducers(&mut self, outgoing: &mut PacketVec) -> Result<(), NodeError> {
+		self.ask_introducers(self.config.introducer_count, outgoing)?;
+		self.rendezvous.introducers = Some(Vec::with_capacity(self.config.introducer_count));
+		self.rendezvous.next_refresh = self.ticks + self.config.introducer_refresh_interval;
+		Ok(())
+	}
+	/// Route `count` IntroducerRequests towards random points far from this node
+	fn ask_introducers(&mut self, count: usize, outgoing: &mut PacketVec) -> Result<(), NodeError> {
+		let self_route_coord = self.route_coord.ok_or(NodeError::NoCalculatedRouteCoord)?;
+		let self_route_coord_f64 = self_route_coord.map(|s|s as f64);
+		let farthest_peer = self.peer_list.right_values().map(|c|nalgebra::distance(&c.map(|s|s as f64), &self_route_coord_f64)).fold(1.0, f64::max);
+		for _ in 0..count {
+			let angle = self.rng.gen_range(0.0..std::f64::consts::TAU);
+			let offset = nalgebra::Vector2::new(angle.cos(), angle.sin()) * farthest_peer * INTRODUCER_DISTANCE_FACTOR;
+			let destination = (self_route_coord_f64 + offset).map(|s|s as i64);
+			self.send_routed(None, destination, NodePacket::IntroducerRequest, outgoing)?;
+		}
+		Ok(())
+	}
+	/// Ask the introducers to store a new path back to this node before the nodes on the old one forget it.
+	/// Introducers that didn't accept the last refresh are dropped and new ones are asked for in their place.
+	pub(super) fn refresh_introducers(&mut self, outgoing: &mut PacketVec) -> Result<(), NodeError> {
+		let introducers = match &mut self.rendezvous.introducers { Some(introducers) if self.ticks >= self.rendezvous.next_refresh => introducers, _ => return Ok(()) };
+		let unconfirmed = std::mem::take(&mut self.rendezvous.unconfirmed);
+		let count = introducers.len();
+		introducers.retain(|(id,_)|!unconfirmed.contains(id));
+		let introducers = introducers.clone();
+		if introducers.len() < count {
+			log::warn!("Dropped {} introducers that didn't answer", count - introducers.len());
+			let descriptor = RendezvousDescriptor::new(self.node_id, introducers.clone(), self.descriptor_key);
+			outgoing.push(InternetPacket::gen_request(self.net_id, InternetRequest::DescriptorDHTWrite(descriptor)));
+		}
+		self.rendezvous.next_refresh = self.ticks + self.config.introducer_refresh_interval;
+		for &(introducer, introducer_coord) in &introducers {
+			self.rendezvous.unconfirmed.push(introducer);
+			self.send_routed(Some(introducer), introducer_coord, NodePacket::IntroducerRequest, outgoing)?;
+		}
+		self.ask_introducers(self.config.introducer_count.saturating_sub(introducers.len()), outgoing)
+	}
+	/// Send data to a private node through one of the introducers in its descriptor, or reply to a client that reached this node
+	pub(super) fn rendezvous_send(&mut self, remote_node_id: NodeID, data: u64, outgoing: &mut PacketVec) -> Result<(), NodeError> {
+		// Reply to a client through the introducer it came from
+		if let Some(&introducer) = self.rendezvous.clients.get(&remote_node_id) {
+			let introducer_coord = self.rendezvous.introducers.iter().flatten().find(|(id,_)|*id == introducer).map(|&(_,c)|c).ok_or(NodeError::NoRemoteRouteCoord { remote: introducer })?;
+			return self.send_routed(Some(introducer), introducer_coord, NodePacket::IntroductionReply(remote_node_id, data), outgoing)
+		}
+		let self_route_coord = self.route_coord.ok_or(NodeError::NoCalculatedRouteCoord)?;
+		let descriptor = self.remote(&remote_node_id)?.descriptor.clone().ok_or(NodeError::NoDescriptor { remote: remote_node_id })?;
+		// Use the introducer closest to this node
+		let self_route_coord_f64 = self_route_coord.map(|s|s as f64);
+		let &(introducer, introducer_coord) = descriptor.introducers.iter().min_by_key(|(_,c)|nalgebra::distance_squared(&c.map(|s|s as f64), &self_route_coord_f64) as i64).ok_or(NodeError::NoDescriptor { remote: remote_node_id })?;
+		let encryption = NodeEncryption::Introduce { introducer, target: remote_node_id, sender: self.node_id, sender_coord: self_route_coord, data };
+		self.send_traverse(introducer_coord, encryption, outgoing)
+	}
+	/// Called on an introducer when an Introduce traversal reaches it
+	pub(super) fn introduce(&mut self, target: NodeID, sender: NodeID, sender_coord: RouteCoord, data: u64, outgoing: &mut PacketVec) -> Result<(), NodeError> {
+		let path = if let Some(path) = self.rendezvous.introducing.get(&target) { path.clone() } else {
+			log::warn!("Received Introduce for NodeID({}) which it doesn't introduce", target);
+			return Ok(())
+		};
+		// The private node stopped refreshing its path, so it no longer uses this node
+		if !path.last().is_some_and(|handle|self.traverse_hops.contains_key(handle)) {
+			log::warn!("Path back to NodeID({}) has expired, no longer introducing it", target);
+			self.rendezvous.introducing.remove(&target);
+			return Ok(())
+		}
+		self.rendezvous.client_coords.insert(sender, sender_coord);
+		self.send_back(path, target, NodePacket::Introduction(sender, data), outgoing)
+	}
+	/// Handle the rendezvous packets, which only arrive routed. `path` leads back to the sender if it traversed here and is empty if it was sent back along a path.
+	pub(super) fn parse_rendezvous_packet(&mut self, return_node_id: NodeID, path: Vec<HopHandle>, packet: NodePacket, outgoing: &mut PacketVec) -> Result<(), NodeError> {
+		match packet {
+			NodePacket::IntroducerRequest => {
+				let self_route_coord = self.route_coord.ok_or(NodeError::NoCalculatedRouteCoord)?;
+				if path.is_empty() { log::warn!("Received IntroducerRequest from NodeID({}) without a path back", return_node_id); return Ok(()) }
+				self.rendezvous.introducing.insert(return_node_id, path.clone());
+				self.send_back(path, return_node_id, NodePacket::IntroducerAccept(self_route_coord), outgoing)?;
+			},
+			NodePacket::IntroducerAccept(introducer_coord) => {
+				self.rendezvous.unconfirmed.retain(|id|*id != return_node_id);
+				let introducers = self.rendezvous.introducers.get_or_insert_with(Vec::new);
+				if introducers.iter().any(|(id,_)|*id == return_node_id) { return Ok(()) }
+				introducers.push((return_node_id, introducer_coord));
+				// Republish descriptor with the new introducer
+				let descriptor = RendezvousDescriptor::new(self.node_id, introducers.clone(), self.descriptor_key);
+				outgoing.push(InternetPacket::gen_request(self.net_id, InternetRequest::DescriptorDHTWrite(descriptor)));
+			},
+			NodePacket::Introduction(client, data) => {
+				if !self.rendezvous.introducers.as_ref().map(|i|i.iter().any(|(id,_)|*id == return_node_id)).unwrap_or(false) {
+					log::warn!("Received Introduction from NodeID({}) which is not an introducer", return_node_id);
+					return Ok(())
+				}
+				log::info!("Received rendezvous data: {} from NodeID({}) through introducer NodeID({})", data, client, return_node_id);
+				self.rendezvous.clients.insert(client, return_node_id);
+			},
+			NodePacket::IntroductionReply(client, data) => {
+				if !self.rendezvous.introducing.contains_key(&return_node_id) { return Ok(()) }
+				let client_coord = *self.rendezvous.client_coords.get(&client).ok_or(NodeError::NoRemoteRouteCoord { remote: client })?;
+				let encryption = NodeEncryption::Traversal { recipient: client, data, sender: return_node_id };
+				self.send_traverse(client_coord, encryption, outgoing)?;
+			},
+			_ => unreachable!("parse_rendezvous_packet only handles rendezvous packets"),
+		}
+		Ok(())
+	}
+}
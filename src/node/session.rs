@@ -1,20 +1,19 @@
 #![allow(dead_code)]
 
-use std::{cmp::Reverse, mem::{Discriminant, discriminant}, collections::HashMap};
+use std::{cmp::Reverse, collections::HashMap};
 
 use ta::{indicators::{SimpleMovingAverage, StandardDeviation}, Next};
 use thiserror::Error;
 use priority_queue::PriorityQueue;
+use rand::Rng;
 
 use crate::internet::{InternetID, InternetPacket};
-use crate::node::{SessionID, NodeID, RouteScalar, RouteCoord, NodePacket, types::{NodeEncryption, NUM_NODE_PACKETS}};
+use crate::node::{SessionID, NodeID, RouteScalar, RouteCoord, NodePacket, types::{NodeEncryption, NodePacketType, NUM_NODE_PACKETS}};
 
 /// Number that uniquely identifies a ping request so that multiple Pings may be sent at the same time
 pub type PingID = u64;
 
-const MAX_PENDING_PINGS: usize = 25;
-
-#[derive(Derivative)]
+#[derive(Derivative, Serialize, Deserialize)]
 #[derivative(Debug)]
 pub struct SessionTracker {
 	#[derivative(Debug="ignore")]
@@ -31,7 +30,7 @@ pub struct SessionTracker {
 impl SessionTracker {
 	fn new() -> Self {
 		Self {
-			ping_queue: PriorityQueue::with_capacity(MAX_PENDING_PINGS),
+			ping_queue: PriorityQueue::new(),
 			dist_avg: 0,
 			dist_dev: 0,
 			ping_avg: SimpleMovingAverage::new(10).unwrap(),
@@ -40,11 +39,11 @@ impl SessionTracker {
 		}
 	}
 	// Generate Ping Packet
-	pub fn gen_ping(&mut self, gen_time: usize) -> PingID {
-		let ping_id: PingID = rand::random();
+	pub fn gen_ping(&mut self, gen_time: usize, max_pending_pings: usize, rng: &mut impl Rng) -> PingID {
+		let ping_id: PingID = rng.gen();
 		self.ping_queue.push(ping_id, Reverse(gen_time));
-		// There shouldn't be more than 25 pings pending
-		if self.ping_queue.len() >= MAX_PENDING_PINGS {
+		// There shouldn't be more than max_pending_pings pings pending
+		if self.ping_queue.len() >= max_pending_pings {
 			self.ping_queue.pop();
 		}
 		ping_id
@@ -64,7 +63,7 @@ impl SessionTracker {
 }
 
 /// Represents directly connected session over public Network
-#[derive(Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct DirectSession {
 	/// Network Address of remote
 	pub net_id: InternetID,
@@ -73,17 +72,17 @@ pub struct DirectSession {
 	pub is_incoming_peer: bool,
 }
 impl DirectSession {
-	fn new(net_id: InternetID) -> SessionType {
-		SessionType::Direct(DirectSession {
+	fn new(net_id: InternetID) -> Self {
+		DirectSession {
 			net_id,
 			is_peered: false,
 			is_incoming_peer: false,
-		})
+		}
 	}
 }
 
 /// Represents onion-routed session through different Dither nodes
-#[derive(Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct RoutedSession {
 	pub hops: usize, // Desired number of hops in the routed session
 	/// Resolved nodes with their own RoutedSession which messages can be passed through
@@ -93,7 +92,7 @@ pub struct RoutedSession {
 	pub outgoing_net_id: InternetID,
 }
 
-#[derive(Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub enum SessionType {
 	Direct(DirectSession),
 	Routed(RoutedSession),
@@ -108,7 +107,7 @@ pub enum SessionError {
 }
 
 /// Represents a Remote Connection, Direct or Routed
-#[derive(Derivative)]
+#[derive(Derivative, Serialize, Deserialize)]
 #[derivative(Debug)]
 pub struct RemoteSession {
 	/// All connections must have a SessionID for symmetric encryption
@@ -120,7 +119,7 @@ pub struct RemoteSession {
 	pub tracker: SessionTracker,
 	/// Keep track of times certain packets were last received from remote node
 	#[derivative(Debug="ignore")]
-	pub last_packet_times: HashMap<(Discriminant<NodePacket>, NodeID), usize>, // Maps Packets to time last sent
+	pub last_packet_times: HashMap<(NodePacketType, NodeID), usize>, // Maps Packets to time last sent
 }
 impl RemoteSession {
 	pub fn new(session_id: SessionID, session_type: SessionType) -> Self {
@@ -131,7 +130,7 @@ impl RemoteSession {
 			last_packet_times: HashMap::with_capacity(NUM_NODE_PACKETS),
 		}
 	}
-	pub fn from_address(session_id: SessionID, return_net_id: InternetID) -> Self { Self::new(session_id, DirectSession::new(return_net_id)) }
+	pub fn from_address(session_id: SessionID, return_net_id: InternetID) -> Self { Self::new(session_id, SessionType::Direct(DirectSession::new(return_net_id))) }
 	pub fn direct(&self) -> Result<&DirectSession, SessionError> {
 		if let SessionType::Direct(direct) = &self.session_type { Ok(direct) } else { Err(SessionError::NotDirectType) }
 	}
@@ -153,12 +152,12 @@ impl RemoteSession {
 
 	/// Returns how long ago (in ticks) a packet was last sent or None if packet has never been sent
 	pub fn check_packet_time(&mut self, packet: &NodePacket, sending_node_id: NodeID, current_time: usize) -> Option<usize> {
-		if let Some(last_time) = self.last_packet_times.get_mut(&(discriminant(packet), sending_node_id)) {
+		if let Some(last_time) = self.last_packet_times.get_mut(&(packet.packet_type(), sending_node_id)) {
 			let difference = current_time - *last_time;
 			*last_time = current_time;
 			Some(difference)
 		} else { 
-			self.last_packet_times.insert((discriminant(packet), sending_node_id), current_time); None
+			self.last_packet_times.insert((packet.packet_type(), sending_node_id), current_time); None
 		}
 	}
 	/// Generate InternetPacket from NodePacket doing whatever needs to be done to route it through the network securely
@@ -171,13 +170,13 @@ impl RemoteSession {
 			SessionType::Routed(routed_session) => {
 				let mut encrypted = NodeEncryption::Session { session_id: self.session_id, packet };
 				for (session_id, route_coord) in &routed_session.proxy_nodes {
-					encrypted = encrypted.wrap_traverse(*session_id, route_coord.clone());
+					encrypted = encrypted.wrap_traverse(*session_id, *route_coord);
 				}
 				Ok(encrypted.package(routed_session.outgoing_net_id))
 			},
 		}
 	}
 	pub fn dist(&self) -> RouteScalar {
-		return self.tracker.dist_avg;
+		self.tracker.dist_avg
 	}
 }
\ No newline at end of file
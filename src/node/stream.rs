@@ -0,0 +1,380 @@
+use std::collections::{HashMap, BTreeMap};
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::time::Duration;
+
+use crate::node::{Node, NodeID, NodePacket, NodeError, NodeAction, NodeActionCondition, PacketVec, RemoteNode};
+
+/// Number uniquely identifying a TCP stream tunneled between two nodes
+pub type StreamID = u32;
+
+/// How long an exit node will wait for a real TCP connection to open
+const STREAM_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Maximum number of bytes read from an exit connection per tick, also the most data a single StreamData carries
+const STREAM_READ_CHUNK: usize = 16 * 1024;
+/// Maximum number of bytes sent through a stream that haven't been acknowledged yet
+const STREAM_WINDOW: u64 = 64 * 1024;
+/// Bytes an end keeps buffered before it stops taking more, received data beyond this isn't acknowledged and is sent again later
+pub const STREAM_BUFFER: usize = 64 * 1024;
+/// Least number of ticks before unacknowledged stream data is sent again, otherwise four times the session's distance
+const STREAM_MIN_RETRANSMIT: usize = 100;
+/// Ticks a stream waits for a handshake with its exit node that is already under way
+const STREAM_SESSION_TIMEOUT: usize = 500;
+
+/// End of a stream a packet is addressed to, a node can be the client of one stream and the exit of another with the same StreamID
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamEnd {
+	/// Node that asked for the stream
+	Client,
+	/// Node that opened the real connection
+	Exit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamStatus {
+	/// StreamOpen was sent, waiting on the exit node
+	Pending,
+	/// Exit node has an open connection, data may flow
+	Open,
+	/// Exit node couldn't open the connection
+	Refused,
+	/// There is no session with the exit node, streams aren't routed through other nodes
+	Unreachable,
+	/// Either side closed the stream
+	Closed,
+}
+
+/// Real TCP connection of an exit node
+#[derive(Debug)]
+pub enum ExitConnection {
+	/// Address is being resolved and connected to on a background thread
+	Connecting(Receiver<io::Result<TcpStream>>),
+	Connected(TcpStream),
+}
+
+/// One end of a TCP stream that is tunneled over a RemoteSession
+#[derive(Debug)]
+pub struct NodeStream {
+	/// Node on the other end of the tunnel
+	pub remote: NodeID,
+	pub status: StreamStatus,
+	/// Data received through the tunnel that hasn't been taken by the frontend yet (client side only)
+	pub inbound: Vec<u8>,
+	/// Data received through the tunnel that the exit connection hasn't accepted yet (exit side only)
+	pub outbound: Vec<u8>,
+	/// Data waiting to be sent until the other end acknowledges enough of what was sent before
+	pub unsent: Vec<u8>,
+	/// Data that was sent but not acknowledged, by offset, with the tick it was last sent on
+	in_flight: BTreeMap<u64, (Vec<u8>, usize)>,
+	/// Number of bytes this end has sent, the offset of the next StreamData
+	pub sent: u64,
+	/// Number of bytes the other end has acknowledged
+	acked: u64,
+	/// Number of bytes received from the other end in order
+	pub received: u64,
+	/// Data that arrived ahead of earlier data or while the buffer was full, by offset. Packets can be lost or reordered on the way, so data is only delivered in order
+	early: BTreeMap<u64, Vec<u8>>,
+	/// Something arrived since the last StreamAck was sent
+	ack_pending: bool,
+	/// This end won't send more data, StreamFinish is sent once everything before it was sent
+	pub local_finished: bool,
+	/// Tick StreamFinish was last sent on
+	finish_sent_at: Option<usize>,
+	/// The other end acknowledged everything this end sent including StreamFinish
+	finish_acked: bool,
+	/// Number of bytes the other end sent before it sent StreamFinish
+	remote_finish_at: Option<u64>,
+	/// Real TCP connection opened by the exit node (exit side only)
+	pub exit: Option<ExitConnection>,
+	/// Write half of the exit connection was shut down after the other end finished (exit side only)
+	exit_write_shut: bool,
+}
+impl NodeStream {
+	fn new(remote: NodeID, status: StreamStatus, exit: Option<ExitConnection>) -> Self {
+		Self {
+			remote, status, inbound: Vec::new(), outbound: Vec::new(), unsent: Vec::new(), in_flight: BTreeMap::new(), sent: 0, acked: 0, received: 0, early: BTreeMap::new(),
+			ack_pending: false, local_finished: false, finish_sent_at: None, finish_acked: false, remote_finish_at: None, exit, exit_write_shut: false,
+		}
+	}
+	/// The other end sent StreamFinish and everything it sent before has been received
+	pub fn remote_finished(&self) -> bool { self.remote_finish_at == Some(self.received) }
+	/// The stream was refused or closed, or both ends have finished sending and had everything they sent acknowledged
+	pub fn is_finished(&self) -> bool { matches!(self.status, StreamStatus::Refused | StreamStatus::Unreachable | StreamStatus::Closed) || (self.finish_acked && self.remote_finished()) }
+	/// Data is waiting to be sent, acknowledged or delivered, so the stream has to be polled
+	pub fn is_busy(&self) -> bool { !self.unsent.is_empty() || !self.in_flight.is_empty() || !self.early.is_empty() || self.ack_pending || (self.local_finished && !self.finish_acked) }
+	/// Take data sent by the other end from `offset`, it is delivered by `deliver` once everything before it arrived
+	fn receive(&mut self, offset: u64, data: Vec<u8>) {
+		// The other end never sends beyond the window, anything else would only fill up memory
+		if offset >= self.received && offset < self.received + STREAM_WINDOW { self.early.insert(offset, data); }
+		self.ack_pending = true;
+	}
+	/// Move data that arrived in order to `buffer` while it has room
+	fn deliver(early: &mut BTreeMap<u64, Vec<u8>>, received: &mut u64, buffer: &mut Vec<u8>) -> bool {
+		let mut delivered = false;
+		while buffer.len() < STREAM_BUFFER {
+			let data = if let Some(data) = early.remove(received) { data } else { break };
+			*received += data.len() as u64;
+			buffer.extend_from_slice(&data);
+			delivered = true;
+		}
+		delivered
+	}
+	/// Deliver data that arrived in order to the buffer of this end
+	fn deliver_received(&mut self) {
+		let buffer = if self.exit.is_some() { &mut self.outbound } else { &mut self.inbound };
+		if Self::deliver(&mut self.early, &mut self.received, buffer) { self.ack_pending = true; }
+	}
+	/// The other end received everything up to `received`, and StreamFinish if `finished`
+	fn acknowledge(&mut self, received: u64, finished: bool) {
+		if received > self.acked && received <= self.sent {
+			self.acked = received;
+			self.in_flight.retain(|offset, (data, _)|offset + data.len() as u64 > received);
+		}
+		if finished && self.finish_sent_at.is_some() && self.acked == self.sent { self.finish_acked = true; }
+	}
+	/// Send new data while the window allows, resend data that wasn't acknowledged within `retransmit` ticks and acknowledge what arrived.
+	/// `end` is the other end of the stream, that the packets are addressed to.
+	fn flush(&mut self, end: StreamEnd, stream_id: StreamID, now: usize, retransmit: usize, packets: &mut Vec<NodePacket>) {
+		if self.ack_pending {
+			packets.push(NodePacket::StreamAck(end, stream_id, self.received, self.remote_finished()));
+			self.ack_pending = false;
+		}
+		for (&offset, (data, sent_at)) in self.in_flight.iter_mut().filter(|(_, (_, sent_at))|now >= *sent_at + retransmit) {
+			packets.push(NodePacket::StreamData(end, stream_id, offset, data.clone()));
+			*sent_at = now;
+		}
+		while !self.unsent.is_empty() && self.sent - self.acked < STREAM_WINDOW {
+			let len = self.unsent.len().min(STREAM_READ_CHUNK).min((STREAM_WINDOW - (self.sent - self.acked)) as usize);
+			let data: Vec<u8> = self.unsent.drain(..len).collect();
+			packets.push(NodePacket::StreamData(end, stream_id, self.sent, data.clone()));
+			self.in_flight.insert(self.sent, (data, now));
+			self.sent += len as u64;
+		}
+		let finish_due = self.finish_sent_at.is_none_or(|sent_at|now >= sent_at + retransmit);
+		if self.local_finished && self.unsent.is_empty() && !self.finish_acked && finish_due {
+			packets.push(NodePacket::StreamFinish(end, stream_id, self.sent));
+			self.finish_sent_at = Some(now);
+		}
+	}
+	/// Advance the exit connection: finish connecting, write buffered data and read what came back.
+	/// Packets for the other end are pushed to `packets`, returns true once the stream should be forgotten.
+	fn poll_exit(&mut self, stream_id: StreamID, now: usize, retransmit: usize, packets: &mut Vec<NodePacket>) -> bool {
+		if let Some(ExitConnection::Connecting(receiver)) = &self.exit {
+			let connected = match receiver.try_recv() {
+				Ok(result) => result,
+				Err(TryRecvError::Empty) => return false,
+				Err(TryRecvError::Disconnected) => Err(io::Error::other("connecting thread exited")),
+			};
+			match connected {
+				Ok(connection) => {
+					self.exit = Some(ExitConnection::Connected(connection));
+					self.status = StreamStatus::Open;
+					packets.push(NodePacket::StreamOpenResponse(stream_id, true));
+				},
+				Err(err) => {
+					log::warn!("Failed to open exit connection of stream {}: {}", stream_id, err);
+					packets.push(NodePacket::StreamOpenResponse(stream_id, false));
+					return true;
+				},
+			}
+		}
+		self.deliver_received();
+		let remote_finished = self.remote_finished();
+		let connection = if let Some(ExitConnection::Connected(connection)) = &mut self.exit { connection } else { return true };
+		// Write as much as the connection accepts, keeping the rest for the next tick
+		while !self.outbound.is_empty() {
+			match connection.write(&self.outbound) {
+				Ok(len) => { self.outbound.drain(..len); },
+				Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+				Err(err) => {
+					log::warn!("Failed to write to exit connection of stream {}: {}", stream_id, err);
+					packets.push(NodePacket::StreamClose(StreamEnd::Client, stream_id));
+					return true;
+				},
+			}
+		}
+		// Pass the other end's half-close on once everything it sent is written
+		if remote_finished && self.outbound.is_empty() && !self.exit_write_shut {
+			let _ = connection.shutdown(std::net::Shutdown::Write);
+			self.exit_write_shut = true;
+		}
+		// Stop reading while the client falls behind, so the connection's own flow control slows the server down
+		if !self.local_finished && self.unsent.len() < STREAM_BUFFER {
+			let mut buf = vec![0u8; STREAM_READ_CHUNK];
+			match connection.read(&mut buf) {
+				Ok(0) => self.local_finished = true,
+				Ok(len) => self.unsent.extend_from_slice(&buf[..len]),
+				Err(err) if err.kind() == io::ErrorKind::WouldBlock => {},
+				Err(_) => { packets.push(NodePacket::StreamClose(StreamEnd::Client, stream_id)); return true },
+			}
+		}
+		self.flush(StreamEnd::Client, stream_id, now, retransmit, packets);
+		self.is_finished() && self.exit_write_shut
+	}
+}
+
+impl Node {
+	/// Ask `exit_node_id` to open a connection to `addr` over the session with it.
+	/// Waits for a handshake that is under way, otherwise the stream is Unreachable straight away.
+	pub(super) fn open_stream(&mut self, stream_id: StreamID, exit_node_id: NodeID, addr: String, outgoing: &mut PacketVec, out_actions: &mut super::ActionVec) -> Result<(), NodeError> {
+		// Run again once the handshake finished or timed out
+		let waited = self.streams.contains_key(&stream_id);
+		let remote = self.remotes.get(&exit_node_id);
+		if let Some(remote) = remote.filter(|r|r.session_active()) {
+			remote.add_packet(NodePacket::StreamOpen(stream_id, addr.clone()), outgoing)?;
+			self.streams.insert(stream_id, NodeStream::new(exit_node_id, StreamStatus::Pending, None));
+		} else if remote.is_some_and(|r|r.pending_session.is_some()) && !waited {
+			self.streams.insert(stream_id, NodeStream::new(exit_node_id, StreamStatus::Pending, None));
+			let action = NodeAction::OpenStream(stream_id, exit_node_id, addr);
+			out_actions.push(action.clone().gen_condition(NodeActionCondition::timeout(STREAM_SESSION_TIMEOUT, NodeActionCondition::Session(exit_node_id), action)));
+		} else {
+			log::warn!("Can't open stream {} to {}, there is no session with exit NodeID({})", stream_id, addr, exit_node_id);
+			self.streams.insert(stream_id, NodeStream::new(exit_node_id, StreamStatus::Unreachable, None));
+		}
+		Ok(())
+	}
+	/// Queue data to be sent through an open stream by `poll_streams`.
+	/// Frontends should keep `unsent` below `STREAM_BUFFER` so a slow exit slows them down instead of filling up memory.
+	pub(super) fn stream_send(&mut self, stream_id: StreamID, data: Vec<u8>) -> Result<(), NodeError> {
+		let stream = self.streams.get_mut(&stream_id).ok_or(NodeError::UnknownStream { stream_id })?;
+		if stream.status != StreamStatus::Open || stream.local_finished { return Err(NodeError::StreamNotOpen { stream_id }) }
+		stream.unsent.extend_from_slice(&data);
+		Ok(())
+	}
+	/// Stop sending through a stream, the other end is told once the queued data is sent. The stream stays open until the other end finishes too
+	pub(super) fn finish_stream(&mut self, stream_id: StreamID) -> Result<(), NodeError> {
+		let stream = self.streams.get_mut(&stream_id).ok_or(NodeError::UnknownStream { stream_id })?;
+		if stream.status != StreamStatus::Open { return Err(NodeError::StreamNotOpen { stream_id }) }
+		stream.local_finished = true;
+		Ok(())
+	}
+	/// Close a stream from this end and notify the other end
+	pub(super) fn close_stream(&mut self, stream_id: StreamID, outgoing: &mut PacketVec) -> Result<(), NodeError> {
+		let stream = self.streams.remove(&stream_id).ok_or(NodeError::UnknownStream { stream_id })?;
+		if !stream.is_finished() {
+			self.remote(&stream.remote)?.add_packet(NodePacket::StreamClose(StreamEnd::Exit, stream_id), outgoing)?;
+		}
+		Ok(())
+	}
+	/// Handle the stream tunneling packets
+	pub(super) fn parse_stream_packet(&mut self, return_node_id: NodeID, packet: NodePacket, outgoing: &mut PacketVec) -> Result<(), NodeError> {
+		// Data and finishes are sent again until acknowledged, so tell the other end to stop if this end already forgot the stream
+		let unknown_reply = match &packet {
+			NodePacket::StreamData(StreamEnd::Exit, stream_id, ..) | NodePacket::StreamFinish(StreamEnd::Exit, stream_id, _) => Some(NodePacket::StreamClose(StreamEnd::Client, *stream_id)),
+			NodePacket::StreamData(StreamEnd::Client, stream_id, ..) | NodePacket::StreamFinish(StreamEnd::Client, stream_id, _) => Some(NodePacket::StreamClose(StreamEnd::Exit, *stream_id)),
+			_ => None,
+		};
+		let result = self.parse_known_stream_packet(return_node_id, packet);
+		if let (Err(NodeError::UnknownStream { .. }), Some(reply)) = (&result, unknown_reply) {
+			self.remote(&return_node_id)?.add_packet(reply, outgoing)?;
+		}
+		result
+	}
+	fn parse_known_stream_packet(&mut self, return_node_id: NodeID, packet: NodePacket) -> Result<(), NodeError> {
+		match packet {
+			// Exit side: start opening the real connection, `poll_streams` tells the requester how it went
+			NodePacket::StreamOpen(stream_id, addr) => {
+				let connecting = ExitConnection::Connecting(Self::connect_exit(addr));
+				if self.exit_streams.insert((return_node_id, stream_id), NodeStream::new(return_node_id, StreamStatus::Pending, Some(connecting))).is_some() {
+					log::warn!("NodeID({}) reopened stream {}, dropping the old connection", return_node_id, stream_id);
+				}
+			},
+			NodePacket::StreamOpenResponse(stream_id, accepted) => {
+				let stream = self.stream_from(return_node_id, stream_id)?;
+				stream.status = if accepted { StreamStatus::Open } else { StreamStatus::Refused };
+			},
+			// Data is delivered and acknowledged by `poll_streams`
+			NodePacket::StreamData(end, stream_id, offset, data) => {
+				self.end_stream_from(end, return_node_id, stream_id)?.receive(offset, data);
+			},
+			// Exit side shuts down the connection's write half once the data before the finish is written
+			NodePacket::StreamFinish(end, stream_id, sent) => {
+				let stream = self.end_stream_from(end, return_node_id, stream_id)?;
+				stream.remote_finish_at = Some(sent);
+				stream.ack_pending = true;
+			},
+			NodePacket::StreamAck(end, stream_id, received, finished) => {
+				self.end_stream_from(end, return_node_id, stream_id)?.acknowledge(received, finished);
+			},
+			// Exit side has no frontend to drain the stream, so forget about it immediately
+			NodePacket::StreamClose(StreamEnd::Exit, stream_id) => {
+				let stream = self.exit_streams.remove(&(return_node_id, stream_id)).ok_or(NodeError::UnknownStream { stream_id })?;
+				if let Some(ExitConnection::Connected(connection)) = stream.exit { let _ = connection.shutdown(std::net::Shutdown::Both); }
+			},
+			NodePacket::StreamClose(StreamEnd::Client, stream_id) => {
+				self.stream_from(return_node_id, stream_id)?.status = StreamStatus::Closed;
+			},
+			_ => unreachable!("parse_stream_packet only handles stream packets"),
+		}
+		Ok(())
+	}
+	/// Advance exit connections, then send, resend and acknowledge data through every stream
+	pub(super) fn poll_streams(&mut self, outgoing: &mut PacketVec) {
+		let now = self.ticks;
+		let mut closed = Vec::new();
+		for (&(remote_node_id, stream_id), stream) in self.exit_streams.iter_mut() {
+			let mut packets = Vec::new();
+			if stream.poll_exit(stream_id, now, Self::stream_retransmit(&self.remotes, remote_node_id), &mut packets) { closed.push((remote_node_id, stream_id)); }
+			Self::send_stream_packets(&self.remotes, remote_node_id, stream_id, packets, outgoing);
+		}
+		for key in closed { self.exit_streams.remove(&key); }
+		let mut stream_ids: Vec<StreamID> = self.streams.keys().copied().collect();
+		stream_ids.sort_unstable();
+		for stream_id in stream_ids {
+			let stream = if let Some(stream) = self.streams.get_mut(&stream_id) { stream } else { continue };
+			if stream.status != StreamStatus::Open { continue }
+			let mut packets = Vec::new();
+			stream.deliver_received();
+			stream.flush(StreamEnd::Exit, stream_id, now, Self::stream_retransmit(&self.remotes, stream.remote), &mut packets);
+			Self::send_stream_packets(&self.remotes, stream.remote, stream_id, packets, outgoing);
+		}
+	}
+	/// Ticks to wait for an acknowledgement before sending stream data to `remote_node_id` again
+	fn stream_retransmit(remotes: &HashMap<NodeID, RemoteNode>, remote_node_id: NodeID) -> usize {
+		let dist = remotes.get(&remote_node_id).and_then(|r|r.session().ok()).map(|s|s.dist() as usize).unwrap_or(0);
+		(dist * 4).max(STREAM_MIN_RETRANSMIT)
+	}
+	fn send_stream_packets(remotes: &HashMap<NodeID, RemoteNode>, remote_node_id: NodeID, stream_id: StreamID, packets: Vec<NodePacket>, outgoing: &mut PacketVec) {
+		let remote = if let Some(remote) = remotes.get(&remote_node_id) { remote } else { return };
+		for packet in packets {
+			if let Err(err) = remote.add_packet(packet, outgoing) {
+				log::error!("Failed to forward data for stream {}: {:?}", stream_id, err);
+			}
+		}
+	}
+	fn end_stream_from(&mut self, end: StreamEnd, return_node_id: NodeID, stream_id: StreamID) -> Result<&mut NodeStream, NodeError> {
+		if end == StreamEnd::Exit { self.exit_stream_from(return_node_id, stream_id) } else { self.stream_from(return_node_id, stream_id) }
+	}
+	fn exit_stream_from(&mut self, return_node_id: NodeID, stream_id: StreamID) -> Result<&mut NodeStream, NodeError> {
+		self.exit_streams.get_mut(&(return_node_id, stream_id)).ok_or(NodeError::UnknownStream { stream_id })
+	}
+	fn stream_from(&mut self, return_node_id: NodeID, stream_id: StreamID) -> Result<&mut NodeStream, NodeError> {
+		match self.streams.get_mut(&stream_id) {
+			Some(stream) if stream.remote == return_node_id => Ok(stream),
+			_ => Err(NodeError::UnknownStream { stream_id }),
+		}
+	}
+	/// Resolve and connect to `addr` on a background thread so neither blocks the tick, the result is picked up by `poll_streams`
+	fn connect_exit(addr: String) -> Receiver<io::Result<TcpStream>> {
+		let (sender, receiver) = mpsc::channel();
+		std::thread::spawn(move || {
+			let connect = || {
+				let mut last_err = io::Error::new(io::ErrorKind::InvalidInput, "address resolved to nothing");
+				for socket_addr in addr.to_socket_addrs()? {
+					match TcpStream::connect_timeout(&socket_addr, STREAM_CONNECT_TIMEOUT) {
+						Ok(stream) => {
+							stream.set_nonblocking(true)?;
+							stream.set_nodelay(true)?;
+							return Ok(stream);
+						},
+						Err(err) => last_err = err,
+					}
+				}
+				Err(last_err)
+			};
+			let _ = sender.send(connect());
+		});
+		receiver
+	}
+}
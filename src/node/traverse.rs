@@ -0,0 +1,233 @@
+use rand::Rng;
+
+use crate::node::{Node, NodeID, NodeEvent, NodePacket, NodeEncryption, NodeError, PacketVec, RouteCoord, TraverseHeader, TraverseFailure, TraverseID, TraverseNonce, TraverseReceipt, HopHandle};
+
+/// A Traverse packet sent with `NodeAction::TraverseReliable` that hasn't been acknowledged yet
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PendingReceipt {
+	pub recipient: NodeID,
+	pub data: u64,
+	/// Tick the latest attempt was sent on
+	pub sent_at: usize,
+	/// Number of times the packet has been sent
+	pub attempts: usize,
+}
+
+/// What a node remembers about a Traverse packet it passed on, found by the handle it added to the packet's path
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HopState {
+	/// Node the packet came from, None on the node that sent it
+	pub previous: Option<NodeID>,
+	pub nonce: TraverseNonce,
+	/// Peers the packet was sent to or came from, these are skipped when picking the next hop
+	pub tried: Vec<NodeID>,
+	/// Tick the handle was last used on, it is forgotten `traverse_state_timeout` ticks later
+	pub used_at: usize,
+}
+
+impl Node {
+	/// Start routing a Traverse packet towards `route_coord`
+	pub(super) fn send_traverse(&mut self, route_coord: RouteCoord, encryption: NodeEncryption, outgoing: &mut PacketVec) -> Result<(), NodeError> {
+		let header = self.start_traverse(TraverseHeader::new(route_coord, self.config.traverse_hop_limit));
+		self.forward_traverse(header, Box::new(encryption), outgoing)
+	}
+	/// Give a Traverse packet sent by this node a new nonce and start its path here
+	fn start_traverse(&mut self, header: TraverseHeader) -> TraverseHeader {
+		let (nonce, handle) = (self.rng.gen(), self.rng.gen());
+		self.traverse_hops.insert(handle, HopState { previous: None, nonce, tried: Vec::new(), used_at: self.ticks });
+		header.sent_from(nonce, handle)
+	}
+	/// Traverse `packet` to `recipient` at `route_coord` (or the node closest to it if None) instead of sending it over a session, the recipient can reply along the path it took
+	pub(super) fn send_routed(&mut self, recipient: Option<NodeID>, route_coord: RouteCoord, packet: NodePacket, outgoing: &mut PacketVec) -> Result<(), NodeError> {
+		self.send_traverse(route_coord, NodeEncryption::Routed { recipient, sender: self.node_id, packet }, outgoing)
+	}
+	/// Reply to `recipient` along `path`, the path back from a routed packet it sent
+	pub(super) fn send_back(&mut self, path: Vec<HopHandle>, recipient: NodeID, packet: NodePacket, outgoing: &mut PacketVec) -> Result<(), NodeError> {
+		self.return_routed(path, Box::new(NodeEncryption::Routed { recipient: Some(recipient), sender: self.node_id, packet }), outgoing)
+	}
+	/// Send (or resend) a pending Traverse packet that asks for a receipt, gives up once it has been sent `max_traverse_attempts` times
+	pub(super) fn send_traverse_reliable(&mut self, traverse_id: TraverseID, outgoing: &mut PacketVec) -> Result<(), NodeError> {
+		let ticks = self.ticks;
+		let pending = if let Some(pending) = self.traverse_receipts.get_mut(&traverse_id) { pending } else { return Ok(()) };
+		if pending.attempts >= self.config.max_traverse_attempts {
+			let (recipient, data, attempts) = (pending.recipient, pending.data, pending.attempts);
+			self.traverse_receipts.remove(&traverse_id);
+			log::warn!("Gave up on Traverse packet with data: {} to NodeID({}) after {} attempts", data, recipient, attempts);
+			self.push_event(NodeEvent::TraverseUndeliverable(recipient, data, TraverseFailure::NoReceipt));
+			return Ok(())
+		}
+		pending.attempts += 1;
+		pending.sent_at = ticks;
+		let (recipient, data) = (pending.recipient, pending.data);
+		let route_coord = self.remote(&recipient)?.route_coord.ok_or(NodeError::NoRemoteRouteCoord { remote: recipient })?;
+		let encryption = NodeEncryption::Traversal { recipient, data, sender: self.node_id };
+		let header = self.start_traverse(TraverseHeader::new(route_coord, self.config.traverse_hop_limit).with_receipt(traverse_id));
+		self.forward_traverse(header, Box::new(encryption), outgoing)
+	}
+	/// Resend Traverse packets that haven't been acknowledged in time
+	pub(super) fn check_traverse_receipts(&mut self, outgoing: &mut PacketVec) {
+		let expired: Vec<TraverseID> = self.traverse_receipts.iter().filter(|(_,p)|self.ticks >= p.sent_at + self.config.traverse_receipt_timeout).map(|(&id,_)|id).collect();
+		for traverse_id in expired {
+			if let Err(err) = self.send_traverse_reliable(traverse_id, outgoing) {
+				log::error!("Failed to resend Traverse packet: {:?}", err);
+			}
+		}
+	}
+	/// Forget the handles of Traverse packets that haven't been used in `traverse_state_timeout` ticks, replies can no longer be passed back along their paths
+	pub(super) fn expire_traverse_hops(&mut self) {
+		let (ticks, timeout) = (self.ticks, self.config.traverse_state_timeout);
+		self.traverse_hops.retain(|_,state|ticks < state.used_at + timeout);
+	}
+	/// Handle Traverse packets, undeliverable notices and receipts
+	pub(super) fn parse_traverse_packet(&mut self, return_node_id: NodeID, packet: NodePacket, outgoing: &mut PacketVec) -> Result<(), NodeError> {
+		match packet {
+			NodePacket::Traverse(mut header, encryption) => {
+				header.hops += 1;
+				let own_handle = header.path.last().copied().filter(|h|self.traverse_hops.get(h).is_some_and(|s|s.nonce == header.nonce));
+				let state = if let Some(handle) = own_handle {
+					// Sent back out of a dead end, or by a peer that had already seen the packet
+					let state = self.traverse_hops.get_mut(&handle).expect("handle was just found");
+					if !state.tried.contains(&return_node_id) { state.tried.push(return_node_id); }
+					state
+				} else if self.traverse_hops.values().any(|s|s.nonce == header.nonce) {
+					// Reached this node a second time, send it back so the previous node tries another peer
+					log::debug!("Traverse packet to RouteCoord({}) came back, returning it to NodeID({})", header.destination, return_node_id);
+					self.remote(&return_node_id)?.add_packet(NodePacket::Traverse(header, encryption), outgoing)?;
+					return Ok(())
+				} else {
+					let handle = self.rng.gen();
+					header.path.push(handle);
+					self.traverse_hops.entry(handle).or_insert(HopState { previous: Some(return_node_id), nonce: header.nonce, tried: vec![return_node_id], used_at: self.ticks })
+				};
+				state.used_at = self.ticks;
+				let tried = state.tried.clone();
+				match *encryption {
+					NodeEncryption::Traversal { recipient, data, sender } if recipient == self.node_id => {
+						// If packet meant for me, log it
+						log::info!("Received Traverse packet with data: {} from NodeID({}) after {} hops", data, sender, header.hops);
+						self.push_event(NodeEvent::TraverseReceived(sender, data, header.hops));
+						if let Some(traverse_id) = header.receipt {
+							let receipt = TraverseReceipt { traverse_id, hops: header.hops, route: Vec::new() };
+							self.return_receipt(header.path, receipt, outgoing)?;
+						}
+					},
+					// If I'm the introducer, relay to the private node
+					NodeEncryption::Introduce { introducer, target, sender, sender_coord, data } if introducer == self.node_id => {
+						self.introduce(target, sender, sender_coord, data, outgoing)?;
+					},
+					NodeEncryption::Routed { recipient, sender, packet } if recipient.map_or_else(||self.takes_routed(sender, header.destination, &tried), |r|r == self.node_id) => {
+						self.parse_routed_packet(sender, header.path, packet, outgoing)?;
+					},
+					NodeEncryption::Traversal { .. } | NodeEncryption::Introduce { .. } | NodeEncryption::Routed { .. } => self.forward_traverse(header, encryption, outgoing)?,
+					_ => { unimplemented!("Traverse doesn't support this NodeEncryption variant") }
+				}
+			},
+			NodePacket::TraverseUndeliverable(path, failure, receipt, encryption) => self.return_undeliverable(path, failure, receipt, encryption, outgoing)?,
+			NodePacket::TraverseDelivered(path, receipt) => self.return_receipt(path, receipt, outgoing)?,
+			NodePacket::TraverseReturn(path, encryption) => self.return_routed(path, encryption, outgoing)?,
+			_ => unreachable!("parse_traverse_packet only handles traverse packets"),
+		}
+		Ok(())
+	}
+	/// Handle a packet that was routed to this node, `path` leads back to the sender and is empty if the packet was itself returned along a path
+	fn parse_routed_packet(&mut self, sender: NodeID, path: Vec<HopHandle>, packet: NodePacket, outgoing: &mut PacketVec) -> Result<(), NodeError> {
+		match packet {
+			NodePacket::IntroducerRequest | NodePacket::IntroducerAccept(..) | NodePacket::Introduction(..) | NodePacket::IntroductionReply(..) => {
+				self.parse_rendezvous_packet(sender, path, packet, outgoing)
+			},
+			_ => { log::warn!("Dropping routed {:?} from NodeID({}), only rendezvous packets are routed", packet.packet_type(), sender); Ok(()) },
+		}
+	}
+	/// Whether this node takes a routed packet without a recipient: none of the peers it hasn't tried are closer to its destination.
+	/// Nodes with a session to the sender know its InternetID, so they pass it on instead, as do nodes that already introduce the sender.
+	fn takes_routed(&self, sender: NodeID, destination: RouteCoord, tried: &[NodeID]) -> bool {
+		let self_route_coord = if let Some(self_route_coord) = self.route_coord { self_route_coord } else { return false };
+		if sender == self.node_id || self.rendezvous.introducing.contains_key(&sender) { return false }
+		if self.remotes.get(&sender).is_some_and(|r|r.session.is_some() || r.pending_session.is_some()) { return false }
+		let distance = |coord: &RouteCoord|nalgebra::distance_squared(&coord.map(|s|s as f64), &destination.map(|s|s as f64));
+		self.peer_list.iter().filter(|(id,_)|!tried.contains(id)).all(|(_,peer_coord)|distance(peer_coord) >= distance(&self_route_coord))
+	}
+	/// Send a Traverse packet to the closest peer it hasn't tried, backtracking along its path if there is none.
+	/// The last handle in the packet's path must be this node's.
+	fn forward_traverse(&mut self, mut header: TraverseHeader, encryption: Box<NodeEncryption>, outgoing: &mut PacketVec) -> Result<(), NodeError> {
+		if header.hops >= header.hop_limit {
+			log::warn!("Dropping Traverse packet to RouteCoord({}) after reaching hop limit of {}", header.destination, header.hop_limit);
+			return self.return_undeliverable(header.path, TraverseFailure::HopLimit, header.receipt, encryption, outgoing)
+		}
+		let handle = *header.path.last().ok_or(NodeError::UnknownHopHandle)?;
+		let state = self.traverse_hops.get(&handle).ok_or(NodeError::UnknownHopHandle)?;
+		if let Some(next_node_id) = self.closest_peer(header.destination, &state.tried) {
+			self.traverse_hops.get_mut(&handle).expect("handle was just found").tried.push(next_node_id);
+			self.remote(&next_node_id)?.add_packet(NodePacket::Traverse(header, encryption), outgoing)?; // Forward packet to nearest to destination
+		} else if let Some(previous_node_id) = state.previous {
+			// Dead end, go back to the previous node so that it can try its other peers
+			log::debug!("Traverse packet to RouteCoord({}) hit a dead end, backtracking to NodeID({})", header.destination, previous_node_id);
+			header.path.pop();
+			self.remote(&previous_node_id)?.add_packet(NodePacket::Traverse(header, encryption), outgoing)?;
+		} else {
+			self.return_undeliverable(header.path, TraverseFailure::DeadEnd, header.receipt, encryption, outgoing)?;
+		}
+		Ok(())
+	}
+	/// Pop this node's handle off a path being returned along and find the node before it.
+	/// Returns Ok(None) if this node sent the packet the path belongs to.
+	fn previous_hop(&mut self, path: &mut Vec<HopHandle>) -> Result<Option<NodeID>, NodeError> {
+		let handle = path.pop().ok_or(NodeError::UnknownHopHandle)?;
+		let state = self.traverse_hops.get_mut(&handle).ok_or(NodeError::UnknownHopHandle)?;
+		state.used_at = self.ticks;
+		Ok(state.previous)
+	}
+	/// Pass an undeliverable notice to the next node on the path back to the sender, or handle it if this node is the sender
+	fn return_undeliverable(&mut self, mut path: Vec<HopHandle>, failure: TraverseFailure, receipt: Option<TraverseID>, encryption: Box<NodeEncryption>, outgoing: &mut PacketVec) -> Result<(), NodeError> {
+		if let Some(next_node_id) = self.previous_hop(&mut path)? {
+			self.remote(&next_node_id)?.add_packet(NodePacket::TraverseUndeliverable(path, failure, receipt, encryption), outgoing)?;
+		} else {
+			match *encryption {
+				NodeEncryption::Traversal { recipient, data, .. } | NodeEncryption::Introduce { target: recipient, data, .. } => {
+					log::warn!("Traverse packet with data: {} to NodeID({}) was undeliverable: {:?}", data, recipient, failure);
+					match receipt {
+						// Retry packets waiting for a receipt instead of reporting them
+						Some(traverse_id) if self.traverse_receipts.contains_key(&traverse_id) => self.send_traverse_reliable(traverse_id, outgoing)?,
+						// Already acknowledged or given up on, which was reported then
+						Some(traverse_id) => log::debug!("Undeliverable notice for Traverse packet that is no longer pending: {}", traverse_id),
+						None => self.push_event(NodeEvent::TraverseUndeliverable(recipient, data, failure)),
+					}
+					// The introducer may be gone, read the descriptor again before the next attempt
+					if let NodeEncryption::Introduce { .. } = *encryption {
+						if let Some(remote) = self.remotes.get_mut(&recipient) { remote.descriptor = None; }
+					}
+				},
+				_ => log::warn!("Traverse packet was undeliverable: {:?}", failure),
+			}
+		}
+		Ok(())
+	}
+	/// Pass a reply to the next node on the path back to the sender, or handle it if this node is the sender
+	fn return_routed(&mut self, mut path: Vec<HopHandle>, encryption: Box<NodeEncryption>, outgoing: &mut PacketVec) -> Result<(), NodeError> {
+		if let Some(next_node_id) = self.previous_hop(&mut path)? {
+			self.remote(&next_node_id)?.add_packet(NodePacket::TraverseReturn(path, encryption), outgoing)?;
+		} else {
+			match *encryption {
+				NodeEncryption::Routed { recipient: Some(recipient), sender, packet } if recipient == self.node_id => self.parse_routed_packet(sender, Vec::new(), packet, outgoing)?,
+				_ => log::warn!("Returned packet wasn't routed to this node: {:?}", encryption),
+			}
+		}
+		Ok(())
+	}
+	/// Pass a receipt to the next node on the path back to the sender, or handle it if this node is the sender
+	fn return_receipt(&mut self, mut path: Vec<HopHandle>, mut receipt: TraverseReceipt, outgoing: &mut PacketVec) -> Result<(), NodeError> {
+		receipt.route.push(self.node_id);
+		if let Some(next_node_id) = self.previous_hop(&mut path)? {
+			self.remote(&next_node_id)?.add_packet(NodePacket::TraverseDelivered(path, receipt), outgoing)?;
+		} else if let Some(pending) = self.traverse_receipts.remove(&receipt.traverse_id) {
+			receipt.route.reverse();
+			let round_trip = self.ticks - pending.sent_at;
+			log::info!("Traverse packet with data: {} to NodeID({}) was delivered in {} hops, round trip: {} ticks", pending.data, pending.recipient, receipt.hops, round_trip);
+			self.remote_mut(&pending.recipient)?.traverse_route = Some((receipt.route, round_trip));
+			self.push_event(NodeEvent::TraverseAcknowledged(pending.recipient, pending.data, round_trip, receipt.hops));
+		} else {
+			log::debug!("Received receipt for unknown or already acknowledged Traverse packet: {}", receipt.traverse_id);
+		}
+		Ok(())
+	}
+}
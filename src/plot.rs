@@ -7,62 +7,225 @@ use petgraph::{Graph};
 
 use std::ops::Range;
 
+use crate::internet::embedding::EmbeddingReport;
+use crate::internet::traversal::TraversalPath;
+
+mod html;
+pub mod charts;
+pub use html::export_html;
+
 const DEFAULT_BACKGROUND: RGBColor = RGBColor(200, 200, 200);
 
+/// Node of a plotted graph
+#[derive(Debug)]
+pub struct PlotNode {
+	pub label: String,
+	pub position: Point2<i32>,
+	pub color: RGBColor,
+	/// Details shown when hovering the node in interactive plots
+	pub info: String,
+}
+impl PlotNode {
+	pub fn new(label: String, position: Point2<i32>) -> Self { Self { label, position, color: BLACK, info: String::new() } }
+	pub fn with_color(mut self, color: RGBColor) -> Self { self.color = color; self }
+	pub fn with_info(mut self, info: String) -> Self { self.info = info; self }
+}
+
+/// Radius of plotted nodes in pixels, shrinks as more nodes are plotted so that large networks stay readable
+pub fn node_radius(node_count: usize) -> f32 {
+	(20. * (30. / node_count.max(1) as f32).sqrt()).clamp(4., 20.)
+}
+
+/// Edge of a plotted graph, drawn in `color` with an optional label at its midpoint
+#[derive(Debug)]
+pub struct PlotEdge {
+	pub color: RGBColor,
+	pub label: Option<String>,
+}
+impl From<RGBColor> for PlotEdge { fn from(color: RGBColor) -> Self { Self { color, label: None } } }
+
 pub trait GraphPlottable {
-	fn gen_graph(&self) -> Graph<(String, Point2<i32>), RGBColor>;
+	fn gen_graph(&self) -> Graph<PlotNode, PlotEdge>;
 }
 
+/// Plot a graph as an SVG if `image_output` ends in `.svg`, as an interactive HTML page if it ends in `.html` and as a bitmap otherwise
 pub fn default_graph<GI: GraphPlottable>(item: &GI, render_range: &(Range<i32>, Range<i32>), image_output: &str, image_dimensions: (u32,u32)) -> anyhow::Result<()> {
-	let graph_data = item.gen_graph();
+	if image_output.ends_with(".svg") {
+		let root = SVGBackend::new(image_output, image_dimensions).into_drawing_area();
+		draw_graph(&root, &item.gen_graph(), render_range)
+	} else if image_output.ends_with(".html") {
+		export_html(&item.gen_graph(), render_range, image_output, image_output)
+	} else {
+		let root = BitMapBackend::new(image_output, image_dimensions).into_drawing_area();
+		draw_graph(&root, &item.gen_graph(), render_range)
+	}
+}
+
+/// Plot two graphs next to each other with a title above each, e.g. a node's view of the network and the real network
+pub fn side_by_side<L: GraphPlottable, R: GraphPlottable>(left: (&str, &L), right: (&str, &R), render_range: &(Range<i32>, Range<i32>), image_output: &str, image_dimensions: (u32,u32)) -> anyhow::Result<()> {
 	let root = BitMapBackend::new(image_output, image_dimensions).into_drawing_area();
-	
+	root.fill(&DEFAULT_BACKGROUND)?;
+	let (left_area, right_area) = root.split_horizontally(image_dimensions.0 / 2);
+	for (area, (title, item)) in [(left_area, (left.0, &left.1.gen_graph())), (right_area, (right.0, &right.1.gen_graph()))] {
+		let area = area.margin(0, 0, 2, 2).titled(title, ("sans-serif", 30))?;
+		draw_graph(&area, item, render_range)?;
+	}
+	Ok(())
+}
+
+/// Draw a graph onto an area, with the origin in the middle of the area
+pub fn draw_graph<DB: DrawingBackend>(area: &DrawingArea<DB, plotters::coord::Shift>, graph_data: &Graph<PlotNode, PlotEdge>, render_range: &(Range<i32>, Range<i32>)) -> anyhow::Result<()>
+	where DB::ErrorType: 'static
+{
 	let to_tuple = |point: Point2<f32>| {
 		(point[0], point[1])
 	};
 
 	// Set background color
-	root.fill(&DEFAULT_BACKGROUND)?;
+	area.fill(&DEFAULT_BACKGROUND)?;
 	// Make sure it uses correct graph layout with 4 quadrants
 	let logic_x = -(render_range.0.end as f32)..(render_range.0.end as f32);
 	let logic_y = (render_range.1.end as f32)..-(render_range.1.end as f32);
-	let root = root.apply_coord_spec(Cartesian2d::<RangedCoordf32, RangedCoordf32>::new(
+	let root = area.apply_coord_spec(Cartesian2d::<RangedCoordf32, RangedCoordf32>::new(
 		logic_x,
 		logic_y,
-		(0..image_dimensions.0 as i32, 0..image_dimensions.1 as i32),
+		area.get_pixel_range(),
 	));
 
 	// Draw Connections
 	use petgraph::visit::EdgeRef;
+	use plotters::style::text_anchor::{Pos, HPos, VPos};
 	for node_idx in graph_data.node_indices() {
-		let node_coord = &graph_data[node_idx].1.clone().map(|n|n as f32);
+		let node_coord = &graph_data[node_idx].position.map(|n|n as f32);
 		for edge in graph_data.edges_directed(node_idx, petgraph::EdgeDirection::Outgoing) {
 			let remote_idx = edge.target();
-			let remote_coord = graph_data[remote_idx].1.clone().map(|n|n as f32);
-	
+			let remote_coord = graph_data[remote_idx].position.map(|n|n as f32);
+
 			// offset connections so both directions show side by side
 			let offset = (nalgebra::Rotation2::new(std::f32::consts::FRAC_PI_2) * (node_coord - remote_coord)).normalize();
 			let offset_node_coord = node_coord + (offset * 1.);
 			let offset_remote_coord = remote_coord + (offset * 1.);
 			// Draw offset edge with passed color
-			root.draw(&PathElement::new([to_tuple(offset_node_coord), to_tuple(offset_remote_coord)], ShapeStyle::from(edge.weight()).stroke_width(3)))?;
+			root.draw(&PathElement::new([to_tuple(offset_node_coord), to_tuple(offset_remote_coord)], ShapeStyle::from(&edge.weight().color).stroke_width(3)))?;
+			if let Some(label) = &edge.weight().label {
+				// Move label off the line so labels of both directions don't overlap
+				let midpoint = offset_node_coord + (offset_remote_coord - offset_node_coord) / 2. + offset * 6.;
+				root.draw(&Text::new(label.clone(), to_tuple(midpoint), ("sans-serif", 16.0).into_font().color(&edge.weight().color).pos(Pos::new(HPos::Center, VPos::Center))))?;
+			}
 		}
 	}
-	
+
 	// Draw Nodes
-	use plotters::style::text_anchor::{Pos, HPos, VPos};
+	let radius = node_radius(graph_data.node_count());
 	for node in graph_data.raw_nodes() {
-		let (label, position) = &node.weight;
+		let PlotNode { label, position, color, .. } = &node.weight;
 		let position = (position[0] as f32, position[1] as f32);
-		
+
 		root.draw(&(EmptyElement::at(position) // Outer object
-			+ Circle::new((0, 0), 20, ShapeStyle::from(&BLACK).filled()) // Draw Circle
+			+ Circle::new((0, 0), radius as i32, ShapeStyle::from(color).filled()) // Draw Circle
 			+ Text::new( // Draw Text
 				label.clone(),
 				(0, 0),
-				("sans-serif", 30.0).into_font().color(&WHITE).pos(Pos::new(HPos::Center, VPos::Center)),
+				("sans-serif", radius as f64 * 1.5).into_font().color(&WHITE).pos(Pos::new(HPos::Center, VPos::Center)),
 			))
 		)?;
 	}
 	Ok(())
-}
\ No newline at end of file
+}
+
+/// Plot every node's real position (black) and its RouteCoord (red) joined by the error between them, with error statistics in the corner
+pub fn embedding_overlay(report: &EmbeddingReport, render_range: &(Range<i32>, Range<i32>), image_output: &str, image_dimensions: (u32,u32)) -> anyhow::Result<()> {
+	let area = BitMapBackend::new(image_output, image_dimensions).into_drawing_area();
+	area.fill(&DEFAULT_BACKGROUND)?;
+	let logic_x = -(render_range.0.end as f32)..(render_range.0.end as f32);
+	let logic_y = (render_range.1.end as f32)..-(render_range.1.end as f32);
+	let root = area.apply_coord_spec(Cartesian2d::<RangedCoordf32, RangedCoordf32>::new(logic_x, logic_y, area.get_pixel_range()));
+
+	use plotters::style::text_anchor::{Pos, HPos, VPos};
+	let error_color = RGBColor(200, 0, 0);
+	for sample in &report.samples {
+		let (position, route_coord) = ((sample.position[0], sample.position[1]), (sample.route_coord[0], sample.route_coord[1]));
+		root.draw(&PathElement::new([position, route_coord], ShapeStyle::from(&error_color).stroke_width(2)))?;
+		root.draw(&Circle::new(route_coord, 6, ShapeStyle::from(&error_color).filled()))?;
+		root.draw(&(EmptyElement::at(position)
+			+ Circle::new((0, 0), 12, ShapeStyle::from(&BLACK).filled())
+			+ Text::new(sample.node_id.to_string(), (0, 0), ("sans-serif", 16.0).into_font().color(&WHITE).pos(Pos::new(HPos::Center, VPos::Center)))
+		))?;
+	}
+	let lines = [
+		format!("{} nodes at tick {}", report.samples.len(), report.tick),
+		format!("Mean error: {:.2}", report.mean),
+		format!("Median error: {:.2}", report.median),
+		format!("90th percentile: {:.2}", report.p90),
+		format!("Max error: {:.2}", report.max),
+	];
+	for (i, line) in lines.iter().enumerate() {
+		area.draw(&Text::new(line.clone(), (10, 10 + i as i32 * 24), ("sans-serif", 20.0).into_font().color(&BLACK)))?;
+	}
+	Ok(())
+}
+
+/// Plot the route a Traverse packet took over the nodes of a graph: an arrow per hop numbered in order (orange when backtracking),
+/// the destination RouteCoord marked with a red cross and the dead ends greedy routing got stuck at circled in red
+pub fn traversal_overlay<GI: GraphPlottable>(item: &GI, path: &TraversalPath, render_range: &(Range<i32>, Range<i32>), image_output: &str, image_dimensions: (u32,u32)) -> anyhow::Result<()> {
+	let area = BitMapBackend::new(image_output, image_dimensions).into_drawing_area();
+	area.fill(&DEFAULT_BACKGROUND)?;
+	let logic_x = -(render_range.0.end as f32)..(render_range.0.end as f32);
+	let logic_y = (render_range.1.end as f32)..-(render_range.1.end as f32);
+	let root = area.apply_coord_spec(Cartesian2d::<RangedCoordf32, RangedCoordf32>::new(logic_x, logic_y, area.get_pixel_range()));
+	// Pixels per logical unit, used to keep arrows clear of the nodes they join
+	let scale = image_dimensions.0 as f32 / (render_range.0.end * 2) as f32;
+
+	use plotters::style::text_anchor::{Pos, HPos, VPos};
+	let graph = item.gen_graph();
+	let radius = node_radius(graph.node_count());
+	for node in graph.raw_nodes() {
+		let PlotNode { label, position, .. } = &node.weight;
+		root.draw(&(EmptyElement::at((position[0] as f32, position[1] as f32))
+			+ Circle::new((0, 0), radius as i32, ShapeStyle::from(&RGBColor(100, 100, 100)).filled())
+			+ Text::new(label.clone(), (0, 0), ("sans-serif", radius as f64 * 1.5).into_font().color(&WHITE).pos(Pos::new(HPos::Center, VPos::Center)))
+		))?;
+	}
+
+	let (forward_color, backtrack_color, mark_color) = (RGBColor(0, 90, 200), RGBColor(230, 120, 0), RGBColor(200, 0, 0));
+	for (i, hop) in path.hops.iter().enumerate() {
+		let delta = hop.to_position - hop.from_position;
+		if delta.norm() == 0. { continue }
+		let direction = delta.normalize();
+		let normal = nalgebra::Vector2::new(-direction[1], direction[0]);
+		// Offset hops sideways so that backtracking doesn't hide the hop it undoes
+		let offset = normal * (4. / scale);
+		let start = hop.from_position + direction * (radius / scale) + offset;
+		let end = hop.to_position - direction * (radius / scale) + offset;
+		let head = 12. / scale;
+		let color = if hop.backtrack { &backtrack_color } else { &forward_color };
+		let style = ShapeStyle::from(color).stroke_width(3);
+		root.draw(&PathElement::new([(start[0], start[1]), (end[0], end[1])], style.clone()))?;
+		let (left, right) = (end - direction * head + normal * (head / 2.), end - direction * head - normal * (head / 2.));
+		root.draw(&PathElement::new([(left[0], left[1]), (end[0], end[1]), (right[0], right[1])], style))?;
+		let label = start + (end - start) / 2. + normal * (12. / scale);
+		root.draw(&Text::new((i + 1).to_string(), (label[0], label[1]), ("sans-serif", 20.0).into_font().color(color).pos(Pos::new(HPos::Center, VPos::Center))))?;
+	}
+
+	for (_, position) in path.dead_ends() {
+		root.draw(&(EmptyElement::at((position[0], position[1]))
+			+ Circle::new((0, 0), radius as i32 + 6, ShapeStyle::from(&mark_color).stroke_width(3))
+			+ Text::new("Dead end", (0, radius as i32 + 10), ("sans-serif", 18.0).into_font().color(&mark_color).pos(Pos::new(HPos::Center, VPos::Top)))
+		))?;
+	}
+	let destination = (path.destination[0] as f32, path.destination[1] as f32);
+	root.draw(&(EmptyElement::at(destination)
+		+ Cross::new((0, 0), 10, ShapeStyle::from(&mark_color).stroke_width(3))
+		+ Text::new("Destination", (0, 14), ("sans-serif", 18.0).into_font().color(&mark_color).pos(Pos::new(HPos::Center, VPos::Top)))
+	))?;
+
+	let backtracks = path.hops.iter().filter(|h|h.backtrack).count();
+	let lines = [
+		format!("NodeID({}) to RouteCoord({}, {})", path.origin, path.destination[0], path.destination[1]),
+		format!("{} hops, {} backtracking", path.hops.len(), backtracks),
+	];
+	for (i, line) in lines.iter().enumerate() {
+		area.draw(&Text::new(line.clone(), (10, 10 + i as i32 * 24), ("sans-serif", 20.0).into_font().color(&BLACK)))?;
+	}
+	Ok(())
+}
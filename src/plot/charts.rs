@@ -0,0 +1,133 @@
+//! Charts of simulation statistics, written as an SVG if the output path ends in `.svg` and as a bitmap otherwise
+
+use plotters::prelude::*;
+use plotters::coord::Shift;
+
+use crate::internet::embedding::EmbeddingReport;
+use crate::internet::session_stats::LatencySample;
+
+/// Number of bars in a histogram of continuous values
+const HISTOGRAM_BINS: usize = 30;
+
+/// Run a drawing function on an SVG backend if `path` ends in `.svg` and on a bitmap backend otherwise
+macro_rules! draw_to {
+	($path:expr, $dimensions:expr, $draw:ident ( $($arg:expr),* )) => {
+		if $path.ends_with(".svg") { $draw(&SVGBackend::new($path, $dimensions).into_drawing_area(), $($arg),*) }
+		else { $draw(&BitMapBackend::new($path, $dimensions).into_drawing_area(), $($arg),*) }
+	};
+}
+
+/// Count `values` into `bins` equal bins from 0 to `max`, values at or above `max` go in the last bin
+fn bin_counts(values: impl Iterator<Item = f64>, bins: usize, max: f64) -> Vec<usize> {
+	let mut counts = vec![0; bins];
+	for value in values {
+		counts[((value / max * bins as f64) as usize).min(bins - 1)] += 1;
+	}
+	counts
+}
+
+/// Histogram of every direct session's measured latency (`SessionTracker::dist_avg`) overlaid on the real latency between the same nodes
+pub fn latency_histogram(samples: &[LatencySample], path: &str, dimensions: (u32, u32)) -> anyhow::Result<()> {
+	draw_to!(path, dimensions, draw_latency_histogram(samples))
+}
+fn draw_latency_histogram<DB: DrawingBackend>(area: &DrawingArea<DB, Shift>, samples: &[LatencySample]) -> anyhow::Result<()>
+	where DB::ErrorType: 'static
+{
+	area.fill(&WHITE)?;
+	let max = samples.iter().map(|s|s.measured.max(s.actual)).max().unwrap_or(0) as f64 + 1.;
+	let measured = bin_counts(samples.iter().map(|s|s.measured as f64), HISTOGRAM_BINS, max);
+	let actual = bin_counts(samples.iter().map(|s|s.actual as f64), HISTOGRAM_BINS, max);
+	let max_count = measured.iter().chain(&actual).max().cloned().unwrap_or(0) + 1;
+	let bin_width = max / HISTOGRAM_BINS as f64;
+
+	let mut chart = ChartBuilder::on(area)
+		.caption(format!("Measured vs real latency of {} direct sessions", samples.len()), ("sans-serif", 30))
+		.margin(20).x_label_area_size(50).y_label_area_size(60)
+		.build_cartesian_2d(0f64..max, 0..max_count)?;
+	chart.configure_mesh().x_desc("Latency (ticks)").y_desc("Sessions").disable_x_mesh().draw()?;
+	for (counts, color, label) in [(&actual, RGBColor(100, 100, 100), "Real latency"), (&measured, RGBColor(0, 90, 200), "Measured dist_avg")] {
+		chart.draw_series(counts.iter().enumerate().map(|(i, &count)|{
+			Rectangle::new([(i as f64 * bin_width, 0), ((i + 1) as f64 * bin_width, count)], color.mix(0.5).filled())
+		}))?
+			.label(label)
+			.legend(move |(x, y)|Rectangle::new([(x, y - 5), (x + 10, y + 5)], color.mix(0.5).filled()));
+	}
+	chart.configure_series_labels().background_style(WHITE.mix(0.8)).border_style(BLACK).draw()?;
+	area.present()?;
+	Ok(())
+}
+
+/// Heatmap of the embedding error between every pair of nodes (see `EmbeddingReport::pairwise_errors`), from white (no error) to red (largest error)
+pub fn embedding_error_heatmap(report: &EmbeddingReport, path: &str, dimensions: (u32, u32)) -> anyhow::Result<()> {
+	draw_to!(path, dimensions, draw_embedding_error_heatmap(report))
+}
+fn draw_embedding_error_heatmap<DB: DrawingBackend>(area: &DrawingArea<DB, Shift>, report: &EmbeddingReport) -> anyhow::Result<()>
+	where DB::ErrorType: 'static
+{
+	area.fill(&WHITE)?;
+	let errors = report.pairwise_errors();
+	let count = errors.len();
+	let max = errors.iter().flatten().cloned().fold(0f32, f32::max);
+	let node_ids: Vec<String> = report.samples.iter().map(|s|s.node_id.to_string()).collect();
+	let label = |i: &usize| node_ids.get(*i).cloned().unwrap_or_default();
+
+	let mut chart = ChartBuilder::on(area)
+		.caption(format!("Pairwise embedding error at tick {}, max: {:.2}", report.tick, max), ("sans-serif", 30))
+		.margin(20).x_label_area_size(50).y_label_area_size(60)
+		.build_cartesian_2d(0..count, 0..count)?;
+	chart.configure_mesh().x_desc("NodeID").y_desc("NodeID").disable_mesh().x_label_formatter(&label).y_label_formatter(&label).draw()?;
+	chart.draw_series(errors.iter().enumerate().flat_map(|(i, row)|{
+		row.iter().enumerate().map(move |(j, &error)|{
+			let shade = if max > 0. { 1. - error / max } else { 1. };
+			Rectangle::new([(i, j), (i + 1, j + 1)], RGBColor(255, (255. * shade) as u8, (255. * shade) as u8).filled())
+		})
+	}))?;
+	area.present()?;
+	Ok(())
+}
+
+/// Bar chart of how many nodes have each number of peers
+pub fn peer_count_histogram(peer_counts: &[usize], path: &str, dimensions: (u32, u32)) -> anyhow::Result<()> {
+	draw_to!(path, dimensions, draw_peer_count_histogram(peer_counts))
+}
+fn draw_peer_count_histogram<DB: DrawingBackend>(area: &DrawingArea<DB, Shift>, peer_counts: &[usize]) -> anyhow::Result<()>
+	where DB::ErrorType: 'static
+{
+	area.fill(&WHITE)?;
+	let max_peers = peer_counts.iter().max().cloned().unwrap_or(0);
+	let mut counts = vec![0; max_peers + 1];
+	for &peers in peer_counts { counts[peers] += 1 }
+	let max_count = counts.iter().max().cloned().unwrap_or(0) + 1;
+
+	let mut chart = ChartBuilder::on(area)
+		.caption(format!("Peer counts of {} nodes", peer_counts.len()), ("sans-serif", 30))
+		.margin(20).x_label_area_size(50).y_label_area_size(60)
+		.build_cartesian_2d((0..max_peers).into_segmented(), 0..max_count)?;
+	chart.configure_mesh().x_desc("Peers").y_desc("Nodes").disable_x_mesh().draw()?;
+	chart.draw_series(Histogram::vertical(&chart).style(RGBColor(0, 90, 200).filled()).margin(4).data(counts.iter().enumerate().map(|(peers, &count)|(peers, count))))?;
+	area.present()?;
+	Ok(())
+}
+
+/// Line chart of the mean embedding error over time, from `(tick, mean error)` points
+pub fn error_time_series(series: &[(usize, f32)], path: &str, dimensions: (u32, u32)) -> anyhow::Result<()> {
+	draw_to!(path, dimensions, draw_error_time_series(series))
+}
+fn draw_error_time_series<DB: DrawingBackend>(area: &DrawingArea<DB, Shift>, series: &[(usize, f32)]) -> anyhow::Result<()>
+	where DB::ErrorType: 'static
+{
+	area.fill(&WHITE)?;
+	let (start, end) = (series.first().map_or(0, |p|p.0), series.last().map_or(0, |p|p.0) + 1);
+	let max = series.iter().map(|p|p.1).fold(0f32, f32::max) * 1.1 + 1.;
+
+	let mut chart = ChartBuilder::on(area)
+		.caption("Mean embedding error", ("sans-serif", 30))
+		.margin(20).x_label_area_size(50).y_label_area_size(60)
+		.build_cartesian_2d(start..end, 0f32..max)?;
+	chart.configure_mesh().x_desc("Tick").y_desc("Mean error").draw()?;
+	let color = RGBColor(200, 0, 0);
+	chart.draw_series(LineSeries::new(series.iter().cloned(), ShapeStyle::from(&color).stroke_width(2)))?;
+	chart.draw_series(series.iter().map(|&point|Circle::new(point, 3, color.filled())))?;
+	area.present()?;
+	Ok(())
+}
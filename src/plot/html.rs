@@ -0,0 +1,84 @@
+//! Self-contained HTML export of a plotted graph, drawn as inline SVG that can be zoomed with the mouse wheel, panned by dragging and hovered for node details
+
+use std::fmt::Write as _;
+use std::ops::Range;
+
+use petgraph::Graph;
+use petgraph::visit::EdgeRef;
+use plotters::style::RGBColor;
+
+use super::{PlotNode, PlotEdge, node_radius};
+
+fn escape(text: &str) -> String {
+	text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\n', "&#10;")
+}
+fn css_color(color: &RGBColor) -> String { format!("rgb({},{},{})", color.0, color.1, color.2) }
+
+const SCRIPT: &str = r#"
+const svg = document.getElementById('graph');
+const info = document.getElementById('info');
+const view = svg.viewBox.baseVal;
+const toGraph = (x, y) => { const p = svg.createSVGPoint(); p.x = x; p.y = y; return p.matrixTransform(svg.getScreenCTM().inverse()); };
+svg.addEventListener('wheel', e => {
+	e.preventDefault();
+	const scale = e.deltaY < 0 ? 0.8 : 1.25;
+	const c = toGraph(e.clientX, e.clientY);
+	view.x = c.x - (c.x - view.x) * scale; view.y = c.y - (c.y - view.y) * scale;
+	view.width *= scale; view.height *= scale;
+});
+let drag = null;
+svg.addEventListener('mousedown', e => { drag = { x: e.clientX, y: e.clientY }; });
+window.addEventListener('mouseup', () => { drag = null; });
+window.addEventListener('mousemove', e => {
+	if (!drag) return;
+	const k = 1 / svg.getScreenCTM().a;
+	view.x -= (e.clientX - drag.x) * k; view.y -= (e.clientY - drag.y) * k;
+	drag = { x: e.clientX, y: e.clientY };
+});
+document.querySelectorAll('.node').forEach(n => n.addEventListener('mouseenter', () => { info.textContent = n.dataset.info; }));
+"#;
+
+/// Write a graph to an HTML page, hovering a node shows its `info` in a panel
+pub fn export_html(graph: &Graph<PlotNode, PlotEdge>, render_range: &(Range<i32>, Range<i32>), path: &str, title: &str) -> anyhow::Result<()> {
+	let (width, height) = (render_range.0.end - render_range.0.start, render_range.1.end - render_range.1.start);
+	let radius = node_radius(graph.node_count()) / 2.;
+	let mut svg = String::new();
+	// SVG's y axis points down, so y is flipped to keep the same orientation as the bitmap plots
+	writeln!(svg, r#"<svg id="graph" viewBox="{} {} {} {}" xmlns="http://www.w3.org/2000/svg">"#, render_range.0.start, -render_range.1.end, width, height)?;
+	for edge in graph.edge_references() {
+		let (from, to) = (&graph[edge.source()].position, &graph[edge.target()].position);
+		let PlotEdge { color, label } = edge.weight();
+		writeln!(svg, r#"<line x1="{}" y1="{}" x2="{}" y2="{}" stroke="{}" stroke-width="{}"><title>{}</title></line>"#,
+			from[0], -from[1], to[0], -to[1], css_color(color), radius / 4., escape(label.as_deref().unwrap_or("")))?;
+	}
+	for node in graph.raw_nodes() {
+		let PlotNode { label, position, color, info } = &node.weight;
+		writeln!(svg, r#"<g class="node" data-info="{}"><circle cx="{}" cy="{}" r="{}" fill="{}"/><text x="{}" y="{}" font-size="{}">{}</text><title>{}</title></g>"#,
+			escape(info), position[0], -position[1], radius, css_color(color), position[0], -position[1], radius, escape(label), escape(info))?;
+	}
+	svg.push_str("</svg>");
+
+	let html = format!(r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{title}</title>
+<style>
+body {{ margin: 0; background: rgb(200,200,200); font-family: sans-serif; }}
+#graph {{ width: 100vw; height: 100vh; cursor: grab; }}
+.node text {{ fill: white; text-anchor: middle; dominant-baseline: central; pointer-events: none; }}
+.node:hover circle {{ stroke: red; stroke-width: {stroke}; }}
+#info {{ position: fixed; top: 8px; right: 8px; margin: 0; padding: 8px; background: rgba(255,255,255,0.9); white-space: pre; }}
+</style>
+</head>
+<body>
+<h3 style="position: fixed; top: 8px; left: 8px; margin: 0;">{title}</h3>
+<pre id="info">Hover a node for details, scroll to zoom and drag to pan</pre>
+{svg}
+<script>{script}</script>
+</body>
+</html>
+"#, title = escape(title), stroke = radius / 3., svg = svg, script = SCRIPT);
+	std::fs::write(path, html)?;
+	Ok(())
+}
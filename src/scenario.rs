@@ -0,0 +1,325 @@
+//! Declarative simulation scenarios, loaded from TOML or JSON files and run to completion without the REPL
+
+use std::collections::HashSet;
+
+use rand::SeedableRng;
+
+use crate::config::{self, NodeConfig, SimConfig};
+use crate::internet::{InternetID, InternetSim, CustomNode, SimRng};
+use crate::node::{Node, NodeAction, NodeActionCondition, NodeEvent, NodeID};
+use crate::logging::span;
+use crate::plot;
+
+/// A whole simulation run: which nodes exist, how they join, what happens to them and what is checked at the end
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Scenario {
+	/// Seed of the RNG driving the simulation
+	pub seed: u64,
+	pub sim: SimConfig,
+	pub nodes: NodeSet,
+	pub bootstrap: BootstrapPlan,
+	/// Actions run at a tick relative to the end of the bootstrap
+	pub events: Vec<TimedEvent>,
+	/// Ticks to run after the bootstrap, defaults to the tick of the last event
+	pub duration: Option<usize>,
+	/// Checked once the scenario has finished running
+	pub assertions: Vec<Assertion>,
+	/// Written once the scenario has finished running
+	pub outputs: Vec<Output>,
+}
+
+/// Nodes to create, either a count (NodeIDs 0 to count-1 with the default config) or a list
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum NodeSet {
+	Count(usize),
+	List(Vec<NodeSpec>),
+}
+impl Default for NodeSet { fn default() -> Self { NodeSet::Count(0) } }
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NodeSpec {
+	pub id: NodeID,
+	/// Config for this node, the simulation's default node config if not set
+	#[serde(default)]
+	pub config: Option<NodeConfig>,
+	/// Private nodes are only reachable through introducers
+	#[serde(default = "default_public")]
+	pub public: bool,
+}
+fn default_public() -> bool { true }
+
+/// How nodes join the network: every node bootstraps onto `onto` one after another
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct BootstrapPlan {
+	/// NodeID of the node everyone else bootstraps onto
+	pub onto: NodeID,
+	/// Ticks between each node bootstrapping
+	pub interval: usize,
+	/// Ticks to run after the last node has bootstrapped
+	pub settle: usize,
+}
+impl Default for BootstrapPlan { fn default() -> Self { Self { onto: 0, interval: 400, settle: 4000 } } }
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TimedEvent {
+	/// Ticks after the bootstrap finished
+	pub at: usize,
+	#[serde(flatten)]
+	pub action: ScenarioAction,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum ScenarioAction {
+	/// Send a Traverse packet, optionally asking for a receipt.
+	/// Both this and `Route` wait for the optional `when` condition first, e.g. `when = { and = ["has_route_coord", { peer_count_at_least = 3 }] }`
+	Traverse { from: NodeID, to: NodeID, data: u64, #[serde(default)] reliable: bool, #[serde(default)] when: Option<NodeActionCondition> },
+	/// Establish a routed session
+	Route { from: NodeID, to: NodeID, #[serde(default = "default_route_hops")] hops: usize, #[serde(default)] when: Option<NodeActionCondition> },
+	/// Remove a node from the network
+	Del { node: NodeID },
+	/// Cut the listed nodes off from the rest of the network
+	Partition { nodes: Vec<NodeID> },
+	/// Remove the partition
+	Heal,
+	/// Check an assertion at this point in the scenario
+	Assert { assertion: Assertion },
+}
+fn default_route_hops() -> usize { 3 }
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Assertion {
+	/// A Traverse packet with `data` sent by `from` was received by `to`
+	Delivered { from: NodeID, to: NodeID, data: u64 },
+	/// A node has a RouteCoord, or every node if `node` is not set
+	HasRouteCoord { #[serde(default)] node: Option<NodeID> },
+	/// A node has at least `count` peers, or every node if `node` is not set
+	PeerCount { #[serde(default)] node: Option<NodeID>, count: usize },
+	/// Routing between random pairs delivers at least this fraction of packets
+	DeliveryRate { at_least: f64, #[serde(default = "default_samples")] samples: usize, #[serde(default = "default_timeout")] timeout: usize },
+	/// No action has failed on a node so far, or on any node if `node` is not set
+	NoFailedActions { #[serde(default)] node: Option<NodeID> },
+}
+fn default_samples() -> usize { 50 }
+fn default_interval() -> usize { 100 }
+fn default_timeout() -> usize { 3000 }
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Output {
+	/// Plot of the network, as an SVG if the path ends in `.svg`, as an interactive HTML page if it ends in `.html` and as a bitmap otherwise
+	Graph { path: String },
+	/// Plot of every node's real position against its RouteCoord
+	Embedding { path: String },
+	/// Plot of the network every `every` ticks from the start, as a GIF if the path ends in `.gif` and as a directory of frames otherwise.
+	/// Only one animation is recorded per scenario.
+	Animation { path: String, #[serde(default = "default_interval")] every: usize },
+	/// Snapshot that can be loaded into the REPL
+	Snapshot { path: String },
+	/// Trace of every packet delivered during the scenario, recorded from the start
+	Trace { path: String },
+	/// Metrics sampled every `every` ticks from the start, as CSV, JSON if the path ends in `.json` or the last sample in Prometheus text format
+	/// if it ends in `.prom`. Only one set of metrics is sampled per scenario.
+	Metrics { path: String, #[serde(default = "default_interval")] every: usize },
+	/// Routing report of random node pairs, as CSV or JSON
+	Routing { path: String, #[serde(default = "default_samples")] samples: usize, #[serde(default = "default_timeout")] timeout: usize },
+}
+
+/// Outcome of running a scenario
+#[derive(Debug, Default)]
+pub struct ScenarioResult {
+	/// Every assertion checked and whether it passed, with the tick it was checked on
+	pub assertions: Vec<(usize, Assertion, bool)>,
+	/// Every event taken from the nodes while the scenario ran, with the tick it was taken on and the node it happened on
+	pub events: Vec<(usize, NodeID, NodeEvent)>,
+}
+impl ScenarioResult {
+	pub fn passed(&self) -> bool { self.assertions.iter().all(|(_,_,passed)|*passed) }
+}
+
+impl Scenario {
+	pub fn load(path: &str) -> anyhow::Result<Self> {
+		let scenario: Self = config::load(path)?;
+		scenario.validate().map_err(|err|anyhow!("{}: {}", path, err))?;
+		Ok(scenario)
+	}
+	/// Check the conditions of every event, see `NodeActionCondition::validate`
+	pub fn validate(&self) -> anyhow::Result<()> {
+		for event in &self.events {
+			if let ScenarioAction::Traverse { when: Some(condition), .. } | ScenarioAction::Route { when: Some(condition), .. } = &event.action {
+				condition.validate().map_err(|err|anyhow!("event at {}: {}", event.at, err))?;
+			}
+		}
+		Ok(())
+	}
+
+	pub fn run(&self) -> anyhow::Result<ScenarioResult> {
+		self.run_then(|_, _|()).map(|(result, _)|result)
+	}
+	/// Run the scenario, then call `after` on the finished simulation before it is dropped
+	pub fn run_then<T>(&self, after: impl FnOnce(&mut InternetSim<Node>, &mut SimRng) -> T) -> anyhow::Result<(ScenarioResult, T)> {
+		let rng = &mut SimRng::seed_from_u64(self.seed);
+		let mut runner = Runner { internet: InternetSim::new(self.sim.clone()), received: HashSet::new(), result: ScenarioResult::default() };
+		let internet = &mut runner.internet;
+		let specs = match &self.nodes {
+			NodeSet::Count(count) => (0..*count as NodeID).map(|id|NodeSpec { id, config: None, public: true }).collect(),
+			NodeSet::List(specs) => specs.clone(),
+		};
+		for spec in &specs {
+			let mut node = Node::new(spec.id, internet.lease(), spec.config.clone().unwrap_or_else(||internet.config.node.clone()));
+			node.is_public = spec.public;
+			internet.add_node(node, rng);
+		}
+		for output in &self.outputs {
+			match output {
+				Output::Trace { path } => internet.start_trace(path)?,
+				Output::Animation { path, every } => internet.start_animation(path, *every, (1280, 720))?,
+				Output::Metrics { every, .. } => internet.start_metrics(*every)?,
+				_ => {},
+			}
+		}
+
+		// Bootstrap every node onto one node
+		let onto_net_id = runner.net_id(self.bootstrap.onto)?;
+		for spec in specs.iter().filter(|s|s.id != self.bootstrap.onto) {
+			let net_id = runner.net_id(spec.id)?;
+			runner.internet.node_mut(net_id).unwrap().action(NodeAction::Bootstrap(self.bootstrap.onto, onto_net_id));
+			runner.advance(self.bootstrap.interval, rng);
+		}
+		runner.advance(self.bootstrap.settle, rng);
+		{
+			let _span = span!("scenario", tick = runner.internet.ticks);
+			log::info!("Scenario finished bootstrapping {} nodes", specs.len());
+		}
+
+		// Run timed events in order
+		let start = runner.internet.ticks;
+		let mut events = self.events.clone();
+		events.sort_by_key(|e|e.at);
+		for event in &events {
+			runner.advance((start + event.at).saturating_sub(runner.internet.ticks), rng);
+			runner.apply(&event.action, rng)?;
+		}
+		let end = start + self.duration.unwrap_or_else(||events.last().map_or(0, |e|e.at));
+		runner.advance(end.saturating_sub(runner.internet.ticks), rng);
+
+		for assertion in &self.assertions { runner.check(assertion, rng)?; }
+		runner.internet.stop_trace()?;
+		runner.internet.stop_animation()?;
+		for output in &self.outputs {
+			match output {
+				Output::Graph { path } => plot::default_graph(&runner.internet, &runner.internet.router.field_dimensions, path, (1280, 720))?,
+				Output::Embedding { path } => {
+					let report = runner.internet.embedding_report();
+					println!("{}", report);
+					plot::embedding_overlay(&report, &runner.internet.router.field_dimensions, path, (1280, 720))?;
+				},
+				Output::Snapshot { path } => runner.internet.save_snapshot(rng, path)?,
+				Output::Trace { .. } | Output::Animation { .. } => {},
+				Output::Metrics { path, .. } => if let Some(metrics) = runner.internet.metrics() { metrics.export(path)? },
+				Output::Routing { path, samples, timeout } => {
+					let report = runner.internet.measure_routing(*samples, *timeout, rng);
+					println!("{}", report);
+					report.export(path)?;
+				},
+			}
+		}
+		let after = after(&mut runner.internet, rng);
+		Ok((runner.result, after))
+	}
+}
+
+/// State of a scenario while it runs
+struct Runner {
+	internet: InternetSim<Node>,
+	/// Traverse packets that arrived: (sender, recipient, data)
+	received: HashSet<(NodeID, NodeID, u64)>,
+	result: ScenarioResult,
+}
+impl Runner {
+	fn net_id(&self, node_id: NodeID) -> anyhow::Result<InternetID> {
+		self.internet.nodes.iter().find(|(_,n)|n.node_id == node_id).map(|(&id,_)|id).ok_or(anyhow!("Scenario refers to unknown NodeID({})", node_id))
+	}
+	/// Queue an action on a node, waiting for `when` first if set. `RunAt` times in `when` count from the current tick.
+	fn action(&mut self, node_id: NodeID, action: NodeAction, when: &Option<NodeActionCondition>) -> anyhow::Result<()> {
+		let node = self.internet.node_mut(self.net_id(node_id)?).unwrap();
+		let action = match when { Some(condition) => action.gen_condition(condition.clone().delay(node.ticks)), None => action };
+		node.action(action);
+		Ok(())
+	}
+	/// Tick the simulation, recording every node event and which Traverse packets arrived
+	fn advance(&mut self, ticks: usize, rng: &mut SimRng) {
+		let end = self.internet.ticks + ticks;
+		while self.internet.ticks < end {
+			// Run up to and including the next tick anything happens on
+			let until = self.internet.next_event().map_or(end, |t|(t + 1).min(end));
+			self.internet.tick(until - self.internet.ticks, rng);
+			for node in self.internet.nodes.values_mut() {
+				for event in node.take_events() {
+					match &event {
+						NodeEvent::TraverseReceived(sender, data, _) => { self.received.insert((*sender, node.node_id, *data)); },
+						NodeEvent::ActionFailed(action, failure) => log::warn!("NodeID({}) action failed ({:?}): {:?}", node.node_id, failure, action),
+						_ => {},
+					}
+					self.result.events.push((self.internet.ticks, node.node_id, event));
+				}
+			}
+		}
+	}
+	fn apply(&mut self, action: &ScenarioAction, rng: &mut SimRng) -> anyhow::Result<()> {
+		let _span = span!("scenario_action", tick = self.internet.ticks);
+		log::info!("Scenario action: {:?}", action);
+		match action {
+			ScenarioAction::Traverse { from, to, data, reliable, when } => {
+				let action = if *reliable { NodeAction::TraverseReliable(*to, *data) } else { NodeAction::Traverse(*to, *data) };
+				self.action(*from, action, when)?;
+			},
+			ScenarioAction::Route { from, to, hops, when } => self.action(*from, NodeAction::ConnectRouted(*to, *hops), when)?,
+			ScenarioAction::Del { node } => {
+				let net_id = self.net_id(*node)?;
+				self.internet.del_node(net_id);
+			},
+			ScenarioAction::Partition { nodes } => {
+				let net_ids = nodes.iter().map(|&id|self.net_id(id)).collect::<anyhow::Result<HashSet<InternetID>>>()?;
+				self.internet.router.partition(net_ids);
+			},
+			ScenarioAction::Heal => self.internet.router.heal(),
+			ScenarioAction::Assert { assertion } => self.check(assertion, rng)?,
+		}
+		Ok(())
+	}
+	fn check(&mut self, assertion: &Assertion, rng: &mut SimRng) -> anyhow::Result<()> {
+		let nodes_matching = |internet: &InternetSim<Node>, node: &Option<NodeID>| -> Vec<NodeID> {
+			internet.nodes.values().map(|n|n.node_id).filter(|id|node.is_none_or(|n|n == *id)).collect()
+		};
+		let passed = match assertion {
+			Assertion::Delivered { from, to, data } => self.received.contains(&(*from, *to, *data)),
+			Assertion::HasRouteCoord { node } => {
+				let node_ids = nodes_matching(&self.internet, node);
+				!node_ids.is_empty() && node_ids.iter().all(|&id|self.internet.node(self.net_id(id).unwrap()).unwrap().route_coord.is_some())
+			},
+			Assertion::PeerCount { node, count } => {
+				let node_ids = nodes_matching(&self.internet, node);
+				!node_ids.is_empty() && node_ids.iter().all(|&id|self.internet.node(self.net_id(id).unwrap()).unwrap().peer_list.len() >= *count)
+			},
+			Assertion::DeliveryRate { at_least, samples, timeout } => {
+				let report = self.internet.measure_routing(*samples, *timeout, rng);
+				println!("{}", report);
+				report.delivery_rate >= *at_least
+			},
+			Assertion::NoFailedActions { node } => {
+				let failures = self.result.events.iter().filter(|(_, id, event)|node.is_none_or(|n|n == *id) && matches!(event, NodeEvent::ActionFailed(..))).collect::<Vec<_>>();
+				for (tick, id, event) in &failures { println!("[{: >6}] NodeID({}): {:?}", tick, id, event); }
+				failures.is_empty()
+			},
+		};
+		println!("[{: >6}] {}: {:?}", self.internet.ticks, if passed { "PASSED" } else { "FAILED" }, assertion);
+		self.result.assertions.push((self.internet.ticks, assertion.clone(), passed));
+		Ok(())
+	}
+}
+
@@ -0,0 +1,358 @@
+//! SOCKS5 frontend that lets local applications use a `Node`'s tunneled streams.
+//! Each CONNECT request is turned into a stream to an exit node, which opens the real TCP connection.
+
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+
+use rand::Rng;
+
+use crate::internet::CustomNode;
+use crate::node::{Node, NodeAction, NodeID, StreamID, StreamStatus, STREAM_BUFFER};
+
+const SOCKS_VERSION: u8 = 0x05;
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_UNACCEPTABLE: u8 = 0xFF;
+const CMD_CONNECT: u8 = 0x01;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+/// SOCKS5 reply codes (RFC 1928 section 6)
+#[derive(Debug, Clone, Copy)]
+enum Reply {
+	Succeeded = 0x00,
+	GeneralFailure = 0x01,
+	ConnectionRefused = 0x05,
+	CommandNotSupported = 0x07,
+	AddressTypeNotSupported = 0x08,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ClientState {
+	/// Waiting for the method negotiation
+	Greeting,
+	/// Waiting for the CONNECT request
+	Request,
+	/// Stream requested, waiting on the exit node
+	Connecting(StreamID),
+	/// Shuttling data between the socket and the stream
+	Connected(StreamID),
+	/// Client shut down its side and the stream was finished, still passing data back until the remote side finishes too
+	Finishing(StreamID),
+	/// Should be dropped
+	Closed,
+}
+
+#[derive(Debug)]
+struct SocksClient {
+	socket: TcpStream,
+	state: ClientState,
+	/// Bytes read from the socket that haven't been consumed yet
+	buffer: Vec<u8>,
+	/// Bytes waiting to be written to the socket
+	pending: Vec<u8>,
+	/// Client has shut down its side of the connection
+	eof: bool,
+	/// Remote side finished, so the socket's write half is shut down once everything pending is written
+	remote_eof: bool,
+	/// Socket's write half has been shut down
+	write_shut: bool,
+}
+
+/// Local SOCKS5 server tunneling every connection through one exit node
+#[derive(Debug)]
+pub struct Socks5Proxy {
+	listener: TcpListener,
+	/// Node that opens the real TCP connections
+	pub exit_node: NodeID,
+	clients: Vec<SocksClient>,
+}
+impl Socks5Proxy {
+	pub fn bind(addr: impl ToSocketAddrs, exit_node: NodeID) -> io::Result<Self> {
+		let listener = TcpListener::bind(addr)?;
+		listener.set_nonblocking(true)?;
+		Ok(Self { listener, exit_node, clients: Vec::new() })
+	}
+	pub fn local_addr(&self) -> io::Result<SocketAddr> { self.listener.local_addr() }
+	pub fn connection_count(&self) -> usize { self.clients.len() }
+	/// Accept new clients and move data between clients and `node`'s streams, should be called every tick
+	pub fn poll(&mut self, node: &mut Node) {
+		loop {
+			match self.listener.accept() {
+				Ok((socket, addr)) => {
+					log::debug!("SOCKS5 connection from {}", addr);
+					if let Err(err) = socket.set_nonblocking(true) { log::error!("Failed to set SOCKS5 socket to non-blocking: {}", err); continue }
+					self.clients.push(SocksClient { socket, state: ClientState::Greeting, buffer: Vec::new(), pending: Vec::new(), eof: false, remote_eof: false, write_shut: false });
+				},
+				Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+				Err(err) => { log::error!("SOCKS5 listener failed to accept: {}", err); break },
+			}
+		}
+		let exit_node = self.exit_node;
+		for client in &mut self.clients {
+			if let Err(err) = client.poll(node, exit_node) {
+				log::warn!("SOCKS5 client errored: {}", err);
+				client.close(node);
+			}
+		}
+		self.clients.retain(|c|c.state != ClientState::Closed);
+	}
+}
+
+impl SocksClient {
+	fn poll(&mut self, node: &mut Node, exit_node: NodeID) -> io::Result<()> {
+		self.read_socket()?;
+		loop {
+			let state = self.state;
+			match state {
+				ClientState::Greeting => {
+					if self.buffer.len() < 2 { break }
+					if self.buffer[0] != SOCKS_VERSION { return Err(io::Error::new(io::ErrorKind::InvalidData, "not a SOCKS5 client")) }
+					let method_count = self.buffer[1] as usize;
+					if self.buffer.len() < 2 + method_count { break }
+					let methods: Vec<u8> = self.buffer.drain(..2 + method_count).skip(2).collect();
+					if methods.contains(&METHOD_NO_AUTH) {
+						self.pending.extend_from_slice(&[SOCKS_VERSION, METHOD_NO_AUTH]);
+						self.state = ClientState::Request;
+					} else {
+						self.pending.extend_from_slice(&[SOCKS_VERSION, METHOD_UNACCEPTABLE]);
+						self.finish();
+					}
+				},
+				ClientState::Request => {
+					match parse_request(&self.buffer) {
+						Ok(None) => break,
+						Ok(Some((len, addr))) => {
+							self.buffer.drain(..len);
+							let stream_id: StreamID = node.rng.gen();
+							node.action(NodeAction::OpenStream(stream_id, exit_node, addr));
+							self.state = ClientState::Connecting(stream_id);
+						},
+						Err(reply) => { self.reply(reply); self.finish(); },
+					}
+				},
+				ClientState::Connecting(stream_id) => {
+					match node.streams.get(&stream_id).map(|s|s.status) {
+						Some(StreamStatus::Open) => { self.reply(Reply::Succeeded); self.state = ClientState::Connected(stream_id); },
+						Some(StreamStatus::Refused) => { node.streams.remove(&stream_id); self.reply(Reply::ConnectionRefused); self.finish(); },
+						Some(StreamStatus::Pending) | None => break, // Action may not have run yet
+						Some(StreamStatus::Unreachable) | Some(StreamStatus::Closed) => { node.streams.remove(&stream_id); self.reply(Reply::GeneralFailure); self.finish(); },
+					}
+				},
+				ClientState::Connected(stream_id) | ClientState::Finishing(stream_id) => {
+					let stream = if let Some(stream) = node.streams.get_mut(&stream_id) { stream } else { self.finish(); break };
+					// Only hand the stream what it has room for, the rest waits here and stops the socket from being read
+					let room = STREAM_BUFFER.saturating_sub(stream.unsent.len()).min(self.buffer.len());
+					let send: Vec<u8> = self.buffer.drain(..room).collect();
+					if self.pending.len() < STREAM_BUFFER || stream.is_finished() { self.pending.append(&mut stream.inbound); }
+					self.remote_eof = stream.remote_finished() && stream.inbound.is_empty();
+					let finished = stream.is_finished();
+					if !send.is_empty() && !finished { node.action(NodeAction::StreamSend(stream_id, send)); }
+					// Closed by either side, or both sides have finished sending
+					if finished {
+						node.streams.remove(&stream_id);
+						self.finish();
+					} else if self.eof && self.buffer.is_empty() && state == ClientState::Connected(stream_id) {
+						node.action(NodeAction::FinishStream(stream_id));
+						self.state = ClientState::Finishing(stream_id);
+					}
+					break
+				},
+				ClientState::Closed => break,
+			}
+		}
+		self.flush_socket()
+	}
+	/// Read whatever the socket has available into the buffer, until the buffer holds `STREAM_BUFFER` bytes
+	fn read_socket(&mut self) -> io::Result<()> {
+		let mut buf = [0u8; 4096];
+		while !self.eof && self.buffer.len() < STREAM_BUFFER {
+			match self.socket.read(&mut buf) {
+				Ok(0) => self.eof = true,
+				Ok(len) => self.buffer.extend_from_slice(&buf[..len]),
+				Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+				Err(err) => return Err(err),
+			}
+		}
+		Ok(())
+	}
+	/// Write as much pending data as the socket accepts, shutting down the socket once a closed client is flushed
+	/// and its write half once a client whose remote side finished is flushed
+	fn flush_socket(&mut self) -> io::Result<()> {
+		while !self.pending.is_empty() {
+			match self.socket.write(&self.pending) {
+				Ok(len) => { self.pending.drain(..len); },
+				Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+				Err(err) => return Err(err),
+			}
+		}
+		if self.pending.is_empty() {
+			if self.state == ClientState::Closed {
+				let _ = self.socket.shutdown(Shutdown::Both);
+			} else if self.remote_eof && !self.write_shut {
+				let _ = self.socket.shutdown(Shutdown::Write);
+				self.write_shut = true;
+			}
+		}
+		Ok(())
+	}
+	fn reply(&mut self, reply: Reply) {
+		// Bound address is not meaningful for a tunneled connection, so always report 0.0.0.0:0
+		self.pending.extend_from_slice(&[SOCKS_VERSION, reply as u8, 0x00, ATYP_IPV4, 0, 0, 0, 0, 0, 0]);
+	}
+	fn finish(&mut self) { self.state = ClientState::Closed; }
+	/// Drop the client immediately, closing its stream if it had one
+	fn close(&mut self, node: &mut Node) {
+		if let ClientState::Connecting(stream_id) | ClientState::Connected(stream_id) | ClientState::Finishing(stream_id) = self.state {
+			node.action(NodeAction::CloseStream(stream_id));
+		}
+		let _ = self.socket.shutdown(Shutdown::Both);
+		self.finish();
+	}
+}
+
+/// Parse a SOCKS5 request, returns Ok(None) if more bytes are needed or Ok(Some((bytes_consumed, "host:port")))
+fn parse_request(buf: &[u8]) -> Result<Option<(usize, String)>, Reply> {
+	if buf.len() < 5 { return Ok(None) }
+	if buf[0] != SOCKS_VERSION { return Err(Reply::GeneralFailure) }
+	if buf[1] != CMD_CONNECT { return Err(Reply::CommandNotSupported) }
+	let (host, addr_end) = match buf[3] {
+		ATYP_IPV4 => {
+			if buf.len() < 4 + 4 { return Ok(None) }
+			(IpAddr::V4(Ipv4Addr::new(buf[4], buf[5], buf[6], buf[7])).to_string(), 8)
+		},
+		ATYP_IPV6 => {
+			if buf.len() < 4 + 16 { return Ok(None) }
+			let mut octets = [0u8; 16];
+			octets.copy_from_slice(&buf[4..20]);
+			(format!("[{}]", Ipv6Addr::from(octets)), 20)
+		},
+		ATYP_DOMAIN => {
+			let len = buf[4] as usize;
+			if buf.len() < 5 + len { return Ok(None) }
+			(String::from_utf8(buf[5..5 + len].to_vec()).map_err(|_|Reply::GeneralFailure)?, 5 + len)
+		},
+		_ => return Err(Reply::AddressTypeNotSupported),
+	};
+	if buf.len() < addr_end + 2 { return Ok(None) }
+	let port = u16::from_be_bytes([buf[addr_end], buf[addr_end + 1]]);
+	Ok(Some((addr_end + 2, format!("{}:{}", host, port))))
+}
+
+/// Spawn a TCP echo server on a background thread, useful as a target for testing the proxy
+pub fn spawn_echo_server(addr: impl ToSocketAddrs) -> io::Result<SocketAddr> {
+	let listener = TcpListener::bind(addr)?;
+	let local_addr = listener.local_addr()?;
+	std::thread::spawn(move || {
+		for socket in listener.incoming().flatten() {
+			std::thread::spawn(move || {
+				if let Ok(mut reader) = socket.try_clone() {
+					let mut writer = socket;
+					let _ = io::copy(&mut reader, &mut writer);
+				}
+			});
+		}
+	});
+	Ok(local_addr)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::time::{Duration, Instant};
+	use crate::config::SimConfig;
+	use crate::internet::{InternetID, InternetSim, SimRng, tests::bootstrapped};
+
+	/// Tick the simulation and serve the proxy until `done` returns true
+	fn run_until(internet: &mut InternetSim<Node>, rng: &mut SimRng, net_id: InternetID, proxy: &mut Socks5Proxy, mut done: impl FnMut(&InternetSim<Node>, &Socks5Proxy) -> bool) {
+		let start = Instant::now();
+		while !done(internet, proxy) {
+			assert!(start.elapsed() < Duration::from_secs(30), "timed out after {} ticks", internet.node(net_id).unwrap().ticks);
+			internet.tick(1, rng);
+			proxy.poll(internet.node_mut(net_id).unwrap());
+		}
+	}
+	/// Read whatever the socket has available, returns true once the other side shut down its write half
+	fn read_available(socket: &mut TcpStream, received: &mut Vec<u8>) -> bool {
+		let mut buf = [0u8; 4096];
+		loop {
+			match socket.read(&mut buf) {
+				Ok(0) => return true,
+				Ok(len) => received.extend_from_slice(&buf[..len]),
+				Err(err) if err.kind() == io::ErrorKind::WouldBlock => return false,
+				Err(err) => panic!("client socket errored: {}", err),
+			}
+		}
+	}
+
+	/// Echo 100 KB through exit net 6 from net 1, dropping `loss_rate` of the packets once the network is bootstrapped
+	fn echo_through_exit(loss_rate: f64) {
+		let (mut internet, mut rng) = bootstrapped(SimConfig::default(), 1);
+		internet.router.loss_rate = loss_rate;
+		let (net_id, exit_net_id) = (1, 6);
+		let exit_node_id = internet.node(exit_net_id).unwrap().node_id;
+		let echo_addr = spawn_echo_server("127.0.0.1:0").unwrap();
+		let mut proxy = Socks5Proxy::bind("127.0.0.1:0", exit_node_id).unwrap();
+		let mut client = TcpStream::connect(proxy.local_addr().unwrap()).unwrap();
+		client.set_nonblocking(true).unwrap();
+
+		// Greeting and CONNECT request to the echo server
+		let ip = match echo_addr.ip() { IpAddr::V4(ip) => ip.octets(), ip => panic!("echo server bound to {}", ip) };
+		let mut request = vec![SOCKS_VERSION, 1, METHOD_NO_AUTH, SOCKS_VERSION, CMD_CONNECT, 0x00, ATYP_IPV4];
+		request.extend_from_slice(&ip);
+		request.extend_from_slice(&echo_addr.port().to_be_bytes());
+		client.write_all(&request).unwrap();
+		let mut received = Vec::new();
+		run_until(&mut internet, &mut rng, net_id, &mut proxy, |_, _| { read_available(&mut client, &mut received); received.len() >= 12 });
+		assert_eq!(received.drain(..12).take(4).collect::<Vec<u8>>(), vec![SOCKS_VERSION, METHOD_NO_AUTH, SOCKS_VERSION, Reply::Succeeded as u8]);
+
+		// More data than one tick reads from the exit connection, then half-close and keep reading until the echo comes back finished
+		let payload: Vec<u8> = (0..100_000).map(|i|(i % 251) as u8).collect();
+		let (mut written, mut eof) = (0, false);
+		run_until(&mut internet, &mut rng, net_id, &mut proxy, |_, _| {
+			if written < payload.len() {
+				match client.write(&payload[written..]) {
+					Ok(len) => written += len,
+					Err(err) if err.kind() == io::ErrorKind::WouldBlock => {},
+					Err(err) => panic!("client socket errored: {}", err),
+				}
+				if written == payload.len() { client.shutdown(Shutdown::Write).unwrap(); }
+			}
+			eof |= read_available(&mut client, &mut received);
+			eof
+		});
+		assert_eq!(received.len(), payload.len());
+		assert!(received == payload, "echoed bytes differ");
+
+		// Both ends finished, so the stream is gone everywhere once the last acknowledgements arrived
+		run_until(&mut internet, &mut rng, net_id, &mut proxy, |internet, proxy| {
+			proxy.connection_count() == 0 && internet.node(exit_net_id).unwrap().exit_streams.is_empty()
+		});
+		assert!(internet.node(net_id).unwrap().streams.is_empty());
+	}
+
+	#[test]
+	fn echo_through_exit_node() { echo_through_exit(0.0) }
+
+	#[test]
+	fn echo_survives_packet_loss() { echo_through_exit(0.1) }
+
+	#[test]
+	fn exit_without_session_gets_general_failure() {
+		let (mut internet, mut rng) = bootstrapped(SimConfig::default(), 1);
+		let net_id = 1;
+		let node = internet.node(net_id).unwrap();
+		let exit_node_id = internet.nodes.values().map(|n|n.node_id).find(|id|*id != node.node_id && !node.remote(id).is_ok_and(|r|r.session_active()))
+			.expect("every node has a session with the client");
+		let mut proxy = Socks5Proxy::bind("127.0.0.1:0", exit_node_id).unwrap();
+		let mut client = TcpStream::connect(proxy.local_addr().unwrap()).unwrap();
+		client.set_nonblocking(true).unwrap();
+
+		client.write_all(&[SOCKS_VERSION, 1, METHOD_NO_AUTH, SOCKS_VERSION, CMD_CONNECT, 0x00, ATYP_IPV4, 127, 0, 0, 1, 0, 80]).unwrap();
+		let mut received = Vec::new();
+		run_until(&mut internet, &mut rng, net_id, &mut proxy, |_, _| read_available(&mut client, &mut received));
+		assert_eq!(received.iter().take(4).copied().collect::<Vec<u8>>(), vec![SOCKS_VERSION, METHOD_NO_AUTH, SOCKS_VERSION, Reply::GeneralFailure as u8]);
+		assert_eq!(proxy.connection_count(), 0);
+		assert!(internet.node(net_id).unwrap().streams.is_empty());
+	}
+}